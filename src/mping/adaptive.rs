@@ -0,0 +1,311 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::mping::ping::monotonic_nanos;
+use crate::mping::stat::TargetResult;
+
+// 明显丢包: 丢失率和丢包数都达到下限
+const LOSS_THRESHOLD: f64 = 0.05;
+const MIN_LOSS: u32 = 2;
+// 降速后丢失率降到原来的这个比例以下, 或收到的回复数保持在原来的这个比例以上, 认为丢包与速率相关
+// 丢包与速率无关时回复数随速率减半, 对端限速时回复数基本不变
+const IMPROVEMENT: f64 = 0.5;
+const HELD_REPLIES: f64 = 0.7;
+// 每次降速和提速的倍数
+const DOWN_FACTOR: f64 = 0.5;
+const UP_FACTOR: f64 = 1.5;
+// 连续多少个没有明显丢包的窗口之后尝试提速
+const PROBE_UP_WINDOWS: u32 = 5;
+// 丢包与速率无关或提速失败后, 多少个窗口内不再调整速率
+const COOLDOWN_WINDOWS: u32 = 10;
+// 每个目标的最低速率, 包/秒
+const MIN_RATE: f64 = 1.0;
+
+// Phase 是一个目标的速率控制阶段
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    // 保持当前速率
+    Steady,
+    // 已降速, 等待降速后的第一个窗口判断丢包是否与速率相关
+    ProbingDown,
+    // 已提速, 等待提速后的第一个窗口判断是否仍然没有丢包
+    ProbingUp,
+}
+
+// TargetState 是一个目标的速率控制状态
+struct TargetState {
+    // 发送额度, 每个探测时机增加 factor, 达到 1 时发送一个探测
+    credit: f64,
+    // 当前速率与基础速率的比例
+    factor: f64,
+    phase: Phase,
+    // 最近一次改变速率的时间, 在此之前开始的窗口混有旧速率的探测, 不用于判断
+    changed_at: u128,
+    // 降速前一个窗口的丢失率和回复数
+    loss_before: f64,
+    received_before: u32,
+    // 确认没有明显丢包的速率比例
+    sustainable: Option<f64>,
+    // 连续没有明显丢包的窗口数
+    clean: u32,
+    // 剩余的冷却窗口数
+    cooldown: u32,
+    // 丢包疑似来自 ICMP 限速
+    suspect: bool,
+}
+
+impl TargetState {
+    fn new_target_state() -> TargetState {
+        TargetState {
+            credit: 0.0,
+            factor: 1.0,
+            phase: Phase::Steady,
+            // 第一个窗口只有部分探测, 不用于判断
+            changed_at: monotonic_nanos(),
+            loss_before: 0.0,
+            received_before: 0,
+            sustainable: None,
+            clean: 0,
+            cooldown: 0,
+            suspect: false,
+        }
+    }
+}
+
+// RateController 按每个目标的丢包情况调整发送速率
+// 出现明显丢包时先降速, 丢包随之减少说明目标在限速, 之后保持在没有丢包的速率并定期尝试提速
+pub struct RateController {
+    // 每个目标的基础速率, 包/秒
+    base_rate: f64,
+    // 最低的速率比例
+    floor: f64,
+    targets: Mutex<HashMap<String, TargetState>>,
+}
+
+impl RateController {
+    pub fn new_rate_controller(base_rate: f64) -> RateController {
+        RateController {
+            base_rate,
+            floor: (MIN_RATE / base_rate).min(1.0),
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 每个探测发送前调用, 返回是否发送, 降速的目标按比例跳过探测
+    pub fn allow(&self, target: &str) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        let state = targets
+            .entry(target.to_string())
+            .or_insert_with(TargetState::new_target_state);
+        state.credit += state.factor;
+        if state.credit >= 1.0 {
+            state.credit -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // 每个基础窗口统计完成后调用, 更新目标的速率并标注结果
+    // tr 的 loss 和 received 是计数, window_start 是窗口的开始时间, CLOCK_MONOTONIC 纳秒
+    pub fn observe(&self, tr: &mut TargetResult, window_start: u128) {
+        let mut targets = self.targets.lock().unwrap();
+        let state = targets
+            .entry(tr.target.clone())
+            .or_insert_with(TargetState::new_target_state);
+
+        let sent = tr.sent();
+        if sent > 0 && window_start >= state.changed_at {
+            let loss_rate = tr.loss as f64 / sent as f64;
+            let lossy = tr.loss >= MIN_LOSS && loss_rate >= LOSS_THRESHOLD;
+            self.update(&tr.target, state, loss_rate, tr.received, lossy);
+        }
+
+        tr.rate_limited = state.suspect;
+        tr.sustainable_rate = self.base_rate * state.sustainable.unwrap_or(state.factor);
+    }
+
+    fn update(
+        &self,
+        target: &str,
+        state: &mut TargetState,
+        loss_rate: f64,
+        received: u32,
+        lossy: bool,
+    ) {
+        match state.phase {
+            Phase::ProbingDown => {
+                // 降速前后都没有回复时目标不可达, 回复数保持不变不能说明在限速
+                let held = state.received_before > 0
+                    && received > 0
+                    && received as f64 >= state.received_before as f64 * HELD_REPLIES;
+                if loss_rate <= state.loss_before * IMPROVEMENT || held {
+                    // 降速后丢包明显减少, 丢包与速率相关
+                    if !state.suspect {
+                        info!(
+                            "{}: loss rate {:.2}% at {:.1}pps, {:.2}% at {:.1}pps, suspect rate limiting",
+                            target,
+                            state.loss_before * 100.0,
+                            self.base_rate * state.factor / DOWN_FACTOR,
+                            loss_rate * 100.0,
+                            self.base_rate * state.factor
+                        );
+                    }
+                    state.suspect = true;
+                } else if !state.suspect {
+                    // 丢包与速率无关, 恢复基础速率
+                    self.set_factor(target, state, 1.0);
+                    state.cooldown = COOLDOWN_WINDOWS;
+                }
+                state.phase = Phase::Steady;
+                state.clean = 0;
+            }
+            Phase::ProbingUp => {
+                if lossy {
+                    // 提速后又出现丢包, 回到上一个没有丢包的速率
+                    let factor = state.sustainable.unwrap_or(self.floor);
+                    self.set_factor(target, state, factor);
+                    state.cooldown = COOLDOWN_WINDOWS;
+                } else {
+                    state.sustainable = Some(state.factor);
+                    // 恢复到基础速率仍然没有丢包, 不再怀疑限速
+                    if state.factor >= 1.0 {
+                        state.suspect = false;
+                        state.sustainable = None;
+                    }
+                }
+                state.phase = Phase::Steady;
+                state.clean = 0;
+            }
+            Phase::Steady => {
+                if lossy {
+                    state.clean = 0;
+                    if state.cooldown == 0 && state.factor > self.floor {
+                        state.loss_before = loss_rate;
+                        state.received_before = received;
+                        let factor = (state.factor * DOWN_FACTOR).max(self.floor);
+                        self.set_factor(target, state, factor);
+                        state.phase = Phase::ProbingDown;
+                    }
+                } else {
+                    state.clean += 1;
+                    if state.factor < 1.0 {
+                        state.sustainable = Some(state.factor);
+                        if state.clean >= PROBE_UP_WINDOWS && state.cooldown == 0 {
+                            let factor = (state.factor * UP_FACTOR).min(1.0);
+                            self.set_factor(target, state, factor);
+                            state.phase = Phase::ProbingUp;
+                        }
+                    }
+                }
+                state.cooldown = state.cooldown.saturating_sub(1);
+            }
+        }
+    }
+
+    fn set_factor(&self, target: &str, state: &mut TargetState, factor: f64) {
+        if factor != state.factor {
+            info!(
+                "{}: adaptive rate {:.1}pps -> {:.1}pps",
+                target,
+                self.base_rate * state.factor,
+                self.base_rate * factor
+            );
+        }
+        state.factor = factor;
+        state.changed_at = monotonic_nanos();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "10.0.0.1";
+
+    // 用一个基础窗口的发送数和回复数更新速率, 返回标注后的结果
+    fn observe(controller: &RateController, sent: u32, received: u32) -> TargetResult {
+        let mut tr = TargetResult {
+            target: TARGET.to_string(),
+            received,
+            loss: sent - received,
+            ..Default::default()
+        };
+        controller.observe(&mut tr, monotonic_nanos());
+        tr
+    }
+
+    fn factor(controller: &RateController) -> f64 {
+        controller.targets.lock().unwrap()[TARGET].factor
+    }
+
+    fn new_controller() -> RateController {
+        let controller = RateController::new_rate_controller(100.0);
+        // 第一个探测创建目标的状态, 之后开始的窗口才用于判断
+        controller.allow(TARGET);
+        controller
+    }
+
+    #[test]
+    fn rate_limited_target() {
+        let controller = new_controller();
+        // 对端每秒只回复 50 个
+        let tr = observe(&controller, 100, 50);
+        assert!(!tr.rate_limited);
+        assert_eq!(factor(&controller), DOWN_FACTOR);
+
+        let tr = observe(&controller, 50, 50);
+        assert!(tr.rate_limited);
+        assert_eq!(tr.sustainable_rate, 50.0);
+
+        // 保持在没有丢包的速率, 之后尝试提速
+        for _ in 0..PROBE_UP_WINDOWS {
+            observe(&controller, 50, 50);
+        }
+        assert_eq!(factor(&controller), DOWN_FACTOR * UP_FACTOR);
+        let tr = observe(&controller, 75, 50);
+        assert!(tr.rate_limited);
+        assert_eq!(factor(&controller), DOWN_FACTOR);
+    }
+
+    #[test]
+    fn random_loss_target() {
+        let controller = new_controller();
+        // 丢失率与速率无关, 降速后回复数随速率减半
+        observe(&controller, 100, 80);
+        assert_eq!(factor(&controller), DOWN_FACTOR);
+        let tr = observe(&controller, 50, 40);
+        assert!(!tr.rate_limited);
+        assert_eq!(factor(&controller), 1.0);
+
+        // 冷却期间不再降速
+        for _ in 0..COOLDOWN_WINDOWS - 1 {
+            observe(&controller, 100, 80);
+            assert_eq!(factor(&controller), 1.0);
+        }
+    }
+
+    #[test]
+    fn dead_target() {
+        let controller = new_controller();
+        observe(&controller, 100, 0);
+        assert_eq!(factor(&controller), DOWN_FACTOR);
+        // 降速前后都没有回复, 不能认为是限速
+        let tr = observe(&controller, 50, 0);
+        assert!(!tr.rate_limited);
+        assert_eq!(factor(&controller), 1.0);
+        assert_eq!(tr.sustainable_rate, 100.0);
+    }
+
+    #[test]
+    fn allow_skips_probes_at_lower_rate() {
+        let controller = new_controller();
+        observe(&controller, 100, 50);
+        let allowed = (0..100).filter(|_| controller.allow(TARGET)).count();
+        assert_eq!(allowed, 50);
+    }
+}
@@ -0,0 +1,209 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::mping::stat::TargetResult;
+
+// 单个帧的最大长度, 超过时认为数据流已经损坏
+const MAX_FRAME_LEN: usize = 1 << 20;
+// 与 collector 断开时最多缓存的结果数, 超过时丢弃最旧的结果
+const MAX_BUFFERED: usize = 100_000;
+// 重连的退避时间
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// 连接和写入的超时
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Report 是 agent 发送给 collector 的一个帧
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    // 探测点的名字, 默认为主机名
+    pub source: String,
+    pub result: TargetResult,
+}
+
+/// 写入一个帧: 4 字节大端长度, 后面是 JSON 编码的内容
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, value: &T) -> io::Result<()> {
+    let body = serde_json::to_vec(value)?;
+    w.write_all(&(body.len() as u32).to_be_bytes())?;
+    w.write_all(&body)?;
+    w.flush()
+}
+
+/// 读取一个帧, 对端在帧边界关闭连接时返回 None
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame too large: {} bytes", len),
+        ));
+    }
+
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+// 返回本机的主机名, 用作默认的探测点名字
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn connect(collector: &str) -> io::Result<TcpStream> {
+    let addr = collector.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("failed to resolve {}", collector),
+        )
+    })?;
+    let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// 把每个窗口的结果发送给 collector, 直到通道关闭
+/// collector 不可达时结果缓存在本地, 重连成功后按顺序补发
+pub fn run(rx: Receiver<TargetResult>, collector: String, source: String) {
+    let mut buffer: VecDeque<Report> = VecDeque::new();
+    let mut conn: Option<TcpStream> = None;
+    let mut next_connect = Instant::now();
+    let mut backoff = MIN_BACKOFF;
+    let mut dropped = 0u64;
+
+    loop {
+        let closed = match rx.recv_timeout(MIN_BACKOFF) {
+            Ok(result) => {
+                if buffer.len() >= MAX_BUFFERED {
+                    buffer.pop_front();
+                    dropped += 1;
+                }
+                buffer.push_back(Report {
+                    source: source.clone(),
+                    result,
+                });
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if conn.is_none() && Instant::now() >= next_connect {
+            match connect(&collector) {
+                Ok(stream) => {
+                    info!(
+                        "connected to collector {}, {} results buffered",
+                        collector,
+                        buffer.len()
+                    );
+                    if dropped > 0 {
+                        warn!(
+                            "dropped {} results while collector was unreachable",
+                            dropped
+                        );
+                        dropped = 0;
+                    }
+                    backoff = MIN_BACKOFF;
+                    conn = Some(stream);
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to connect to collector {}: {}, retry in {:?}",
+                        collector, e, backoff
+                    );
+                    next_connect = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+
+        if let Some(stream) = &mut conn {
+            while let Some(report) = buffer.front() {
+                if let Err(e) = write_frame(stream, report) {
+                    warn!("lost connection to collector {}: {}", collector, e);
+                    conn = None;
+                    next_connect = Instant::now() + backoff;
+                    break;
+                }
+                buffer.pop_front();
+            }
+        }
+
+        if closed {
+            if !buffer.is_empty() {
+                warn!("exit with {} results not reported", buffer.len());
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_round_trip() {
+        let report = Report {
+            source: "probe".to_string(),
+            result: TargetResult {
+                target: "10.0.0.1".to_string(),
+                received: 9,
+                loss: 1,
+                window: Duration::from_secs(1),
+                ..Default::default()
+            },
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &report).unwrap();
+        write_frame(&mut buf, &report).unwrap();
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        assert_eq!(len * 2 + 8, buf.len());
+
+        let mut r = Cursor::new(buf);
+        for _ in 0..2 {
+            let decoded: Report = read_frame(&mut r).unwrap().unwrap();
+            assert_eq!(decoded.source, "probe");
+            assert_eq!(decoded.result.target, "10.0.0.1");
+            assert_eq!((decoded.result.received, decoded.result.loss), (9, 1));
+            assert_eq!(decoded.result.window, Duration::from_secs(1));
+        }
+        // 在帧边界结束
+        assert!(read_frame::<_, Report>(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let mut buf = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        let err = read_frame::<_, Report>(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // 帧内容不完整
+        let mut buf = 10u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        assert!(read_frame::<_, Report>(&mut Cursor::new(buf)).is_err());
+    }
+}
@@ -0,0 +1,501 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::mping::anomaly::Anomaly;
+use crate::mping::http;
+use crate::mping::stat::TargetResult;
+
+// AlertKind 是告警规则检查的指标
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlertKind {
+    // 丢失率超过给定的百分比
+    Loss(f64),
+    // 平均延迟超过给定的毫秒数
+    Latency(f64),
+    // 出现任何 bitflip
+    Bitflip,
+    // 延迟或丢失率偏离基线, 需要开启异常检测
+    Anomaly,
+}
+
+/// 告警规则, 格式为 `<指标>[:<窗口数>]`, 例如:
+/// - `loss>5%:3` 连续 3 个窗口丢失率超过 5%
+/// - `latency>100ms` 平均延迟超过 100ms
+/// - `bitflip` 出现 bitflip
+/// - `anomaly` 延迟或丢失率偏离该目标的基线
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertRule {
+    pub kind: AlertKind,
+    // 连续多少个窗口超过阈值后才触发告警
+    pub windows: u32,
+}
+
+impl FromStr for AlertRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (expr, windows) = match s.split_once(':') {
+            Some((expr, n)) => (
+                expr,
+                n.parse::<u32>()
+                    .map_err(|_| format!("invalid window count: {}", s))?,
+            ),
+            None => (s, 1),
+        };
+        if windows == 0 {
+            return Err(format!("window count must be positive: {}", s));
+        }
+
+        let kind = if expr == "bitflip" {
+            AlertKind::Bitflip
+        } else if expr == "anomaly" {
+            AlertKind::Anomaly
+        } else if let Some(v) = expr.strip_prefix("loss>") {
+            let v = v.trim_end_matches('%');
+            AlertKind::Loss(v.parse().map_err(|_| format!("invalid loss rate: {}", s))?)
+        } else if let Some(v) = expr.strip_prefix("latency>") {
+            let v = v.trim_end_matches("ms");
+            AlertKind::Latency(v.parse().map_err(|_| format!("invalid latency: {}", s))?)
+        } else {
+            return Err(format!("invalid alert rule: {}", s));
+        };
+
+        Ok(AlertRule { kind, windows })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            AlertKind::Loss(v) => write!(f, "loss>{}%", v)?,
+            AlertKind::Latency(v) => write!(f, "latency>{}ms", v)?,
+            AlertKind::Bitflip => write!(f, "bitflip")?,
+            AlertKind::Anomaly => write!(f, "anomaly")?,
+        }
+        if self.windows > 1 {
+            write!(f, ":{}", self.windows)?;
+        }
+        Ok(())
+    }
+}
+
+impl AlertRule {
+    // 返回该窗口的观测值, 以及是否超过阈值
+    // 窗口中没有可以判断的数据时返回 None, 例如没有回复时无法判断延迟, 告警状态保持不变
+    fn check(&self, tr: &TargetResult) -> Option<(f64, bool)> {
+        match self.kind {
+            AlertKind::Loss(threshold) => {
+                if tr.sent() == 0 {
+                    return None;
+                }
+                let loss = tr.loss_rate * 100.0;
+                Some((loss, loss > threshold))
+            }
+            AlertKind::Latency(threshold) => {
+                if tr.received == 0 {
+                    return None;
+                }
+                let latency = Duration::from_nanos(tr.latency as u64).as_secs_f64() * 1000.0;
+                Some((latency, latency > threshold))
+            }
+            AlertKind::Bitflip => Some((tr.bitflip_count as f64, tr.bitflip_count > 0)),
+            // 观测值是偏离最大的标准差数
+            AlertKind::Anomaly => Some((
+                tr.anomalies.iter().map(|a| a.sigmas).fold(0.0, f64::max),
+                !tr.anomalies.is_empty(),
+            )),
+        }
+    }
+}
+
+// AlertAction 是告警状态变化时执行的动作
+#[derive(Clone, Debug)]
+pub enum AlertAction {
+    // 通过 sh -c 执行本地命令, 事件的 JSON 写入标准输入
+    // 规则、目标和状态同时通过环境变量 MPING_ALERT_RULE、MPING_ALERT_TARGET 和 MPING_ALERT_STATE 传递
+    Command(String),
+    // 以 POST 方式把事件的 JSON 发送到 URL
+    Webhook(String),
+}
+
+// AlertState 是告警的状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+// AlertEvent 是告警状态变化的事件
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub target: String,
+    pub state: AlertState,
+    // 触发或恢复时的观测值
+    pub value: f64,
+    // 统计窗口, 毫秒
+    pub window_ms: u128,
+    pub loss_rate: f64,
+    pub latency_ms: f64,
+    // 该窗口的异常, 包含基线和观测值
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub anomalies: Vec<Anomaly>,
+}
+
+// 每个 (规则, 目标) 的告警状态
+#[derive(Default)]
+struct RuleState {
+    // 连续超过阈值的窗口数
+    breaches: u32,
+    // 是否正在告警
+    firing: bool,
+}
+
+// Alerter 根据告警规则检查每个窗口的结果, 只在状态变化时执行动作, 避免重复告警
+pub struct Alerter {
+    rules: Vec<AlertRule>,
+    actions: Vec<AlertAction>,
+    // 只检查该长度窗口的结果, 避免 roll-up 窗口干扰连续窗口的计数
+    window: Duration,
+    states: HashMap<(usize, String), RuleState>,
+    // 还在执行的动作, 退出前等待它们完成
+    running: Vec<JoinHandle<()>>,
+}
+
+impl Alerter {
+    // 创建一个 Alerter
+    pub fn new_alerter(
+        rules: Vec<AlertRule>,
+        actions: Vec<AlertAction>,
+        window: Duration,
+    ) -> Alerter {
+        Alerter {
+            rules,
+            actions,
+            window,
+            states: HashMap::new(),
+            running: Vec::new(),
+        }
+    }
+
+    // 检查一个窗口的结果, 返回状态发生变化的告警事件
+    pub fn check(&mut self, tr: &TargetResult) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+        if tr.window != self.window {
+            return events;
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            let Some((value, breach)) = rule.check(tr) else {
+                continue;
+            };
+            let state = self.states.entry((i, tr.target.clone())).or_default();
+
+            let changed = if breach {
+                state.breaches += 1;
+                if !state.firing && state.breaches >= rule.windows {
+                    state.firing = true;
+                    Some(AlertState::Firing)
+                } else {
+                    None
+                }
+            } else {
+                state.breaches = 0;
+                if state.firing {
+                    state.firing = false;
+                    Some(AlertState::Resolved)
+                } else {
+                    None
+                }
+            };
+
+            if let Some(state) = changed {
+                events.push(AlertEvent {
+                    rule: rule.to_string(),
+                    target: tr.target.clone(),
+                    state,
+                    value,
+                    window_ms: tr.window.as_millis(),
+                    loss_rate: tr.loss_rate,
+                    latency_ms: Duration::from_nanos(tr.latency as u64).as_secs_f64() * 1000.0,
+                    anomalies: tr.anomalies.clone(),
+                });
+            }
+        }
+
+        events
+    }
+
+    // 对一个告警事件执行所有动作, 每个动作在单独的线程中执行, 避免阻塞统计结果的处理
+    pub fn notify(&mut self, event: &AlertEvent) {
+        match event.state {
+            AlertState::Firing => warn!(
+                "alert firing: {} {} value={:.2}",
+                event.target, event.rule, event.value
+            ),
+            AlertState::Resolved => info!(
+                "alert resolved: {} {} value={:.2}",
+                event.target, event.rule, event.value
+            ),
+        }
+
+        self.running.retain(|handle| !handle.is_finished());
+        for action in &self.actions {
+            let action = action.clone();
+            let event = event.clone();
+            self.running.push(thread::spawn(move || {
+                if let Err(e) = run_action(&action, &event) {
+                    error!("alert action {:?} failed: {}", action, e);
+                }
+            }));
+        }
+    }
+
+    // 等待所有还在执行的动作完成
+    pub fn wait(&mut self) {
+        for handle in self.running.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_action(action: &AlertAction, event: &AlertEvent) -> anyhow::Result<()> {
+    let body = serde_json::to_string(event)?;
+    match action {
+        AlertAction::Command(cmd) => {
+            let state = match event.state {
+                AlertState::Firing => "firing",
+                AlertState::Resolved => "resolved",
+            };
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .env("MPING_ALERT_RULE", &event.rule)
+                .env("MPING_ALERT_TARGET", &event.target)
+                .env("MPING_ALERT_STATE", state)
+                .stdin(Stdio::piped())
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(body.as_bytes())?;
+            }
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("command exited with {}", status));
+            }
+        }
+        AlertAction::Webhook(url) => {
+            let status = http::post_json(url, &body)?;
+            if !(200..300).contains(&status) {
+                return Err(anyhow::anyhow!("webhook returned HTTP {}", status));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// 从通道接收每个窗口的结果并检查告警, 直到通道关闭, 返回前等待所有动作完成
+pub fn run(rx: Receiver<TargetResult>, mut alerter: Alerter) {
+    for tr in rx {
+        for event in alerter.check(&tr) {
+            alerter.notify(&event);
+        }
+    }
+    alerter.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let rule: AlertRule = "loss>5%:3".parse().unwrap();
+        assert_eq!(rule.kind, AlertKind::Loss(5.0));
+        assert_eq!(rule.windows, 3);
+        assert_eq!(rule.to_string(), "loss>5%:3");
+
+        let rule: AlertRule = "latency>100ms".parse().unwrap();
+        assert_eq!(rule.kind, AlertKind::Latency(100.0));
+        assert_eq!(rule.windows, 1);
+        assert_eq!(rule.to_string(), "latency>100ms");
+
+        assert_eq!(
+            "latency>2.5".parse::<AlertRule>().unwrap().kind,
+            AlertKind::Latency(2.5)
+        );
+        assert_eq!(
+            "bitflip".parse::<AlertRule>().unwrap().kind,
+            AlertKind::Bitflip
+        );
+        assert_eq!(
+            "anomaly:2".parse::<AlertRule>().unwrap().kind,
+            AlertKind::Anomaly
+        );
+
+        for invalid in [
+            "",
+            "loss",
+            "loss>x%",
+            "latency>fast",
+            "jitter>1ms",
+            "loss>5%:0",
+            "loss>5%:n",
+        ] {
+            assert!(invalid.parse::<AlertRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    fn window(received: u32, loss: u32, latency_ms: u64) -> TargetResult {
+        let total = received + loss;
+        TargetResult {
+            target: "a".to_string(),
+            received,
+            loss,
+            loss_rate: if total == 0 {
+                0.0
+            } else {
+                loss as f64 / total as f64
+            },
+            latency: latency_ms as u128 * 1_000_000,
+            window: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    fn states(events: Vec<AlertEvent>) -> Vec<AlertState> {
+        events.into_iter().map(|e| e.state).collect()
+    }
+
+    #[test]
+    fn fire_after_consecutive_windows_and_resolve() {
+        let rules = vec!["loss>10%:2".parse().unwrap()];
+        let mut alerter = Alerter::new_alerter(rules, Vec::new(), Duration::from_secs(1));
+
+        assert!(alerter.check(&window(5, 5, 1)).is_empty());
+        assert!(alerter.check(&window(10, 0, 1)).is_empty());
+        assert!(alerter.check(&window(5, 5, 1)).is_empty());
+        assert_eq!(
+            states(alerter.check(&window(5, 5, 1))),
+            vec![AlertState::Firing]
+        );
+        assert!(alerter.check(&window(5, 5, 1)).is_empty());
+        assert_eq!(
+            states(alerter.check(&window(10, 0, 1))),
+            vec![AlertState::Resolved]
+        );
+    }
+
+    #[test]
+    fn no_replies_keep_latency_state() {
+        let rules = vec!["latency>50ms".parse().unwrap()];
+        let mut alerter = Alerter::new_alerter(rules, Vec::new(), Duration::from_secs(1));
+
+        assert_eq!(
+            states(alerter.check(&window(10, 0, 80))),
+            vec![AlertState::Firing]
+        );
+        // 全部丢失的窗口没有延迟数据, 不会恢复告警
+        assert!(alerter.check(&window(0, 10, 0)).is_empty());
+        assert!(alerter.check(&window(0, 0, 0)).is_empty());
+        assert_eq!(
+            states(alerter.check(&window(10, 0, 10))),
+            vec![AlertState::Resolved]
+        );
+    }
+
+    #[test]
+    fn other_windows_ignored() {
+        let rules = vec!["loss>10%".parse().unwrap()];
+        let mut alerter = Alerter::new_alerter(rules, Vec::new(), Duration::from_secs(1));
+        let mut tr = window(0, 10, 0);
+        tr.window = Duration::from_secs(60);
+        assert!(alerter.check(&tr).is_empty());
+    }
+
+    fn firing_event() -> AlertEvent {
+        let rules = vec!["loss>10%".parse().unwrap()];
+        let mut alerter = Alerter::new_alerter(rules, Vec::new(), Duration::from_secs(1));
+        alerter.check(&window(5, 5, 2)).pop().unwrap()
+    }
+
+    #[test]
+    fn webhook_posts_event() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = http::read_request(&stream).unwrap();
+            http::write_response(&mut stream, 200, "{}").unwrap();
+            request
+        });
+
+        let mut alerter = Alerter::new_alerter(
+            vec!["loss>10%".parse().unwrap()],
+            vec![AlertAction::Webhook(url)],
+            Duration::from_secs(1),
+        );
+        let event = firing_event();
+        alerter.notify(&event);
+        alerter.wait();
+
+        let request = server.join().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/alerts");
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["rule"], "loss>10%");
+        assert_eq!(body["target"], "a");
+        assert_eq!(body["state"], "firing");
+        assert_eq!(body["value"], 50.0);
+        assert_eq!(body["window_ms"], 1000);
+        assert_eq!(body["latency_ms"], 2.0);
+        // 没有异常时不输出 anomalies
+        assert!(body.get("anomalies").is_none());
+    }
+
+    #[test]
+    fn webhook_error_status() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            http::read_request(&stream).unwrap();
+            http::write_response(&mut stream, 500, "{}").unwrap();
+        });
+        let err = run_action(&AlertAction::Webhook(url), &firing_event()).unwrap_err();
+        assert!(err.to_string().contains("500"), "{}", err);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn command_gets_event() {
+        let out = std::env::temp_dir().join(format!("mping-alert-{}", std::process::id()));
+        let cmd = format!(
+            "{{ cat; echo; echo \"$MPING_ALERT_RULE $MPING_ALERT_TARGET $MPING_ALERT_STATE\"; }} > {}",
+            out.display()
+        );
+        let event = firing_event();
+        run_action(&AlertAction::Command(cmd), &event).unwrap();
+
+        let output = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        let (body, env) = output.split_once('\n').unwrap();
+        assert_eq!(body, serde_json::to_string(&event).unwrap());
+        assert_eq!(env, "loss>10% a firing\n");
+
+        // 命令失败时返回错误
+        assert!(run_action(&AlertAction::Command("exit 3".to_string()), &event).is_err());
+    }
+}
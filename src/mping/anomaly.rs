@@ -0,0 +1,279 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mping::stat::TargetResult;
+
+// 延迟标准差的下限: 绝对值 (毫秒) 和相对基线的比例, 避免非常稳定的目标的微小波动被判为异常
+const MIN_LATENCY_STDDEV_MS: f64 = 0.05;
+const MIN_LATENCY_STDDEV_RATIO: f64 = 0.05;
+// 计算丢失率二项分布标准差时使用的最小丢失率
+const MIN_LOSS_RATE: f64 = 0.01;
+
+// AnomalyOption 是异常检测的配置
+#[derive(Clone, Debug)]
+pub struct AnomalyOption {
+    // 偏离基线超过多少个标准差判为异常
+    pub sigmas: f64,
+    // EWMA 的平滑系数, 越大基线跟随越快
+    pub alpha: f64,
+    // 每个目标积累多少个窗口之后才开始判断
+    pub warmup: u32,
+}
+
+impl AnomalyOption {
+    pub fn new_anomaly_option(sigmas: f64) -> AnomalyOption {
+        AnomalyOption {
+            sigmas,
+            alpha: 0.1,
+            warmup: 10,
+        }
+    }
+}
+
+// Metric 是异常检测的指标
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    // 平均延迟, 毫秒
+    Latency,
+    // 丢失率, 0 到 1
+    Loss,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Metric::Latency => write!(f, "latency"),
+            Metric::Loss => write!(f, "loss"),
+        }
+    }
+}
+
+// Anomaly 是一个窗口中偏离基线的指标
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Anomaly {
+    pub metric: Metric,
+    // 基线的均值和标准差
+    pub baseline: f64,
+    pub stddev: f64,
+    // 该窗口的观测值
+    pub observed: f64,
+    // 观测值偏离基线的标准差数
+    pub sigmas: f64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.metric {
+            Metric::Latency => write!(
+                f,
+                "latency {:.2}ms, baseline {:.2}ms ± {:.2}ms",
+                self.observed, self.baseline, self.stddev
+            )?,
+            Metric::Loss => write!(
+                f,
+                "loss rate {:.2}%, baseline {:.2}% ± {:.2}%",
+                self.observed * 100.0,
+                self.baseline * 100.0,
+                self.stddev * 100.0
+            )?,
+        }
+        write!(f, " ({:.1} sigmas)", self.sigmas)
+    }
+}
+
+// Baseline 是一个指标的指数加权移动平均和方差
+#[derive(Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: u32,
+}
+
+impl Baseline {
+    fn update(&mut self, value: f64, alpha: f64) {
+        if self.samples == 0 {
+            self.mean = value;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        }
+        self.samples += 1;
+    }
+}
+
+// 每个目标的延迟和丢失率基线
+#[derive(Default)]
+struct TargetBaseline {
+    latency: Baseline,
+    loss: Baseline,
+}
+
+// AnomalyDetector 为每个目标学习延迟和丢失率的基线, 标注明显变差的窗口
+pub struct AnomalyDetector {
+    option: AnomalyOption,
+    baselines: HashMap<String, TargetBaseline>,
+}
+
+impl AnomalyDetector {
+    pub fn new_anomaly_detector(option: AnomalyOption) -> AnomalyDetector {
+        AnomalyDetector {
+            option,
+            baselines: HashMap::new(),
+        }
+    }
+
+    // 检查一个基础窗口的结果, 把异常记入 tr.anomalies, 然后用该窗口更新基线
+    // tr 是尚未 finish 的统计, latency 是延迟之和
+    pub fn check(&mut self, tr: &mut TargetResult) {
+        let sent = tr.sent();
+        if sent == 0 {
+            return;
+        }
+        let option = &self.option;
+        let baseline = self.baselines.entry(tr.target.clone()).or_default();
+
+        // 延迟只在有回复时才有意义
+        if tr.received > 0 {
+            let mut latency = tr.latency as f64 / tr.received as f64 / 1_000_000.0;
+            let b = &baseline.latency;
+            if b.samples >= option.warmup {
+                let stddev = b
+                    .variance
+                    .sqrt()
+                    .max(MIN_LATENCY_STDDEV_MS)
+                    .max(b.mean * MIN_LATENCY_STDDEV_RATIO);
+                latency = push_if_anomalous(tr, Metric::Latency, b.mean, stddev, latency, option);
+            }
+            baseline.latency.update(latency, option.alpha);
+        }
+
+        let mut loss_rate = tr.loss as f64 / sent as f64;
+        let b = &baseline.loss;
+        if b.samples >= option.warmup {
+            // 探测数少时丢失率本身的抽样误差很大, 标准差不低于二项分布的标准差
+            // 也不低于一个探测对应的丢失率, 从未丢包的目标偶尔丢一个包不会被判为异常
+            let p = b.mean.max(MIN_LOSS_RATE);
+            let stddev = b
+                .variance
+                .sqrt()
+                .max((p * (1.0 - p) / sent as f64).sqrt())
+                .max(1.0 / sent as f64);
+            loss_rate = push_if_anomalous(tr, Metric::Loss, b.mean, stddev, loss_rate, option);
+        }
+        baseline.loss.update(loss_rate, option.alpha);
+    }
+}
+
+// 只标注变差的方向: 延迟升高或丢失率升高
+// 返回用于更新基线的值, 异常窗口的值被限制在阈值处, 避免一次异常抬高基线和方差而掩盖之后的异常
+fn push_if_anomalous(
+    tr: &mut TargetResult,
+    metric: Metric,
+    baseline: f64,
+    stddev: f64,
+    observed: f64,
+    option: &AnomalyOption,
+) -> f64 {
+    let sigmas = (observed - baseline) / stddev;
+    if sigmas < option.sigmas {
+        return observed;
+    }
+    tr.anomalies.push(Anomaly {
+        metric,
+        baseline,
+        stddev,
+        observed,
+        sigmas,
+    });
+    baseline + option.sigmas * stddev
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 一个基础窗口的结果, latency 是每个回复的延迟, 毫秒
+    fn window(received: u32, loss: u32, latency_ms: u128) -> TargetResult {
+        TargetResult {
+            target: "10.0.0.1".to_string(),
+            received,
+            loss,
+            latency: latency_ms * 1_000_000 * received as u128,
+            ..Default::default()
+        }
+    }
+
+    fn check(
+        detector: &mut AnomalyDetector,
+        received: u32,
+        loss: u32,
+        latency_ms: u128,
+    ) -> Vec<Metric> {
+        let mut tr = window(received, loss, latency_ms);
+        detector.check(&mut tr);
+        tr.anomalies.iter().map(|a| a.metric).collect()
+    }
+
+    fn warmed_detector() -> AnomalyDetector {
+        let mut detector =
+            AnomalyDetector::new_anomaly_detector(AnomalyOption::new_anomaly_option(3.0));
+        for _ in 0..detector.option.warmup {
+            assert!(check(&mut detector, 10, 0, 10).is_empty());
+        }
+        detector
+    }
+
+    #[test]
+    fn no_anomalies_during_warmup() {
+        let mut detector =
+            AnomalyDetector::new_anomaly_detector(AnomalyOption::new_anomaly_option(3.0));
+        assert!(check(&mut detector, 10, 0, 10).is_empty());
+        assert!(check(&mut detector, 1, 9, 500).is_empty());
+    }
+
+    #[test]
+    fn latency_spike() {
+        let mut detector = warmed_detector();
+        assert!(check(&mut detector, 10, 0, 10).is_empty());
+        assert_eq!(check(&mut detector, 10, 0, 50), vec![Metric::Latency]);
+        // 延迟降低不是异常
+        assert!(check(&mut detector, 10, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn single_loss_is_not_anomalous() {
+        let mut detector = warmed_detector();
+        assert!(check(&mut detector, 9, 1, 10).is_empty());
+        assert!(check(&mut detector, 8, 2, 10).is_empty());
+        assert_eq!(check(&mut detector, 6, 4, 10), vec![Metric::Loss]);
+        // 没有回复时只判断丢失率
+        assert_eq!(check(&mut detector, 0, 10, 0), vec![Metric::Loss]);
+    }
+
+    #[test]
+    fn anomalies_do_not_mask_later_ones() {
+        let mut detector = warmed_detector();
+        assert_eq!(check(&mut detector, 10, 0, 1000), vec![Metric::Latency]);
+        assert_eq!(check(&mut detector, 10, 0, 20), vec![Metric::Latency]);
+        assert_eq!(check(&mut detector, 0, 10, 0), vec![Metric::Loss]);
+        assert_eq!(check(&mut detector, 5, 5, 10), vec![Metric::Loss]);
+    }
+
+    #[test]
+    fn baseline_follows_lasting_change() {
+        let mut detector = warmed_detector();
+        // 延迟持续升高后基线逐渐跟随, 不会一直判为异常
+        let anomalies = (0..100)
+            .filter(|_| !check(&mut detector, 10, 0, 20).is_empty())
+            .count();
+        assert!(anomalies > 0 && anomalies < 100);
+        assert!(check(&mut detector, 10, 0, 20).is_empty());
+    }
+}
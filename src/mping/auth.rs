@@ -0,0 +1,146 @@
+#![cfg(target_os = "linux")]
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+// 会话 cookie 的长度
+pub const COOKIE_LEN: usize = 8;
+// 截断后的 HMAC-SHA256 的长度
+pub const MAC_LEN: usize = 16;
+// payload 中认证字段的长度: cookie 之后是 MAC, 没有配置密钥时 MAC 全为 0
+pub const AUTH_LEN: usize = COOKIE_LEN + MAC_LEN;
+
+// Verdict 是检查一个回复的结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    // 本会话发出的请求的回复
+    Valid,
+    // cookie 不匹配, 例如另一个 Id 相同的 mping 进程的回复
+    Foreign,
+    // cookie 匹配但 MAC 校验失败, 回复是伪造或被篡改的
+    Spoofed,
+}
+
+// Session 标识一个 ping 会话: ICMP Id 加上随机生成的 cookie
+// 配置了密钥时再对时间戳、序列号和 cookie 计算 MAC
+#[derive(Clone, Debug)]
+pub struct Session {
+    // ICMP 报文的 Id, 只用于快速过滤, 不同进程之间可能相同
+    pub ident: u16,
+    cookie: [u8; COOKIE_LEN],
+    key: Option<Vec<u8>>,
+}
+
+impl Session {
+    // 创建一个会话, cookie 随机生成
+    pub fn new_session(ident: u16, key: Option<Vec<u8>>) -> Session {
+        Session {
+            ident,
+            cookie: rand::thread_rng().gen(),
+            key,
+        }
+    }
+
+    // 对时间戳、序列号和 cookie 计算 HMAC-SHA256
+    fn hmac(&self, key: &[u8], signed: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(signed);
+        mac.update(&self.cookie);
+        mac
+    }
+
+    // 生成认证字段, signed 是 payload 中时间戳和序列号部分
+    pub fn seal(&self, signed: &[u8]) -> [u8; AUTH_LEN] {
+        let mut auth = [0u8; AUTH_LEN];
+        auth[..COOKIE_LEN].copy_from_slice(&self.cookie);
+        if let Some(key) = &self.key {
+            let digest = self.hmac(key, signed).finalize().into_bytes();
+            auth[COOKIE_LEN..].copy_from_slice(&digest[..MAC_LEN]);
+        }
+        auth
+    }
+
+    // 检查回复中的认证字段
+    pub fn check(&self, signed: &[u8], auth: &[u8]) -> Verdict {
+        if auth.len() < AUTH_LEN || auth[..COOKIE_LEN] != self.cookie {
+            return Verdict::Foreign;
+        }
+        if let Some(key) = &self.key {
+            // 使用常量时间比较, 避免通过时间差猜测 MAC
+            if self
+                .hmac(key, signed)
+                .verify_truncated_left(&auth[COOKIE_LEN..AUTH_LEN])
+                .is_err()
+            {
+                return Verdict::Spoofed;
+            }
+        }
+        Verdict::Valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED: &[u8] = b"0123456789abcdef";
+
+    fn keyed() -> Session {
+        Session::new_session(1, Some(b"secret".to_vec()))
+    }
+
+    #[test]
+    fn sealed_reply_is_valid() {
+        let session = keyed();
+        assert_eq!(session.check(SIGNED, &session.seal(SIGNED)), Verdict::Valid);
+
+        // 没有密钥时只比较 cookie, MAC 全为 0
+        let session = Session::new_session(1, None);
+        let auth = session.seal(SIGNED);
+        assert!(auth[COOKIE_LEN..].iter().all(|b| *b == 0));
+        assert_eq!(session.check(b"anything", &auth), Verdict::Valid);
+    }
+
+    #[test]
+    fn other_session_is_foreign() {
+        let session = keyed();
+        let other = keyed();
+        assert_eq!(session.check(SIGNED, &other.seal(SIGNED)), Verdict::Foreign);
+
+        let mut auth = session.seal(SIGNED);
+        auth[0] ^= 1;
+        assert_eq!(session.check(SIGNED, &auth), Verdict::Foreign);
+    }
+
+    #[test]
+    fn tampered_reply_is_spoofed() {
+        let session = keyed();
+        let mut auth = session.seal(SIGNED);
+        auth[AUTH_LEN - 1] ^= 1;
+        assert_eq!(session.check(SIGNED, &auth), Verdict::Spoofed);
+
+        let auth = session.seal(SIGNED);
+        let mut signed = SIGNED.to_vec();
+        signed[3] ^= 0x80;
+        assert_eq!(session.check(&signed, &auth), Verdict::Spoofed);
+
+        // 密钥不同
+        let mut other = session.clone();
+        other.key = Some(b"other".to_vec());
+        assert_eq!(other.check(SIGNED, &auth), Verdict::Spoofed);
+    }
+
+    #[test]
+    fn truncated_auth() {
+        let session = keyed();
+        let auth = session.seal(SIGNED);
+        for len in 0..AUTH_LEN {
+            assert_eq!(session.check(SIGNED, &auth[..len]), Verdict::Foreign);
+        }
+        // 多余的字节不参与校验
+        let mut longer = auth.to_vec();
+        longer.push(0xff);
+        assert_eq!(session.check(SIGNED, &longer), Verdict::Valid);
+    }
+}
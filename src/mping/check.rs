@@ -0,0 +1,349 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::mping::ping::{self, PingOption};
+use crate::mping::stat::{self, HostResult, TargetResult};
+
+// Status 是监控插件的检查结果, 数值就是退出码
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl Status {
+    // 汇总多个结果时的优先级: CRITICAL > WARNING > UNKNOWN > OK
+    fn severity(self) -> u8 {
+        match self {
+            Status::Ok => 0,
+            Status::Unknown => 1,
+            Status::Warning => 2,
+            Status::Critical => 3,
+        }
+    }
+
+    fn worst(self, other: Status) -> Status {
+        if other.severity() > self.severity() {
+            other
+        } else {
+            self
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Status::Ok => "OK",
+            Status::Warning => "WARNING",
+            Status::Critical => "CRITICAL",
+            Status::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Threshold 是 check_ping 格式的阈值: "<平均延迟 ms>,<丢失率>%", 例如 100,5%
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Threshold {
+    pub rtt_ms: f64,
+    // 丢失率, 百分比
+    pub loss: f64,
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rtt, loss) = s
+            .split_once(',')
+            .ok_or_else(|| format!("invalid threshold, expected <rtt ms>,<loss>%: {}", s))?;
+        let rtt_ms: f64 = rtt
+            .trim()
+            .trim_end_matches("ms")
+            .parse()
+            .map_err(|_| format!("invalid rtt threshold: {}", rtt))?;
+        let loss: f64 = loss
+            .trim()
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| format!("invalid loss threshold: {}", loss))?;
+        if rtt_ms < 0.0 || !(0.0..=100.0).contains(&loss) {
+            return Err(format!("threshold out of range: {}", s));
+        }
+        Ok(Threshold { rtt_ms, loss })
+    }
+}
+
+impl Threshold {
+    fn exceeded(&self, host: &HostResult) -> bool {
+        host.loss() >= self.loss || (host.received > 0 && host.rtt_ms() >= self.rtt_ms)
+    }
+}
+
+// 一个地址的检查结果, 没有发出探测时为 UNKNOWN
+fn host_status(host: &HostResult, warning: &Threshold, critical: &Threshold) -> Status {
+    if host.sent == 0 {
+        Status::Unknown
+    } else if critical.exceeded(host) {
+        Status::Critical
+    } else if warning.exceeded(host) {
+        Status::Warning
+    } else {
+        Status::Ok
+    }
+}
+
+// 停止发送的时间: 之后还要等待 timeout 收取最后的回复, 统计线程最多再等一个窗口才输出剩余的结果
+fn send_deadline(deadline: Duration, timeout: Duration, interval: Duration) -> Duration {
+    deadline.saturating_sub(timeout + interval)
+}
+
+/// 对每个地址发送 `popt.count` 次探测, 最多运行 `deadline`, 输出一行监控插件格式的结果和 perfdata
+/// 返回汇总的检查结果, 调用者以它作为退出码
+pub fn run(
+    addrs: Vec<IpAddr>,
+    popt: PingOption,
+    deadline: Duration,
+    warning: Threshold,
+    critical: Threshold,
+) -> anyhow::Result<Status> {
+    let (tx, rx) = mpsc::channel::<TargetResult>();
+    let collector = stat::collect_hosts(rx, popt.interval);
+
+    // ping 返回前会输出剩余的所有窗口, 已经发出的探测都计入结果
+    let stop = Arc::new(AtomicBool::new(false));
+    let expired = Arc::new(AtomicBool::new(false));
+    let (timer_stop, timer_expired) = (stop.clone(), expired.clone());
+    let send_deadline = send_deadline(deadline, popt.timeout, popt.interval);
+    thread::spawn(move || {
+        thread::sleep(send_deadline);
+        if !timer_stop.swap(true, Ordering::Relaxed) {
+            timer_expired.store(true, Ordering::Relaxed);
+        }
+    });
+
+    let result = ping::ping_until(addrs.clone(), popt, false, Some(tx), stop.clone());
+    stop.store(true, Ordering::Relaxed);
+    result?;
+    let hosts = collector
+        .join()
+        .map_err(|_| anyhow::anyhow!("check collector panicked"))?;
+
+    let (status, line) = report(
+        &addrs,
+        &hosts,
+        &warning,
+        &critical,
+        expired.load(Ordering::Relaxed),
+    );
+    println!("{}", line);
+
+    Ok(status)
+}
+
+// 汇总所有地址的检查结果, 返回最差的结果和监控插件格式的输出行
+fn report(
+    addrs: &[IpAddr],
+    hosts: &HashMap<String, HostResult>,
+    warning: &Threshold,
+    critical: &Threshold,
+    expired: bool,
+) -> (Status, String) {
+    let empty = HostResult::default();
+    let mut status = Status::Ok;
+    let mut problems = Vec::new();
+    let mut perfdata = Vec::new();
+    for addr in addrs {
+        let target = addr.to_string();
+        let host = hosts.get(&target).unwrap_or(&empty);
+        let host_status = host_status(host, warning, critical);
+        status = status.worst(host_status);
+
+        match host_status {
+            Status::Ok => {}
+            Status::Unknown => problems.push(format!("{}: no results", target)),
+            _ if host.received == 0 => problems.push(format!("{}: rta -, loss 100%", target)),
+            _ => problems.push(format!(
+                "{}: rta {:.2}ms, loss {:.0}%",
+                target,
+                host.rtt_ms(),
+                host.loss()
+            )),
+        }
+
+        if host.sent > 0 {
+            if host.received > 0 {
+                perfdata.push(format!(
+                    "{}_rta={:.3}ms;{:.3};{:.3};0;",
+                    target,
+                    host.rtt_ms(),
+                    warning.rtt_ms,
+                    critical.rtt_ms
+                ));
+            }
+            perfdata.push(format!(
+                "{}_pl={:.0}%;{:.0};{:.0};0;100",
+                target,
+                host.loss(),
+                warning.loss,
+                critical.loss
+            ));
+        }
+    }
+
+    let mut summary = if problems.is_empty() {
+        format!("{} targets ok", addrs.len())
+    } else {
+        problems.join(", ")
+    };
+    if expired {
+        summary.push_str(" (deadline reached)");
+    }
+    let line = format!("PING {} - {}|{}", status, summary, perfdata.join(" "));
+
+    (status, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(sent: u32, received: u32, rtt_ms: u128) -> HostResult {
+        HostResult {
+            sent,
+            received,
+            loss: sent - received,
+            latency_sum: rtt_ms * 1_000_000 * received as u128,
+        }
+    }
+
+    fn thresholds() -> (Threshold, Threshold) {
+        ("100,5%".parse().unwrap(), "500,50%".parse().unwrap())
+    }
+
+    #[test]
+    fn parse_thresholds() {
+        assert_eq!(
+            "100,5%".parse(),
+            Ok(Threshold {
+                rtt_ms: 100.0,
+                loss: 5.0
+            })
+        );
+        assert_eq!(
+            " 2.5ms , 0.5 ".parse(),
+            Ok(Threshold {
+                rtt_ms: 2.5,
+                loss: 0.5
+            })
+        );
+        for input in [
+            "100", "100;5%", "x,5%", "100,y%", "-1,5%", "100,101%", "100,-1%",
+        ] {
+            assert!(input.parse::<Threshold>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn thresholds_are_inclusive() {
+        let (warning, _) = thresholds();
+        assert!(!warning.exceeded(&host(100, 96, 99)));
+        assert!(warning.exceeded(&host(100, 95, 10)));
+        assert!(warning.exceeded(&host(100, 100, 100)));
+        // 没有回复时只比较丢失率, 延迟为 0 不代表没有超过阈值
+        let rtt_only = Threshold {
+            rtt_ms: 0.0,
+            loss: 100.0,
+        };
+        let dropped_locally = HostResult {
+            sent: 10,
+            loss: 5,
+            ..Default::default()
+        };
+        assert!(!rtt_only.exceeded(&dropped_locally));
+        assert!(rtt_only.exceeded(&host(10, 1, 0)));
+    }
+
+    #[test]
+    fn worst_status_wins() {
+        let (warning, critical) = thresholds();
+        assert_eq!(
+            host_status(&host(0, 0, 0), &warning, &critical),
+            Status::Unknown
+        );
+        assert_eq!(
+            host_status(&host(10, 10, 1), &warning, &critical),
+            Status::Ok
+        );
+        assert_eq!(
+            host_status(&host(10, 10, 200), &warning, &critical),
+            Status::Warning
+        );
+        assert_eq!(
+            host_status(&host(10, 0, 0), &warning, &critical),
+            Status::Critical
+        );
+
+        assert_eq!(Status::Ok.worst(Status::Unknown), Status::Unknown);
+        assert_eq!(Status::Unknown.worst(Status::Warning), Status::Warning);
+        assert_eq!(Status::Critical.worst(Status::Warning), Status::Critical);
+        assert_eq!(Status::Warning.worst(Status::Ok), Status::Warning);
+    }
+
+    #[test]
+    fn nagios_output() {
+        let (warning, critical) = thresholds();
+        let addrs: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let mut hosts = HashMap::new();
+        hosts.insert("10.0.0.1".to_string(), host(10, 10, 2));
+
+        let (status, line) = report(&addrs, &hosts, &warning, &critical, false);
+        assert_eq!(status, Status::Unknown);
+        assert_eq!(status.code(), 3);
+        assert_eq!(
+            line,
+            "PING UNKNOWN - 10.0.0.2: no results|10.0.0.1_rta=2.000ms;100.000;500.000;0; 10.0.0.1_pl=0%;5;50;0;100"
+        );
+
+        hosts.insert("10.0.0.2".to_string(), host(10, 0, 0));
+        let (status, line) = report(&addrs, &hosts, &warning, &critical, true);
+        assert_eq!(status.code(), 2);
+        assert_eq!(
+            line,
+            "PING CRITICAL - 10.0.0.2: rta -, loss 100% (deadline reached)|10.0.0.1_rta=2.000ms;100.000;500.000;0; 10.0.0.1_pl=0%;5;50;0;100 10.0.0.2_pl=100%;5;50;0;100"
+        );
+
+        hosts.insert("10.0.0.2".to_string(), host(10, 9, 120));
+        let (status, line) = report(&addrs, &hosts, &warning, &critical, false);
+        assert_eq!(status.code(), 1);
+        assert!(line.starts_with("PING WARNING - 10.0.0.2: rta 120.00ms, loss 10%|"));
+
+        hosts.insert("10.0.0.2".to_string(), host(10, 10, 3));
+        let (status, line) = report(&addrs, &hosts, &warning, &critical, false);
+        assert_eq!(status.code(), 0);
+        assert!(line.starts_with("PING OK - 2 targets ok|"));
+    }
+
+    #[test]
+    fn stop_before_deadline() {
+        let second = Duration::from_secs(1);
+        assert_eq!(send_deadline(second * 10, second, second), second * 8);
+        assert_eq!(send_deadline(second, second, second), Duration::ZERO);
+    }
+}
@@ -0,0 +1,221 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use log::{info, warn};
+
+use crate::mping::agent::{self, Report};
+
+// Cell 是矩阵中一个 (探测点, 目标) 在一个输出周期内的统计
+#[derive(Default, Clone)]
+struct Cell {
+    sent: u64,
+    received: u64,
+    loss: u64,
+    // 所有回复的延迟之和
+    latency_sum: u128,
+}
+
+impl Cell {
+    fn format(&self) -> String {
+        if self.sent == 0 {
+            return "-".to_string();
+        }
+        let loss = self.loss as f64 * 100.0 / self.sent as f64;
+        if self.received == 0 {
+            return format!("{:.1}%/-", loss);
+        }
+        let latency = (self.latency_sum / self.received as u128) as f64 / 1_000_000.0;
+        format!("{:.1}%/{:.2}ms", loss, latency)
+    }
+}
+
+// 探测点 -> 目标 -> 统计
+type Matrix = BTreeMap<String, BTreeMap<String, Cell>>;
+
+fn handle(stream: TcpStream, window: Duration, matrix: Arc<Mutex<Matrix>>) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    info!("agent {} connected", peer);
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        match agent::read_frame::<_, Report>(&mut reader) {
+            Ok(Some(report)) => {
+                // 只统计该长度窗口的结果, 忽略 agent 的 roll-up 窗口
+                if report.result.window != window {
+                    continue;
+                }
+                let tr = &report.result;
+                let mut matrix = matrix.lock().unwrap();
+                let cell = matrix
+                    .entry(report.source.clone())
+                    .or_default()
+                    .entry(tr.target.clone())
+                    .or_default();
+                cell.sent += tr.sent() as u64;
+                cell.received += tr.received as u64;
+                cell.loss += tr.loss as u64;
+                cell.latency_sum += tr.latency * tr.received as u128;
+            }
+            Ok(None) => {
+                info!("agent {} disconnected", peer);
+                return;
+            }
+            Err(e) => {
+                warn!("agent {} error: {}", peer, e);
+                return;
+            }
+        }
+    }
+}
+
+// 输出一个周期的矩阵, 每行是一个探测点, 每列是一个目标, 格式为 丢失率/平均延迟
+fn print_matrix(matrix: &Matrix) {
+    let targets: BTreeSet<&String> = matrix.values().flat_map(|row| row.keys()).collect();
+    let source_width = matrix
+        .keys()
+        .map(|s| s.len())
+        .max()
+        .unwrap_or(0)
+        .max("source".len());
+    let widths: Vec<usize> = targets.iter().map(|t| t.len().max(16)).collect();
+
+    println!("== {} ==", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    let mut line = format!("{:<width$}", "source", width = source_width);
+    for (target, width) in targets.iter().zip(&widths) {
+        line.push_str(&format!("  {:>width$}", target, width = width));
+    }
+    println!("{}", line);
+
+    let empty = Cell::default();
+    for (source, row) in matrix {
+        let mut line = format!("{:<width$}", source, width = source_width);
+        for (target, width) in targets.iter().zip(&widths) {
+            let cell = row.get(*target).unwrap_or(&empty);
+            line.push_str(&format!("  {:>width$}", cell.format(), width = width));
+        }
+        println!("{}", line);
+    }
+}
+
+/// 运行 collector, 接收 agent 上报的结果, 每个 `print_interval` 输出一次
+/// 探测点 × 目标的丢失率和延迟矩阵, 只统计长度为 `window` 的窗口
+pub fn run(listen: &str, window: Duration, print_interval: Duration) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    info!("collector listening on {}", listen);
+
+    let matrix: Arc<Mutex<Matrix>> = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let print_matrix_ref = matrix.clone();
+    thread::spawn(move || loop {
+        thread::sleep(print_interval);
+        // 保留已知的探测点和目标, 只清空统计, 没有数据的单元显示为 -
+        let snapshot = {
+            let mut matrix = print_matrix_ref.lock().unwrap();
+            let snapshot = matrix.clone();
+            for row in matrix.values_mut() {
+                for cell in row.values_mut() {
+                    *cell = Cell::default();
+                }
+            }
+            snapshot
+        };
+        if !snapshot.is_empty() {
+            print_matrix(&snapshot);
+        }
+    });
+
+    serve(listener, window, matrix);
+    Ok(())
+}
+
+// 接受 agent 的连接, 每个连接一个线程
+fn serve(listener: TcpListener, window: Duration, matrix: Arc<Mutex<Matrix>>) {
+    for stream in listener.incoming().flatten() {
+        let matrix = matrix.clone();
+        thread::spawn(move || handle(stream, window, matrix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mping::stat::TargetResult;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn result(target: &str, window: Duration) -> TargetResult {
+        TargetResult {
+            target: target.to_string(),
+            window,
+            received: 9,
+            loss: 1,
+            latency: 2_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn agent_buffers_until_collector_is_up() {
+        // 先找一个空闲的端口, collector 稍后才开始监听
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let window = Duration::from_secs(1);
+
+        let (tx, rx) = mpsc::channel();
+        let agent = thread::spawn(move || agent::run(rx, addr.to_string(), "probe".to_string()));
+        tx.send(result("10.0.0.1", window)).unwrap();
+        tx.send(result("10.0.0.1", window)).unwrap();
+        // roll-up 窗口不计入矩阵
+        tx.send(result("10.0.0.1", Duration::from_secs(60)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let matrix: Arc<Mutex<Matrix>> = Arc::new(Mutex::new(BTreeMap::new()));
+        let listener = TcpListener::bind(addr).unwrap();
+        let serve_matrix = matrix.clone();
+        thread::spawn(move || serve(listener, window, serve_matrix));
+        tx.send(result("10.0.0.2", window)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let cells = loop {
+            let cells: Vec<(String, u64, u64)> = matrix
+                .lock()
+                .unwrap()
+                .get("probe")
+                .map(|row| {
+                    row.iter()
+                        .map(|(target, cell)| (target.clone(), cell.sent, cell.loss))
+                        .collect()
+                })
+                .unwrap_or_default();
+            if cells.len() == 2 || Instant::now() > deadline {
+                break cells;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(
+            cells,
+            vec![
+                ("10.0.0.1".to_string(), 20, 2),
+                ("10.0.0.2".to_string(), 10, 1)
+            ]
+        );
+        let cell = matrix.lock().unwrap()["probe"]["10.0.0.1"].clone();
+        assert_eq!(cell.format(), "10.0%/2.00ms");
+
+        drop(tx);
+        agent.join().unwrap();
+    }
+}
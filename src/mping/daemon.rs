@@ -0,0 +1,769 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::mping::anomaly::AnomalyOption;
+use crate::mping::exec::parse_ips;
+use crate::mping::http;
+use crate::mping::multipath;
+use crate::mping::ping::{self, PingOption};
+use crate::mping::probe::ProbeType;
+use crate::mping::stat::TargetResult;
+use crate::mping::timestamping::TimestampingMode;
+
+// JobSpec 是一个 ping 任务的定义, 没有指定的字段使用与命令行相同的默认值
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobSpec {
+    // 目标地址, 格式与命令行相同, 例如 127.0.0.1、8.8.8.8/24、bing.com
+    pub targets: Vec<String>,
+    pub timeout_ms: u64,
+    pub ttl: u32,
+    pub tos: Option<u32>,
+    pub size: usize,
+    pub rate: u64,
+    pub jitter: f64,
+    pub rcvbuf: usize,
+    pub hmac_key: Option<String>,
+    pub delay: u64,
+    pub count: Option<i64>,
+    pub timestamping: TimestampingMode,
+    pub probe: ProbeType,
+    pub flows: u16,
+    pub adaptive: bool,
+    pub anomaly_sigmas: Option<f64>,
+    pub significance_windows: Option<usize>,
+    pub interval_ms: u64,
+    pub rollups_ms: Vec<u64>,
+}
+
+impl Default for JobSpec {
+    fn default() -> Self {
+        JobSpec {
+            targets: Vec::new(),
+            timeout_ms: 1000,
+            ttl: 64,
+            tos: None,
+            size: 64,
+            rate: 100,
+            jitter: 0.0,
+            rcvbuf: 4 * 1024 * 1024,
+            hmac_key: None,
+            delay: 3,
+            count: None,
+            timestamping: TimestampingMode::Auto,
+            probe: ProbeType::Echo,
+            flows: 1,
+            adaptive: false,
+            anomaly_sigmas: None,
+            significance_windows: None,
+            interval_ms: 1000,
+            rollups_ms: Vec::new(),
+        }
+    }
+}
+
+impl JobSpec {
+    fn validate(&self) -> anyhow::Result<Vec<std::net::IpAddr>> {
+        let addrs = parse_ips(&self.targets.join(","));
+        if addrs.is_empty() {
+            return Err(anyhow!("no valid targets"));
+        }
+        if self.rate == 0 {
+            return Err(anyhow!("rate must be positive"));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(anyhow!("jitter must be between 0 and 1"));
+        }
+        // 与 ping 会话的检查相同, 在创建任务时返回错误, 而不是启动后才失败
+        if self.size < ping::PAYLOAD_HEADER_LEN {
+            return Err(anyhow!(
+                "size must be at least {} bytes",
+                ping::PAYLOAD_HEADER_LEN
+            ));
+        }
+        if self.flows > multipath::MAX_FLOWS {
+            return Err(anyhow!(
+                "at most {} flows per target are supported",
+                multipath::MAX_FLOWS
+            ));
+        }
+        Ok(addrs)
+    }
+
+    fn ping_option(&self, ident: u32) -> PingOption {
+        PingOption {
+            timeout: Duration::from_millis(self.timeout_ms),
+            ttl: self.ttl,
+            tos: self.tos,
+            ident,
+            len: self.size,
+            rate: self.rate,
+            rate_for_all: false,
+            jitter: self.jitter,
+            rcvbuf: self.rcvbuf,
+            hmac_key: self.hmac_key.as_ref().map(|key| key.as_bytes().to_vec()),
+            delay: self.delay,
+            count: self.count,
+            timestamping: self.timestamping,
+            probe: self.probe,
+            flows: self.flows,
+            adaptive: self.adaptive,
+            anomaly: self.anomaly_sigmas.map(AnomalyOption::new_anomaly_option),
+            significance: self.significance_windows,
+            quiet_targets: false,
+            interval: Duration::from_millis(self.interval_ms),
+            rollups: self
+                .rollups_ms
+                .iter()
+                .map(|ms| Duration::from_millis(*ms))
+                .collect(),
+        }
+    }
+}
+
+// JobStatus 是任务的运行状态
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "state", content = "error")]
+pub enum JobStatus {
+    Running,
+    // 设置了 count 的任务发送完成后结束
+    Finished,
+    Failed(String),
+}
+
+// JobInfo 是 API 返回的任务信息
+#[derive(Clone, Debug, Serialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub spec: JobSpec,
+    pub status: JobStatus,
+}
+
+// 持久化到文件中的任务定义
+#[derive(Serialize, Deserialize)]
+struct SavedJob {
+    id: u64,
+    spec: JobSpec,
+}
+
+// Job 是一个正在运行的 ping 会话
+struct Job {
+    spec: JobSpec,
+    status: Arc<Mutex<JobStatus>>,
+    stop: Arc<AtomicBool>,
+    // 任务被删除或修改, ping 结束时 stop 也会被设置, 不能用来区分
+    cancelled: Arc<AtomicBool>,
+    // 订阅该任务结果的客户端
+    subscribers: Arc<Mutex<Vec<Sender<TargetResult>>>>,
+}
+
+impl Job {
+    fn info(&self, id: u64) -> JobInfo {
+        JobInfo {
+            id,
+            spec: self.spec.clone(),
+            status: self.status.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Daemon 管理所有的 ping 任务, 任务定义在每次变化后保存到 jobs_file
+pub struct Daemon {
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: Mutex<u64>,
+    // 下一个会话使用的 ICMP Id, 每个会话使用新的 Id, 修改任务时旧会话还没结束也不会冲突
+    next_ident: Mutex<u32>,
+    jobs_file: PathBuf,
+}
+
+impl Daemon {
+    // 创建一个 Daemon, 并重新启动 jobs_file 中保存的任务
+    pub fn new_daemon(jobs_file: &Path) -> anyhow::Result<Arc<Daemon>> {
+        let daemon = Arc::new(Daemon {
+            jobs: Mutex::new(BTreeMap::new()),
+            next_id: Mutex::new(1),
+            next_ident: Mutex::new(process::id()),
+            jobs_file: jobs_file.to_path_buf(),
+        });
+
+        if jobs_file.exists() {
+            let saved: Vec<SavedJob> = serde_json::from_str(&fs::read_to_string(jobs_file)?)?;
+            let mut jobs = daemon.jobs.lock().unwrap();
+            let mut next_id = daemon.next_id.lock().unwrap();
+            for job in saved {
+                match job.spec.validate() {
+                    Ok(addrs) => {
+                        info!("restore job {}: {:?}", job.id, job.spec.targets);
+                        *next_id = (*next_id).max(job.id + 1);
+                        let ident = daemon.allocate_ident(job.spec.flows);
+                        jobs.insert(job.id, start_job(job.id, ident, job.spec, addrs));
+                    }
+                    Err(e) => error!("skip saved job {}: {}", job.id, e),
+                }
+            }
+        }
+
+        Ok(daemon)
+    }
+
+    // 为一个新的会话分配 ICMP Id
+    // 多路径会话使用 ident 到 ident + flows - 1 的 Id, 按流标识数分配, 不同会话的 Id 不重叠
+    fn allocate_ident(&self, flows: u16) -> u32 {
+        let mut next_ident = self.next_ident.lock().unwrap();
+        let ident = *next_ident;
+        *next_ident = next_ident.wrapping_add(flows.max(1) as u32);
+        ident
+    }
+
+    fn list(&self) -> Vec<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, job)| job.info(*id))
+            .collect()
+    }
+
+    fn get(&self, id: u64) -> Option<JobInfo> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.info(id))
+    }
+
+    fn create(&self, spec: JobSpec) -> anyhow::Result<JobInfo> {
+        let addrs = spec.validate()?;
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+
+        let job = start_job(id, self.allocate_ident(spec.flows), spec, addrs);
+        let info = job.info(id);
+        self.jobs.lock().unwrap().insert(id, job);
+        self.save()?;
+        Ok(info)
+    }
+
+    // 修改任务时先停止旧的会话, 再用新的定义启动
+    fn update(&self, id: u64, spec: JobSpec) -> anyhow::Result<Option<JobInfo>> {
+        let addrs = spec.validate()?;
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(old) = jobs.get(&id) else {
+            return Ok(None);
+        };
+
+        // 已订阅的客户端继续接收新会话的结果
+        let subscribers = std::mem::take(&mut *old.subscribers.lock().unwrap());
+        let job = start_job(id, self.allocate_ident(spec.flows), spec, addrs);
+        job.subscribers.lock().unwrap().extend(subscribers);
+        let info = job.info(id);
+        jobs.insert(id, job);
+        drop(jobs);
+
+        self.save()?;
+        Ok(Some(info))
+    }
+
+    fn delete(&self, id: u64) -> anyhow::Result<bool> {
+        let removed = self.jobs.lock().unwrap().remove(&id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn subscribe(&self, id: u64) -> Option<Receiver<TargetResult>> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?;
+        let (tx, rx) = mpsc::channel();
+        if *job.status.lock().unwrap() == JobStatus::Running {
+            job.subscribers.lock().unwrap().push(tx);
+        }
+        Some(rx)
+    }
+
+    // 先写入临时文件再重命名, 避免写入一半时退出导致文件损坏
+    fn save(&self) -> anyhow::Result<()> {
+        let saved: Vec<SavedJob> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, job)| SavedJob {
+                id: *id,
+                spec: job.spec.clone(),
+            })
+            .collect();
+
+        let tmp = self.jobs_file.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&saved)?)?;
+        fs::rename(&tmp, &self.jobs_file)?;
+        Ok(())
+    }
+
+    // 处理一个连接上的请求, TCP 和 Unix socket 共用
+    fn handle<S: Read + Write>(&self, mut stream: S) -> anyhow::Result<()> {
+        let req = match http::read_request(&mut stream) {
+            Ok(req) => req,
+            Err(e) if e.downcast_ref::<http::BodyTooLarge>().is_some() => {
+                return Ok(http::write_response(
+                    &mut stream,
+                    413,
+                    &error_body(&e.to_string()),
+                )?);
+            }
+            Err(e) => return Err(e),
+        };
+        let path = req.path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        // 订阅结果时一直写入, 直到任务结束或客户端断开
+        if let (["jobs", id, "results"], "GET") = (segments.as_slice(), req.method.as_str()) {
+            let Some(rx) = id.parse().ok().and_then(|id| self.subscribe(id)) else {
+                return Ok(http::write_response(
+                    &mut stream,
+                    404,
+                    &error_body("job not found"),
+                )?);
+            };
+            http::write_stream_header(&mut stream)?;
+            for tr in rx {
+                let mut line = serde_json::to_string(&tr)?;
+                line.push('\n');
+                if stream
+                    .write_all(line.as_bytes())
+                    .and_then(|_| stream.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        let (status, body) = match self.route(&req.method, &segments, &req.body) {
+            Ok(resp) => resp,
+            Err(e) => (400, error_body(&e.to_string())),
+        };
+        http::write_response(&mut stream, status, &body)?;
+        Ok(())
+    }
+
+    fn route(&self, method: &str, segments: &[&str], body: &str) -> anyhow::Result<(u16, String)> {
+        let not_found = || Ok((404, error_body("job not found")));
+
+        match (segments, method) {
+            (["jobs"], "GET") => Ok((200, serde_json::to_string(&self.list())?)),
+            (["jobs"], "POST") => {
+                let info = self.create(serde_json::from_str(body)?)?;
+                Ok((201, serde_json::to_string(&info)?))
+            }
+            (["jobs", id], method) => {
+                let id: u64 = id.parse().map_err(|_| anyhow!("invalid job id: {}", id))?;
+                match method {
+                    "GET" => match self.get(id) {
+                        Some(info) => Ok((200, serde_json::to_string(&info)?)),
+                        None => not_found(),
+                    },
+                    "PUT" => match self.update(id, serde_json::from_str(body)?)? {
+                        Some(info) => Ok((200, serde_json::to_string(&info)?)),
+                        None => not_found(),
+                    },
+                    "DELETE" => {
+                        if self.delete(id)? {
+                            Ok((204, String::new()))
+                        } else {
+                            not_found()
+                        }
+                    }
+                    _ => Ok((405, error_body("method not allowed"))),
+                }
+            }
+            _ => Ok((404, error_body("not found"))),
+        }
+    }
+}
+
+fn error_body(msg: &str) -> String {
+    serde_json::json!({ "error": msg }).to_string()
+}
+
+// 在单独的线程中启动一个 ping 会话, 每个会话使用不同的 ICMP Id 区分回复
+fn start_job(id: u64, ident: u32, spec: JobSpec, addrs: Vec<std::net::IpAddr>) -> Job {
+    let status = Arc::new(Mutex::new(JobStatus::Running));
+    let stop = Arc::new(AtomicBool::new(false));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let subscribers: Arc<Mutex<Vec<Sender<TargetResult>>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let (tx, rx) = mpsc::channel::<TargetResult>();
+    let forward_subscribers = subscribers.clone();
+    thread::spawn(move || {
+        for tr in rx {
+            forward_subscribers
+                .lock()
+                .unwrap()
+                .retain(|s| s.send(tr.clone()).is_ok());
+        }
+        // 会话结束后断开所有订阅, 让客户端的流结束
+        forward_subscribers.lock().unwrap().clear();
+    });
+
+    let popt = spec.ping_option(ident);
+    let job_status = status.clone();
+    let job_stop = stop.clone();
+    let job_cancelled = cancelled.clone();
+    thread::spawn(move || {
+        let result = ping::ping_until(addrs, popt, false, Some(tx), job_stop);
+        let mut status = job_status.lock().unwrap();
+        match result {
+            Ok(()) if job_cancelled.load(Ordering::Relaxed) => {}
+            Ok(()) => {
+                info!("job {} finished", id);
+                *status = JobStatus::Finished;
+            }
+            Err(e) => {
+                error!("job {} failed: {}", id, e);
+                *status = JobStatus::Failed(e.to_string());
+            }
+        }
+    });
+
+    Job {
+        spec,
+        status,
+        stop,
+        cancelled,
+        subscribers,
+    }
+}
+
+/// 运行 daemon, 通过 TCP 地址 `listen` 和/或 Unix socket `socket` 提供 HTTP/JSON 接口:
+/// - `GET /jobs` 列出任务, `POST /jobs` 创建任务
+/// - `GET|PUT|DELETE /jobs/{id}` 查看、修改、删除任务
+/// - `GET /jobs/{id}/results` 以 NDJSON 流的形式返回任务的每个窗口的结果
+pub fn run(
+    listen: Option<String>,
+    socket: Option<PathBuf>,
+    jobs_file: &Path,
+) -> anyhow::Result<()> {
+    let daemon = Daemon::new_daemon(jobs_file)?;
+    let mut handles = Vec::new();
+
+    if let Some(path) = socket {
+        // 删除上次退出时遗留的 socket 文件
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        info!("daemon listening on unix:{}", path.display());
+        let daemon = daemon.clone();
+        handles.push(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let daemon = daemon.clone();
+                thread::spawn(move || {
+                    if let Err(e) = daemon.handle(stream) {
+                        error!("daemon request failed: {}", e);
+                    }
+                });
+            }
+        }));
+    }
+
+    if let Some(addr) = listen {
+        let listener = TcpListener::bind(&addr)?;
+        info!("daemon listening on http://{}", addr);
+        let daemon = daemon.clone();
+        handles.push(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let daemon = daemon.clone();
+                thread::spawn(move || {
+                    if let Err(e) = daemon.handle(stream) {
+                        error!("daemon request failed: {}", e);
+                    }
+                });
+            }
+        }));
+    }
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // 内存中的连接, 读取请求, 记录响应
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // 发送一个请求, 返回状态码和响应体
+    fn request(daemon: &Daemon, method: &str, path: &str, body: &str) -> (u16, String) {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        let mut stream = MockStream {
+            input: Cursor::new(raw.into_bytes()),
+            output: Vec::new(),
+        };
+        daemon.handle(&mut stream).unwrap();
+
+        let response = String::from_utf8(stream.output).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    fn temp_jobs_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mping-jobs-{}-{}.json", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // 发送一次探测后结束的任务
+    const JOB: &str = r#"{"targets":["127.0.0.1"],"count":1,"timeout_ms":200}"#;
+
+    #[test]
+    fn validate_limits() {
+        let spec = |json: &str| serde_json::from_str::<JobSpec>(json).unwrap().validate();
+        assert!(spec(JOB).is_ok());
+        assert!(spec(r#"{"targets":[]}"#).is_err());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"rate":0}"#).is_err());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"jitter":2}"#).is_err());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"size":51}"#).is_err());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"size":52}"#).is_ok());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"flows":32767}"#).is_ok());
+        assert!(spec(r#"{"targets":["127.0.0.1"],"flows":32768}"#).is_err());
+    }
+
+    #[test]
+    fn job_routes() {
+        let jobs_file = temp_jobs_file("routes");
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+
+        assert_eq!(
+            request(&daemon, "GET", "/jobs", ""),
+            (200, "[]".to_string())
+        );
+        let (status, body) = request(&daemon, "POST", "/jobs", JOB);
+        assert_eq!(status, 201);
+        let created: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(created["id"], 1);
+        assert_eq!(created["spec"]["targets"][0], "127.0.0.1");
+        assert_eq!(created["status"]["state"], "running");
+
+        let (status, body) = request(&daemon, "GET", "/jobs/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"],
+            1
+        );
+        let (status, body) = request(&daemon, "GET", "/jobs", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<Vec<serde_json::Value>>(&body)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let (status, body) = request(
+            &daemon,
+            "PUT",
+            "/jobs/1",
+            r#"{"targets":["127.0.0.2"],"count":1,"timeout_ms":200}"#,
+        );
+        assert_eq!(status, 200);
+        let updated: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(updated["spec"]["targets"][0], "127.0.0.2");
+
+        // 无效的任务定义和请求
+        assert_eq!(request(&daemon, "POST", "/jobs", "{").0, 400);
+        let (status, body) = request(
+            &daemon,
+            "POST",
+            "/jobs",
+            r#"{"targets":["127.0.0.1"],"size":8}"#,
+        );
+        assert_eq!(status, 400);
+        assert!(body.contains("size"), "{}", body);
+        assert_eq!(
+            request(
+                &daemon,
+                "PUT",
+                "/jobs/1",
+                r#"{"targets":["127.0.0.1"],"flows":40000}"#
+            )
+            .0,
+            400
+        );
+        assert_eq!(request(&daemon, "GET", "/jobs/x", "").0, 400);
+        assert_eq!(request(&daemon, "GET", "/jobs/9", "").0, 404);
+        assert_eq!(request(&daemon, "PUT", "/jobs/9", JOB).0, 404);
+        assert_eq!(request(&daemon, "PATCH", "/jobs/1", "").0, 405);
+        assert_eq!(request(&daemon, "GET", "/other", "").0, 404);
+        assert_eq!(request(&daemon, "GET", "/jobs/9/results", "").0, 404);
+
+        assert_eq!(
+            request(&daemon, "DELETE", "/jobs/1", ""),
+            (204, String::new())
+        );
+        assert_eq!(request(&daemon, "DELETE", "/jobs/1", "").0, 404);
+        assert_eq!(
+            request(&daemon, "GET", "/jobs", ""),
+            (200, "[]".to_string())
+        );
+
+        fs::remove_file(&jobs_file).unwrap();
+    }
+
+    // 需要创建 raw socket 的权限
+    #[test]
+    fn stream_results_until_job_ends() {
+        let jobs_file = temp_jobs_file("stream");
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+        assert_eq!(request(&daemon, "POST", "/jobs", JOB).0, 201);
+
+        let (status, body) = request(&daemon, "GET", "/jobs/1/results", "");
+        assert_eq!(status, 200);
+        let results: Vec<TargetResult> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].target, "127.0.0.1");
+        assert_eq!(results[0].received, 1);
+
+        // 流结束后任务的状态很快变为结束
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while daemon.get(1).unwrap().status == JobStatus::Running
+            && std::time::Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(daemon.get(1).unwrap().status, JobStatus::Finished);
+        // 已经结束的任务立即结束流
+        assert_eq!(
+            request(&daemon, "GET", "/jobs/1/results", ""),
+            (200, String::new())
+        );
+
+        fs::remove_file(&jobs_file).unwrap();
+    }
+
+    #[test]
+    fn jobs_persist_across_restarts() {
+        let jobs_file = temp_jobs_file("persist");
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+        for _ in 0..3 {
+            assert_eq!(request(&daemon, "POST", "/jobs", JOB).0, 201);
+        }
+        assert_eq!(request(&daemon, "DELETE", "/jobs/2", "").0, 204);
+        drop(daemon);
+
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+        let ids: Vec<u64> = daemon.list().iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(daemon.get(3).unwrap().spec.timeout_ms, 200);
+        // 新任务的编号接着保存的任务
+        let (_, body) = request(&daemon, "POST", "/jobs", JOB);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"],
+            4
+        );
+        drop(daemon);
+
+        // 无效的任务被跳过
+        fs::write(
+            &jobs_file,
+            r#"[{"id":5,"spec":{"targets":["127.0.0.1"],"size":1}},{"id":6,"spec":{"targets":["127.0.0.1"]}}]"#,
+        )
+        .unwrap();
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+        let ids: Vec<u64> = daemon.list().iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![6]);
+
+        fs::remove_file(&jobs_file).unwrap();
+    }
+
+    #[test]
+    fn sessions_get_distinct_idents() {
+        let jobs_file = std::env::temp_dir().join(format!("mping-jobs-{}.json", process::id()));
+        let _ = fs::remove_file(&jobs_file);
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+
+        let first = daemon.allocate_ident(1);
+        let second = daemon.allocate_ident(1);
+        assert_ne!(first as u16, second as u16);
+    }
+
+    #[test]
+    fn multipath_sessions_get_disjoint_idents() {
+        let jobs_file =
+            std::env::temp_dir().join(format!("mping-jobs-flows-{}.json", process::id()));
+        let _ = fs::remove_file(&jobs_file);
+        let daemon = Daemon::new_daemon(&jobs_file).unwrap();
+
+        // 每个流标识的 Id 是会话 Id 加流标识
+        let idents = |ident: u32, flows: u16| -> Vec<u16> {
+            (0..flows)
+                .map(|flow| (ident as u16).wrapping_add(flow))
+                .collect()
+        };
+        let first = idents(daemon.allocate_ident(4), 4);
+        let second = idents(daemon.allocate_ident(3), 3);
+        let third = idents(daemon.allocate_ident(0), 1);
+        for ident in &first {
+            assert!(!second.contains(ident) && !third.contains(ident));
+        }
+        for ident in &second {
+            assert!(!third.contains(ident));
+        }
+    }
+}
@@ -0,0 +1,203 @@
+#![cfg(target_os = "linux")]
+
+use std::cmp::Reverse;
+use std::fs;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use libc::{c_int, c_void, setsockopt, SOL_SOCKET, SO_RCVBUFFORCE, SO_RXQ_OVFL};
+use log::{info, warn};
+use socket2::Socket;
+
+// 设置接收缓冲区的大小, 超过 net.core.rmem_max 时尝试用 SO_RCVBUFFORCE (需要 CAP_NET_ADMIN)
+// size 为 0 时使用系统默认值
+pub fn setup_rcvbuf(socket: &Socket, size: usize) {
+    if size == 0 {
+        return;
+    }
+
+    if let Err(e) = socket.set_recv_buffer_size(size) {
+        warn!("failed to set SO_RCVBUF to {}: {}", size, e);
+    }
+    // 内核返回的是设置值的两倍, 其中一半用于簿记开销
+    if socket.recv_buffer_size().unwrap_or(0) / 2 < size {
+        let value = size.min(c_int::MAX as usize) as c_int;
+        let ret = unsafe {
+            setsockopt(
+                socket.as_raw_fd(),
+                SOL_SOCKET,
+                SO_RCVBUFFORCE,
+                &value as *const _ as *const c_void,
+                mem::size_of_val(&value) as u32,
+            )
+        };
+        if ret == -1 {
+            warn!(
+                "failed to set SO_RCVBUFFORCE to {}, raise net.core.rmem_max to allow a larger buffer",
+                size
+            );
+        }
+    }
+
+    info!(
+        "receive buffer: requested {} bytes, effective {} bytes",
+        size,
+        socket.recv_buffer_size().unwrap_or(0)
+    );
+}
+
+// 开启 SO_RXQ_OVFL, 之后每个收到的报文都带有接收队列累计丢弃数的控制消息
+pub fn enable_rxq_ovfl(socket: &Socket) -> bool {
+    let enable: c_int = 1;
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_RXQ_OVFL,
+            &enable as *const _ as *const c_void,
+            mem::size_of_val(&enable) as u32,
+        )
+    };
+    if ret == -1 {
+        warn!("failed to set SO_RXQ_OVFL, local drops will not be reported");
+        return false;
+    }
+    true
+}
+
+// IcmpCounters 是 /proc/net/snmp 中本机的 ICMP 接收计数
+#[derive(Default, Clone, Copy, Debug)]
+pub struct IcmpCounters {
+    pub in_errors: u64,
+    pub in_csum_errors: u64,
+}
+
+impl IcmpCounters {
+    // 读取当前的计数, 文件中 Icmp: 开头的第一行是字段名, 第二行是对应的值
+    pub fn sample() -> Option<IcmpCounters> {
+        let snmp = fs::read_to_string("/proc/net/snmp").ok()?;
+        let mut lines = snmp.lines().filter(|line| line.starts_with("Icmp:"));
+        let names: Vec<&str> = lines.next()?.split_whitespace().skip(1).collect();
+        let values: Vec<u64> = lines
+            .next()?
+            .split_whitespace()
+            .skip(1)
+            .map(|v| v.parse().unwrap_or(0))
+            .collect();
+
+        let get = |name: &str| {
+            names
+                .iter()
+                .position(|n| *n == name)
+                .and_then(|i| values.get(i).copied())
+                .unwrap_or(0)
+        };
+        Some(IcmpCounters {
+            in_errors: get("InErrors"),
+            in_csum_errors: get("InCsumErrors"),
+        })
+    }
+}
+
+// LocalDrops 是一个窗口内在本机发生的丢弃, 与网络路径上的丢包区分开
+// ICMP 错误计数是整个主机的, 不一定是本进程的回复, 只在日志中输出
+#[derive(Default, Clone, Copy, Debug)]
+pub struct LocalDrops {
+    // socket 接收队列溢出丢弃的报文数
+    pub socket: u32,
+    // 内核收到的有错误的 ICMP 报文数
+    pub icmp_errors: u32,
+    // 内核收到的校验和错误的 ICMP 报文数
+    pub icmp_csum_errors: u32,
+}
+
+// 把一个窗口的 socket 接收队列丢弃数按丢包数的比例分给各个目标, 每个目标最多分到它的丢包数
+// 被丢弃的是哪个目标的回复无法知道, 按比例分配后各目标之和就是本窗口的丢弃数 (不超过总丢包数)
+pub fn allocate(drops: u32, losses: &[u32]) -> Vec<u32> {
+    let total: u64 = losses.iter().map(|loss| *loss as u64).sum();
+    if drops as u64 >= total {
+        return losses.to_vec();
+    }
+
+    let mut shares: Vec<u32> = losses
+        .iter()
+        .map(|loss| (drops as u64 * *loss as u64 / total) as u32)
+        .collect();
+    // 余数按剩余的丢包数从多到少每个目标分一个
+    let mut remaining = drops - shares.iter().sum::<u32>();
+    let mut order: Vec<usize> = (0..losses.len()).collect();
+    order.sort_by_key(|i| Reverse(losses[*i] - shares[*i]));
+    for i in order {
+        if remaining == 0 {
+            break;
+        }
+        if shares[i] < losses[i] {
+            shares[i] += 1;
+            remaining -= 1;
+        }
+    }
+    shares
+}
+
+// DropTracker 根据累计计数计算每个窗口的增量
+pub struct DropTracker {
+    // read 线程从控制消息中读到的最新累计丢弃数
+    rxq_drops: Arc<AtomicU32>,
+    last_rxq_drops: u32,
+    last_icmp: Option<IcmpCounters>,
+}
+
+impl DropTracker {
+    pub fn new_tracker(rxq_drops: Arc<AtomicU32>) -> DropTracker {
+        DropTracker {
+            last_rxq_drops: rxq_drops.load(Ordering::Relaxed),
+            rxq_drops,
+            last_icmp: IcmpCounters::sample(),
+        }
+    }
+
+    // 返回上一次调用以来的本机丢弃数
+    pub fn take(&mut self) -> LocalDrops {
+        let rxq_drops = self.rxq_drops.load(Ordering::Relaxed);
+        let socket = rxq_drops.wrapping_sub(self.last_rxq_drops);
+        self.last_rxq_drops = rxq_drops;
+
+        let icmp = IcmpCounters::sample();
+        let (icmp_errors, icmp_csum_errors) = match (self.last_icmp, icmp) {
+            (Some(last), Some(now)) => (
+                now.in_errors.saturating_sub(last.in_errors) as u32,
+                now.in_csum_errors.saturating_sub(last.in_csum_errors) as u32,
+            ),
+            _ => (0, 0),
+        };
+        self.last_icmp = icmp;
+
+        LocalDrops {
+            socket,
+            icmp_errors,
+            icmp_csum_errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_by_loss() {
+        assert_eq!(allocate(0, &[3, 0, 5]), vec![0, 0, 0]);
+        assert_eq!(allocate(4, &[]), Vec::<u32>::new());
+        assert_eq!(allocate(4, &[0, 0]), vec![0, 0]);
+        // 丢弃数不少于总丢包数时全部是本机丢弃
+        assert_eq!(allocate(10, &[3, 0, 5]), vec![3, 0, 5]);
+        assert_eq!(allocate(4, &[2, 0, 6]), vec![1, 0, 3]);
+        // 余数分给剩余丢包数最多的目标
+        assert_eq!(allocate(2, &[1, 3, 0]), vec![0, 2, 0]);
+        let shares = allocate(3, &[1, 1, 2, 0]);
+        assert_eq!(shares.iter().sum::<u32>(), 3);
+        assert!(shares.iter().zip([1, 1, 2, 0]).all(|(s, l)| *s <= l));
+    }
+}
@@ -1,22 +1,47 @@
 #![cfg(target_os = "linux")]
 
+use std::collections::HashSet;
 use std::io::Write;
-use std::net::{IpAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use anyhow::Result;
-use chrono::Local;
-use clap::Parser;
+use chrono::{Local, NaiveDateTime, TimeZone};
+use clap::{Args, CommandFactory, Parser, Subcommand};
 
 use crate::mping;
+use crate::mping::agent;
+use crate::mping::alert::{self, AlertAction, AlertRule, Alerter};
+use crate::mping::anomaly::AnomalyOption;
+use crate::mping::check::{self, Status, Threshold};
+use crate::mping::collector;
+use crate::mping::daemon;
+use crate::mping::group::{self, Grouper, Grouping};
+use crate::mping::multipath;
+use crate::mping::otlp::{self, OtlpSink};
+use crate::mping::probe::ProbeType;
+use crate::mping::sink::{self, SinkQueue, SinkSpec};
+use crate::mping::stamp;
+use crate::mping::stat::TargetResult;
+use crate::mping::store::{self, Store};
+use crate::mping::sweep;
+use crate::mping::timestamping::TimestampingMode;
+use crate::mping::tui;
 use ipnetwork::IpNetwork;
 
 #[derive(Debug, Parser)]
 #[clap(
     name = "mping",
     version = "0.4.2",
-    about = "A multi-targets ping tool, which supports 10,000 packets/second."
+    about = "A multi-targets ping tool, which supports 10,000 packets/second.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Opt {
     #[clap(
@@ -37,7 +62,8 @@ struct Opt {
         short = 's',
         long = "size",
         default_value = "64",
-        help = "payload size"
+        value_parser = parse_size,
+        help = "payload size, at least 52 bytes for the probe header and the MAC"
     )]
     size: usize,
 
@@ -49,6 +75,27 @@ struct Opt {
     )]
     rate: u64,
 
+    #[clap(
+        long = "jitter",
+        default_value = "0",
+        value_parser = parse_jitter,
+        help = "random send jitter as a fraction of the probe spacing, 0 to 1"
+    )]
+    jitter: f64,
+
+    #[clap(
+        long = "rcvbuf",
+        default_value = "4194304",
+        help = "socket receive buffer size in bytes, 0 for the system default"
+    )]
+    rcvbuf: usize,
+
+    #[clap(
+        long = "hmac-key",
+        help = "key to authenticate replies with HMAC-SHA256, by default only a session cookie is checked. The MAC only covers fields carried by the request, so a request replayed or reflected back to this host still passes as a valid reply"
+    )]
+    hmac_key: Option<String>,
+
     #[clap(
         short = 'd',
         long = "delay",
@@ -60,18 +107,487 @@ struct Opt {
     #[clap(short = 'c', long = "count", help = "max packet count")]
     count: Option<i64>,
 
+    #[clap(
+        long = "timestamping",
+        value_enum,
+        default_value = "auto",
+        help = "timestamping mode"
+    )]
+    timestamping: TimestampingMode,
+
+    #[clap(
+        long = "probe",
+        value_enum,
+        default_value = "echo",
+        help = "probe type, timestamp also estimates one-way delay and clock offset"
+    )]
+    probe: ProbeType,
+
+    #[clap(
+        long = "flows",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..=multipath::MAX_FLOWS as i64),
+        help = "flow IDs per target to spread probes over ECMP paths, results are reported per target#flow"
+    )]
+    flows: u16,
+
+    #[clap(
+        long = "adaptive",
+        help = "lower the rate of targets whose loss drops at a lower rate, to detect ICMP rate limiting"
+    )]
+    adaptive: bool,
+
+    #[clap(
+        long = "anomaly",
+        help = "flag windows whose latency or loss is this many standard deviations above the learned baseline of the target"
+    )]
+    anomaly: Option<f64>,
+
+    #[clap(
+        long = "significant",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "only print windows whose loss or latency is significantly worse than the previous N windows, with 95% confidence"
+    )]
+    significant: Option<u64>,
+
+    #[clap(
+        short = 'i',
+        long = "interval",
+        default_value = "1s",
+        value_parser = parse_duration,
+        help = "aggregation interval, e.g. 100ms,10s,1m"
+    )]
+    interval: Duration,
+
+    #[clap(
+        long = "rollup",
+        value_delimiter = ',',
+        value_parser = parse_duration,
+        help = "roll-up windows computed from the same results, e.g. 1m,5m"
+    )]
+    rollups: Vec<Duration>,
+
+    #[clap(long = "tui", help = "show a live table instead of log lines")]
+    tui: bool,
+
+    #[clap(flatten)]
+    output: OutputOpt,
+
+    #[clap(
+        long = "report",
+        help = "collector address to stream results to, e.g. 10.0.0.1:9091"
+    )]
+    report: Option<String>,
+
+    #[clap(
+        long = "source",
+        help = "name of this vantage point in reports, default the hostname"
+    )]
+    source: Option<String>,
+
+    #[clap(
+        long = "group-by",
+        help = "aggregate results by prefix length, e.g. 24, or by each cidr argument with cidr"
+    )]
+    group_by: Option<Grouping>,
+
+    #[clap(
+        long = "group-worst",
+        default_value_t = 3,
+        help = "number of worst members shown for each group"
+    )]
+    group_worst: usize,
+
     #[clap(
         value_delimiter = ',',
         required = true,
         name = "ip address",
-        help = "one ip address or more, e.g. 127.0.0.1,8.8.8.8/24,bing.com"
+        help = "one ip address or more, separated by ',' or spaces, e.g. 127.0.0.1,8.8.8.8/24,bing.com"
     )]
     free: Vec<std::path::PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// OutputOpt 是 ping 和 stamp 共用的结果输出选项: 告警、保存、sink 和 OTLP
+#[derive(Debug, Args)]
+struct OutputOpt {
+    #[clap(
+        long = "alert",
+        value_delimiter = ',',
+        help = "alert rules, e.g. loss>5%:3,latency>100ms,bitflip,anomaly"
+    )]
+    alerts: Vec<AlertRule>,
+
+    #[clap(
+        long = "alert-command",
+        help = "command to run when an alert fires or resolves, with the event as JSON on stdin and MPING_ALERT_RULE, MPING_ALERT_TARGET and MPING_ALERT_STATE set"
+    )]
+    alert_commands: Vec<String>,
+
+    #[clap(long = "alert-webhook", help = "URL to POST alert events to as JSON")]
+    alert_webhooks: Vec<String>,
+
+    #[clap(long = "store", help = "directory to save per-window results in")]
+    store: Option<PathBuf>,
+
+    #[clap(
+        long = "retention",
+        default_value = "7d",
+        value_parser = parse_duration,
+        help = "how long saved results are kept"
+    )]
+    retention: Duration,
+
+    #[clap(
+        long = "sink",
+        help = "write results to influx-file:<path>, influx-udp:<addr>, statsd:<addr> or graphite:<addr>"
+    )]
+    sinks: Vec<SinkSpec>,
+
+    #[clap(
+        long = "sink-queue",
+        default_value_t = 1024,
+        help = "results buffered for each sink before new ones are dropped"
+    )]
+    sink_queue: usize,
+
+    #[clap(
+        long = "otlp",
+        help = "OTLP/HTTP endpoint to export metrics to, e.g. http://127.0.0.1:4318"
+    )]
+    otlp: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[clap(about = "Query results saved with --store")]
+    Query(QueryOpt),
+    #[clap(about = "Run as a daemon managing ping jobs over an HTTP/JSON API")]
+    Daemon(DaemonOpt),
+    #[clap(about = "Collect results reported by agents running with --report")]
+    Collect(CollectOpt),
+    #[clap(about = "Probe every address in the given ranges --count times and list alive hosts")]
+    Sweep(SweepOpt),
+    #[clap(
+        about = "Check targets against thresholds, with monitoring plugin output and exit codes"
+    )]
+    Check(CheckOpt),
+    #[clap(about = "Measure round-trip and one-way delay to STAMP (RFC 8762) reflectors")]
+    Stamp(StampOpt),
+    #[clap(about = "Run a STAMP (RFC 8762) session-reflector")]
+    Reflect(ReflectOpt),
+}
+
+#[derive(Debug, Args)]
+struct SweepOpt {
+    #[clap(
+        short = 'c',
+        long = "count",
+        default_value = "2",
+        help = "probes per address"
+    )]
+    count: i64,
+
+    #[clap(
+        short = 'w',
+        long = "timeout",
+        default_value = "1",
+        help = "timeout in seconds"
+    )]
+    timeout: u64,
+
+    #[clap(
+        short = 'r',
+        long = "rate",
+        default_value = "1000",
+        help = "total rate in packets/second across all addresses"
+    )]
+    rate: u64,
+
+    #[clap(short = 't', long = "ttl", default_value = "64", help = "time to live")]
+    ttl: u32,
+
+    #[clap(
+        short = 'x',
+        long = "exclude",
+        value_delimiter = ',',
+        help = "addresses, ranges or CIDRs to leave out"
+    )]
+    exclude: Vec<String>,
+
+    #[clap(
+        long = "skip-network-broadcast",
+        help = "skip the network and broadcast address of each IPv4 CIDR"
+    )]
+    skip_network_broadcast: bool,
+
+    #[clap(
+        short = 'a',
+        long = "alive",
+        conflicts_with = "unreachable",
+        help = "only show alive addresses"
+    )]
+    alive: bool,
+
+    #[clap(
+        short = 'u',
+        long = "unreachable",
+        help = "only show unreachable addresses"
+    )]
+    unreachable: bool,
+
+    #[clap(
+        value_delimiter = ',',
+        required = true,
+        name = "targets",
+        help = "CIDRs, ranges or addresses, e.g. 10.0.0.0/24,10.0.1.1-50,bing.com"
+    )]
+    targets: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct CheckOpt {
+    #[clap(
+        short = 'w',
+        long = "warning",
+        default_value = "100,20%",
+        help = "warning threshold as <rtt ms>,<loss>%"
+    )]
+    warning: Threshold,
+
+    #[clap(
+        short = 'c',
+        long = "critical",
+        default_value = "500,60%",
+        help = "critical threshold as <rtt ms>,<loss>%"
+    )]
+    critical: Threshold,
+
+    #[clap(
+        short = 'p',
+        long = "packets",
+        default_value = "5",
+        help = "probes per target"
+    )]
+    packets: i64,
+
+    #[clap(
+        short = 'r',
+        long = "rate",
+        default_value = "1",
+        help = "rate in packets/second per target"
+    )]
+    rate: u64,
+
+    #[clap(
+        long = "timeout",
+        default_value = "1",
+        help = "timeout of each probe in seconds"
+    )]
+    timeout: u64,
+
+    #[clap(
+        short = 't',
+        long = "deadline",
+        default_value = "10s",
+        value_parser = parse_duration,
+        help = "stop and report what was collected after this long"
+    )]
+    deadline: Duration,
+
+    #[clap(
+        value_delimiter = ',',
+        required = true,
+        name = "targets",
+        help = "CIDRs, ranges or addresses, e.g. 10.0.0.1,10.0.1.0/30,bing.com"
+    )]
+    targets: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct StampOpt {
+    #[clap(
+        short = 'w',
+        long = "timeout",
+        default_value = "1",
+        help = "timeout in seconds"
+    )]
+    timeout: u64,
+
+    #[clap(short = 't', long = "ttl", default_value = "64", help = "time to live")]
+    ttl: u32,
+
+    #[clap(short = 'z', long = "tos", help = "type of service")]
+    tos: Option<u32>,
+
+    #[clap(
+        short = 's',
+        long = "size",
+        default_value = "44",
+        help = "test packet size, at least 44"
+    )]
+    size: usize,
+
+    #[clap(
+        short = 'r',
+        long = "rate",
+        default_value = "10",
+        help = "rate in packets/second per reflector"
+    )]
+    rate: u64,
+
+    #[clap(
+        short = 'd',
+        long = "delay",
+        default_value = "3",
+        help = "delay in seconds"
+    )]
+    delay: u64,
+
+    #[clap(short = 'c', long = "count", help = "max packet count")]
+    count: Option<i64>,
+
+    #[clap(
+        long = "flows",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "source ports per reflector to spread probes over ECMP paths, results are reported per target#flow"
+    )]
+    flows: u16,
+
+    #[clap(
+        short = 'i',
+        long = "interval",
+        default_value = "1s",
+        value_parser = parse_duration,
+        help = "aggregation interval, e.g. 100ms,10s,1m"
+    )]
+    interval: Duration,
+
+    #[clap(
+        value_delimiter = ',',
+        required = true,
+        name = "reflectors",
+        help = "IPv4 reflector addresses with an optional port, default 862, e.g. 10.0.0.1,10.0.0.2:8620"
+    )]
+    reflectors: Vec<String>,
+
+    #[clap(flatten)]
+    output: OutputOpt,
+}
+
+#[derive(Debug, Args)]
+struct ReflectOpt {
+    #[clap(
+        long = "listen",
+        default_value = "0.0.0.0:862",
+        help = "UDP address to reflect test packets on"
+    )]
+    listen: String,
+}
+
+#[derive(Debug, Args)]
+struct CollectOpt {
+    #[clap(
+        long = "listen",
+        default_value = "0.0.0.0:9091",
+        help = "TCP address to accept agents on"
+    )]
+    listen: String,
+
+    #[clap(
+        short = 'i',
+        long = "interval",
+        default_value = "1s",
+        value_parser = parse_duration,
+        help = "aggregation interval used by the agents"
+    )]
+    interval: Duration,
+
+    #[clap(
+        long = "print-interval",
+        default_value = "10s",
+        value_parser = parse_duration,
+        help = "how often the source x target matrix is printed"
+    )]
+    print_interval: Duration,
+}
+
+#[derive(Debug, Args)]
+struct DaemonOpt {
+    #[clap(
+        long = "listen",
+        help = "TCP address to serve the API on, e.g. 127.0.0.1:9090"
+    )]
+    listen: Option<String>,
+
+    #[clap(long = "socket", help = "Unix socket path to serve the API on")]
+    socket: Option<PathBuf>,
+
+    #[clap(
+        long = "jobs-file",
+        default_value = "mping-jobs.json",
+        help = "file the job definitions are saved in"
+    )]
+    jobs_file: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct QueryOpt {
+    #[clap(long = "store", help = "directory the results were saved in")]
+    store: PathBuf,
+
+    #[clap(
+        long = "from",
+        default_value = "1h",
+        value_parser = parse_time,
+        help = "start time, e.g. \"2024-01-02 15:04:05\" or 1h for one hour ago"
+    )]
+    from: u64,
+
+    #[clap(long = "to", value_parser = parse_time, help = "end time, default now")]
+    to: Option<u64>,
+
+    #[clap(long = "target", help = "only targets containing this string")]
+    target: Option<String>,
+
+    #[clap(
+        long = "worst",
+        help = "only show the N targets with the highest loss rate"
+    )]
+    worst: Option<usize>,
+
+    #[clap(long = "raw", help = "print every saved window instead of aggregates")]
+    raw: bool,
 }
 
 #[cfg(target_os = "linux")]
 pub fn run() -> Result<(), anyhow::Error> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+    // 检查模式的参数错误也按监控插件的约定以 UNKNOWN 退出
+    // 忽略错误再解析一次, 由 clap 判断出错的参数是否属于 check 子命令
+    let opt = Opt::try_parse().unwrap_or_else(|e| {
+        let matches = Opt::command().ignore_errors(true).get_matches();
+        if e.use_stderr() && matches.subcommand_name() == Some("check") {
+            let _ = e.print();
+            process::exit(Status::Unknown.code());
+        }
+        e.exit()
+    });
+
+    // 终端界面模式下默认关闭日志, 避免破坏界面, 扫描和检查模式只输出警告
+    let default_filter = if opt.tui {
+        "off"
+    } else if matches!(
+        opt.command,
+        Some(Command::Sweep(_)) | Some(Command::Check(_))
+    ) {
+        "warn"
+    } else {
+        "info"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -83,7 +599,36 @@ pub fn run() -> Result<(), anyhow::Error> {
         })
         .init();
 
-    let opt = Opt::parse();
+    match opt.command {
+        Some(Command::Query(q)) => {
+            return store::query(&store::Query {
+                dir: q.store,
+                from: q.from,
+                to: q
+                    .to
+                    .unwrap_or_else(|| Local::now().timestamp_millis() as u64),
+                target: q.target,
+                worst: q.worst,
+                raw: q.raw,
+            });
+        }
+        Some(Command::Daemon(d)) => {
+            // 两者都没有指定时监听本机地址
+            let listen = match (&d.listen, &d.socket) {
+                (None, None) => Some("127.0.0.1:9090".to_string()),
+                _ => d.listen,
+            };
+            return daemon::run(listen, d.socket, &d.jobs_file);
+        }
+        Some(Command::Collect(c)) => {
+            return collector::run(&c.listen, c.interval, c.print_interval);
+        }
+        Some(Command::Sweep(s)) => process::exit(run_sweep(s)),
+        Some(Command::Check(c)) => process::exit(run_check(c)),
+        Some(Command::Stamp(s)) => return run_stamp(s),
+        Some(Command::Reflect(r)) => return stamp::run_reflector(&r.listen),
+        None => {}
+    }
 
     if opt.free.is_empty() {
         println!("Please input ip address");
@@ -92,8 +637,21 @@ pub fn run() -> Result<(), anyhow::Error> {
 
     let _ = opt.count;
 
-    let addrs = opt.free.last().unwrap().to_string_lossy();
-    let ip_addrs = parse_ips(&addrs);
+    // clap 已经按 ',' 把目标拆成多个参数, 每个参数都要解析, 只取最后一个会丢掉前面的目标
+    // 按 cidr 分组时每个参数就是一个组, 所以保留参数和它展开后的地址
+    let specs: Vec<(String, Vec<IpAddr>)> = opt
+        .free
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .map(|s| {
+            let addrs = parse_target(&s, false).unwrap_or_else(|e| {
+                eprintln!("{}: {}", s, e);
+                Vec::new()
+            });
+            (s, addrs)
+        })
+        .collect();
+    let ip_addrs: Vec<IpAddr> = specs.iter().flat_map(|(_, addrs)| addrs.clone()).collect();
 
     let timeout = Duration::from_secs(opt.timeout);
     let pid = process::id();
@@ -106,36 +664,603 @@ pub fn run() -> Result<(), anyhow::Error> {
         len: opt.size,
         rate: opt.rate,
         rate_for_all: false,
+        jitter: opt.jitter,
+        rcvbuf: opt.rcvbuf,
+        hmac_key: opt.hmac_key.map(String::into_bytes),
         delay: opt.delay,
         count: opt.count,
+        timestamping: opt.timestamping,
+        probe: opt.probe,
+        flows: opt.flows,
+        adaptive: opt.adaptive,
+        anomaly: opt.anomaly.map(AnomalyOption::new_anomaly_option),
+        significance: opt.significant.map(|windows| windows as usize),
+        quiet_targets: opt.group_by.is_some(),
+        interval: opt.interval,
+        rollups: opt.rollups,
+    };
+    // 每个结果的消费者都有自己的通道, 由 fan_out 分发
+    // ping 返回后等待所有消费者处理完剩余的结果再退出
+    let mut consumers = Vec::new();
+    let mut handles = Vec::new();
+
+    let grouper = opt
+        .group_by
+        .clone()
+        .map(|grouping| Grouper::new_grouper(grouping, &specs, opt.group_worst, opt.interval));
+    let source = opt.source.clone().unwrap_or_else(agent::hostname);
+    let sinks = spawn_outputs(
+        opt.output,
+        opt.interval,
+        grouper.clone(),
+        &source,
+        &mut consumers,
+        &mut handles,
+    )?;
+
+    // 把结果上报给 collector
+    if let Some(collector) = opt.report {
+        let (agent_tx, agent_rx) = mpsc::channel();
+        handles.push(thread::spawn(move || {
+            agent::run(agent_rx, collector, source)
+        }));
+        consumers.push(agent_tx);
+    }
+
+    // 按组汇总结果, 这时不再输出每个地址的统计行, 见 quiet_targets
+    if let Some(grouper) = grouper {
+        let (group_tx, group_rx) = mpsc::channel();
+        handles.push(thread::spawn(move || group::run(group_rx, grouper)));
+        consumers.push(group_tx);
+    }
+
+    // 终端界面在单独的线程中运行, 用户退出界面时结束进程
+    let stop = Arc::new(AtomicBool::new(false));
+    if opt.tui {
+        let (tui_tx, tui_rx) = mpsc::channel();
+        let tui_stop = stop.clone();
+        let window = opt.interval;
+        handles.push(thread::spawn(move || {
+            let result = tui::run(tui_rx, window, tui_stop.clone());
+            if let Err(e) = &result {
+                eprintln!("tui error: {}", e);
+            }
+            if !tui_stop.load(Ordering::Relaxed) {
+                process::exit(if result.is_ok() { 0 } else { 1 });
+            }
+        }));
+        consumers.push(tui_tx);
+    }
+
+    let (tx, fan_out_handle) = fan_out(consumers, sinks).unzip();
+    let result = mping::ping::ping(ip_addrs, popt, !opt.tui, tx);
+
+    // ping 结束后关闭终端界面, 统计线程退出后 fan_out 和所有消费者的通道依次关闭
+    stop.store(true, Ordering::Relaxed);
+    if let Some(handle) = fan_out_handle {
+        let _ = handle.join();
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    result
+}
+
+// 运行扫描, 返回进程的退出码
+fn run_sweep(s: SweepOpt) -> i32 {
+    let mut bad_target = false;
+    let mut expand = |specs: &[String]| {
+        let mut ips = Vec::new();
+        for spec in specs {
+            match parse_target(spec, s.skip_network_broadcast) {
+                Ok(addrs) => ips.extend(addrs),
+                Err(e) => {
+                    eprintln!("{}: {}", spec, e);
+                    bad_target = true;
+                }
+            }
+        }
+        ips
+    };
+
+    let excluded: HashSet<IpAddr> = expand(&s.exclude).into_iter().collect();
+    let mut seen = HashSet::new();
+    let addrs: Vec<IpAddr> = expand(&s.targets)
+        .into_iter()
+        .filter(|ip| !excluded.contains(ip) && seen.insert(*ip))
+        .collect();
+    if addrs.is_empty() {
+        eprintln!("no addresses to sweep");
+        return sweep::EXIT_BAD_TARGET;
+    }
+
+    let timeout = Duration::from_secs(s.timeout);
+    let popt = mping::ping::PingOption {
+        timeout,
+        ttl: s.ttl,
+        tos: None,
+        ident: process::id(),
+        len: 64,
+        rate: s.rate,
+        rate_for_all: true,
+        jitter: 0.0,
+        rcvbuf: 4 * 1024 * 1024,
+        hmac_key: None,
+        delay: sweep::delay_for(timeout),
+        count: Some(s.count),
+        timestamping: TimestampingMode::Auto,
+        probe: ProbeType::Echo,
+        flows: 1,
+        adaptive: false,
+        anomaly: None,
+        significance: None,
+        quiet_targets: false,
+        interval: Duration::from_secs(1),
+        rollups: Vec::new(),
+    };
+    let show = if s.alive {
+        sweep::Show::Alive
+    } else if s.unreachable {
+        sweep::Show::Unreachable
+    } else {
+        sweep::Show::All
     };
-    mping::ping::ping(ip_addrs, popt, true, None)
+
+    match sweep::run(addrs, popt, show) {
+        Err(e) => {
+            eprintln!("sweep failed: {}", e);
+            sweep::EXIT_ERROR
+        }
+        Ok(_) if bad_target => sweep::EXIT_BAD_TARGET,
+        Ok(0) => sweep::EXIT_ALL_ALIVE,
+        Ok(_) => sweep::EXIT_UNREACHABLE,
+    }
 }
 
-fn parse_ips(input: &str) -> Vec<IpAddr> {
+// 运行检查, 返回监控插件的退出码, 无法解析目标或运行出错时为 UNKNOWN
+fn run_check(c: CheckOpt) -> i32 {
+    let mut addrs = Vec::new();
+    for spec in &c.targets {
+        match parse_target(spec, false) {
+            Ok(ips) => addrs.extend(ips),
+            Err(e) => {
+                println!("PING UNKNOWN - {}: {}", spec, e);
+                return Status::Unknown.code();
+            }
+        }
+    }
+
+    let timeout = Duration::from_secs(c.timeout);
+    let popt = mping::ping::PingOption {
+        timeout,
+        ttl: 64,
+        tos: None,
+        ident: process::id(),
+        len: 64,
+        rate: c.rate,
+        rate_for_all: false,
+        jitter: 0.0,
+        rcvbuf: 4 * 1024 * 1024,
+        hmac_key: None,
+        delay: sweep::delay_for(timeout),
+        count: Some(c.packets),
+        timestamping: TimestampingMode::Auto,
+        probe: ProbeType::Echo,
+        flows: 1,
+        adaptive: false,
+        anomaly: None,
+        significance: None,
+        quiet_targets: false,
+        interval: Duration::from_secs(1),
+        rollups: Vec::new(),
+    };
+
+    match check::run(addrs, popt, c.deadline, c.warning, c.critical) {
+        Ok(status) => status.code(),
+        Err(e) => {
+            println!("PING UNKNOWN - {}", e);
+            Status::Unknown.code()
+        }
+    }
+}
+
+// 运行 STAMP Session-Sender, 每个窗口输出与 ping 相同的统计
+fn run_stamp(s: StampOpt) -> Result<()> {
+    let mut addrs = Vec::new();
+    for spec in &s.reflectors {
+        match stamp::parse_reflector(spec) {
+            Some(addr) => addrs.push(addr),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "{}: invalid or unresolvable IPv4 reflector",
+                    spec
+                ))
+            }
+        }
+    }
+
+    let popt = mping::ping::PingOption {
+        timeout: Duration::from_secs(s.timeout),
+        ttl: s.ttl,
+        tos: s.tos,
+        ident: process::id(),
+        len: s.size.max(stamp::STAMP_LEN),
+        rate: s.rate,
+        rate_for_all: false,
+        jitter: 0.0,
+        rcvbuf: 4 * 1024 * 1024,
+        hmac_key: None,
+        delay: s.delay,
+        count: s.count,
+        timestamping: TimestampingMode::Software,
+        probe: ProbeType::Echo,
+        flows: s.flows,
+        adaptive: false,
+        anomaly: None,
+        significance: None,
+        quiet_targets: false,
+        interval: s.interval,
+        rollups: Vec::new(),
+    };
+
+    let mut consumers = Vec::new();
+    let mut handles = Vec::new();
+    let sinks = spawn_outputs(
+        s.output,
+        s.interval,
+        None,
+        &agent::hostname(),
+        &mut consumers,
+        &mut handles,
+    )?;
+
+    let (tx, fan_out_handle) = fan_out(consumers, sinks).unzip();
+    let result = stamp::run_sender(addrs, popt, true, tx, Arc::new(AtomicBool::new(false)));
+    if let Some(handle) = fan_out_handle {
+        let _ = handle.join();
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    result
+}
+
+// 按输出选项启动告警和保存的消费者, 以及 sink 和 OTLP 的队列
+// 消费者的通道加入 consumers, 线程加入 handles, 返回 sink 的队列, 由 fan_out 分发结果
+fn spawn_outputs(
+    out: OutputOpt,
+    interval: Duration,
+    grouper: Option<Grouper>,
+    source: &str,
+    consumers: &mut Vec<Sender<TargetResult>>,
+    handles: &mut Vec<JoinHandle<()>>,
+) -> Result<Vec<SinkQueue>> {
+    // 配置了告警规则时, 由单独的线程检查每个窗口的结果
+    if !out.alerts.is_empty() {
+        let mut actions: Vec<AlertAction> = out
+            .alert_commands
+            .into_iter()
+            .map(AlertAction::Command)
+            .collect();
+        actions.extend(out.alert_webhooks.into_iter().map(AlertAction::Webhook));
+
+        let alerter = Alerter::new_alerter(out.alerts, actions, interval);
+        let (alert_tx, alert_rx) = mpsc::channel();
+        handles.push(thread::spawn(move || alert::run(alert_rx, alerter)));
+        consumers.push(alert_tx);
+    }
+
+    // 保存每个窗口的结果
+    if let Some(dir) = &out.store {
+        let store = Store::open_store(dir, out.retention, interval)?;
+        let (store_tx, store_rx) = mpsc::channel();
+        handles.push(thread::spawn(move || store::run(store_rx, store)));
+        consumers.push(store_tx);
+    }
+
+    // 每个 sink 有自己的线程和有界队列, 慢的 sink 只会丢弃自己的结果
+    let mut sinks = Vec::new();
+    for spec in &out.sinks {
+        sinks.push(sink::spawn(spec, out.sink_queue)?);
+    }
+    if let Some(endpoint) = &out.otlp {
+        let url = otlp::metrics_url(endpoint)?;
+        let otlp_sink = OtlpSink::new_otlp_sink(url.clone(), interval, grouper, source);
+        sinks.push(sink::spawn_sink(
+            format!("otlp:{}", url),
+            Box::new(otlp_sink),
+            out.sink_queue,
+        ));
+    }
+
+    Ok(sinks)
+}
+
+// 把 ping 的结果分发给所有消费者, 没有消费者时返回 None
+fn fan_out(
+    consumers: Vec<Sender<TargetResult>>,
+    sinks: Vec<SinkQueue>,
+) -> Option<(Sender<TargetResult>, JoinHandle<()>)> {
+    if consumers.is_empty() && sinks.is_empty() {
+        return None;
+    }
+
+    let (tx, rx) = mpsc::channel::<TargetResult>();
+    let handle = thread::spawn(move || {
+        for tr in rx {
+            for consumer in &consumers {
+                let _ = consumer.send(tr.clone());
+            }
+            for sink in &sinks {
+                sink.offer(tr.clone());
+            }
+        }
+        // 统计线程退出后关闭 sink 的队列, 等待最后一批结果写完
+        for sink in sinks {
+            sink.close();
+        }
+    });
+
+    Some((tx, handle))
+}
+
+pub fn parse_ips(input: &str) -> Vec<IpAddr> {
     let mut ips = Vec::new();
 
     for s in input.split(',') {
-        match s.parse::<IpNetwork>() {
-            Ok(network) => {
-                for ip in network.iter() {
-                    ips.push(ip);
-                }
+        if let Ok(addrs) = parse_target(s, false) {
+            ips.extend(addrs);
+        }
+    }
+
+    return ips;
+}
+
+// 一个目标最多展开的地址数, 避免 0.0.0.0/0 这样的目标展开出几十亿个地址
+const MAX_EXPANDED: u64 = 1 << 20;
+
+fn too_many_addresses() -> String {
+    format!("expands to more than {} addresses", MAX_EXPANDED)
+}
+
+// 解析一个目标: CIDR、a.b.c.d-e 或 a.b.c.d-a.b.c.e 形式的地址范围、IP 地址或主机名
+// 无法解析或展开的地址超过 MAX_EXPANDED 时返回错误
+// skip_network_broadcast 为 true 时跳过 IPv4 CIDR 中的网络地址和广播地址 (/31 和 /32 除外)
+pub fn parse_target(s: &str, skip_network_broadcast: bool) -> Result<Vec<IpAddr>, String> {
+    if let Some(ips) = parse_range(s) {
+        return ips;
+    }
+
+    match s.parse::<IpNetwork>() {
+        Ok(network) => {
+            let bits = if network.is_ipv4() { 32 } else { 128 };
+            let host_bits = bits - network.prefix() as u32;
+            if host_bits >= 64 || 1u64 << host_bits > MAX_EXPANDED {
+                return Err(too_many_addresses());
             }
-            Err(_) => {
-                if let Ok(ip) = s.parse::<IpAddr>() {
-                    ips.push(ip);
-                } else if let Ok(addrs) = (s, 0).to_socket_addrs() {
-                    for addr in addrs {
-                        if let IpAddr::V4(ipv4) = addr.ip() {
-                            ips.push(IpAddr::V4(ipv4));
-                            break;
-                        }
-                    }
+            let skip = skip_network_broadcast && network.is_ipv4() && network.prefix() < 31;
+            Ok(network
+                .iter()
+                .filter(|ip| !skip || (*ip != network.network() && *ip != network.broadcast()))
+                .collect())
+        }
+        Err(_) => {
+            let invalid = || "invalid or unresolvable target".to_string();
+            let addrs = (s, 0).to_socket_addrs().map_err(|_| invalid())?;
+            for addr in addrs {
+                if let IpAddr::V4(ipv4) = addr.ip() {
+                    return Ok(vec![IpAddr::V4(ipv4)]);
                 }
             }
+            Err(invalid())
         }
     }
+}
 
-    return ips;
+// 解析 a.b.c.d-e 或 a.b.c.d-a.b.c.e 形式的 IPv4 地址范围, 不是这种形式时返回 None
+fn parse_range(s: &str) -> Option<Result<Vec<IpAddr>, String>> {
+    let (start, end) = s.split_once('-')?;
+    let start: Ipv4Addr = start.parse().ok()?;
+    let end: Ipv4Addr = match end.parse::<u8>() {
+        Ok(last) => {
+            let [a, b, c, _] = start.octets();
+            Ipv4Addr::new(a, b, c, last)
+        }
+        Err(_) => end.parse().ok()?,
+    };
+    if start > end {
+        return Some(Err(format!("range end {} is before start {}", end, start)));
+    }
+    let (start, end) = (u32::from(start), u32::from(end));
+    if (end - start) as u64 + 1 > MAX_EXPANDED {
+        return Some(Err(too_many_addresses()));
+    }
+
+    Some(Ok((start..=end)
+        .map(|ip| IpAddr::V4(Ipv4Addr::from(ip)))
+        .collect()))
+}
+
+// 解析带单位的时间, 支持 ms、s、m、h、d, 没有单位时按秒处理, 不能为 0
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let duration = parse_span(input)?;
+    if duration.is_zero() {
+        return Err(format!("duration must be greater than 0: {}", input));
+    }
+    Ok(duration)
+}
+
+// 解析带单位的时间长度, 可以为 0
+fn parse_span(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", input))?;
+
+    let secs = |scale: u64| {
+        value
+            .checked_mul(scale)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration too large: {}", input))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(3600),
+        "d" => secs(86400),
+        _ => Err(format!("invalid duration unit: {}", input)),
+    }
+}
+
+// 解析发送抖动, 取值 0 到 1
+fn parse_jitter(input: &str) -> Result<f64, String> {
+    let jitter: f64 = input
+        .parse()
+        .map_err(|_| format!("invalid jitter: {}", input))?;
+    if !(0.0..=1.0).contains(&jitter) {
+        return Err(format!("jitter must be between 0 and 1: {}", input));
+    }
+    Ok(jitter)
+}
+
+// 解析 payload 长度, 至少要放下探测头部和 MAC
+fn parse_size(input: &str) -> Result<usize, String> {
+    let size: usize = input
+        .parse()
+        .map_err(|_| format!("invalid payload size: {}", input))?;
+    if size < mping::ping::PAYLOAD_HEADER_LEN {
+        return Err(format!(
+            "payload size must be at least {} bytes: {}",
+            mping::ping::PAYLOAD_HEADER_LEN,
+            input
+        ));
+    }
+    Ok(size)
+}
+
+// 解析时间点, 返回 Unix 时间戳 (毫秒)
+// 支持 "2024-01-02 15:04:05" 格式的本地时间, 或者 1h 这样表示多久之前的时间
+fn parse_time(input: &str) -> Result<u64, String> {
+    if let Ok(t) = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S") {
+        return Local
+            .from_local_datetime(&t)
+            .single()
+            .map(|t| t.timestamp_millis() as u64)
+            .ok_or_else(|| format!("invalid local time: {}", input));
+    }
+
+    let ago = parse_span(input)?;
+    Ok((Local::now().timestamp_millis() as u64).saturating_sub(ago.as_millis() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("100ms"), Ok(Duration::from_millis(100)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration(" 10s "), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+
+        for input in ["", "s", "-1s", "1.5s", "10x", "1 s"] {
+            assert!(parse_duration(input).is_err(), "{}", input);
+        }
+        // 0 会让按窗口长度过滤结果的输出永远不匹配
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("0ms").is_err());
+        assert_eq!(parse_span("0s"), Ok(Duration::ZERO));
+
+        // 溢出时返回错误而不是 panic
+        assert!(parse_duration(&format!("{}d", u64::MAX / 86400 + 1)).is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+    }
+
+    fn ips(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(
+            parse_target("10.0.0.1-3", false),
+            Ok(ips(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]))
+        );
+        assert_eq!(
+            parse_target("10.0.0.254-10.0.1.1", false),
+            Ok(ips(&["10.0.0.254", "10.0.0.255", "10.0.1.0", "10.0.1.1"]))
+        );
+        assert_eq!(parse_target("10.0.0.5-5", false), Ok(ips(&["10.0.0.5"])));
+        // 范围中的网络地址和广播地址不跳过
+        assert_eq!(parse_target("10.0.0.0-1", true).unwrap().len(), 2);
+
+        assert!(parse_target("10.0.0.5-1", false).is_err());
+        assert!(parse_target("10.0.1.0-10.0.0.255", false).is_err());
+        assert!(parse_target("10.0.0.1-256", false).is_err());
+
+        // 展开的地址数有上限
+        let max = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + MAX_EXPANDED as u32 - 1);
+        assert_eq!(
+            parse_target(&format!("10.0.0.0-{}", max), false)
+                .unwrap()
+                .len() as u64,
+            MAX_EXPANDED
+        );
+        let over = Ipv4Addr::from(u32::from(max) + 1);
+        assert!(parse_target(&format!("10.0.0.0-{}", over), false).is_err());
+        assert!(parse_target("0.0.0.0-255.255.255.255", false).is_err());
+    }
+
+    #[test]
+    fn parse_cidrs() {
+        assert_eq!(
+            parse_target("10.0.0.0/30", false),
+            Ok(ips(&["10.0.0.0", "10.0.0.1", "10.0.0.2", "10.0.0.3"]))
+        );
+        assert_eq!(
+            parse_target("10.0.0.0/30", true),
+            Ok(ips(&["10.0.0.1", "10.0.0.2"]))
+        );
+        // /31 和 /32 没有网络地址和广播地址
+        assert_eq!(parse_target("10.0.0.0/31", true).unwrap().len(), 2);
+        assert_eq!(parse_target("10.0.0.7/32", true), Ok(ips(&["10.0.0.7"])));
+        assert_eq!(parse_target("10.0.0.7", true), Ok(ips(&["10.0.0.7"])));
+
+        assert_eq!(parse_target("10.0.0.0/12", false).unwrap().len(), 1 << 20);
+        assert!(parse_target("10.0.0.0/11", false).is_err());
+        assert!(parse_target("0.0.0.0/0", false).is_err());
+        assert!(parse_target("::/0", false).is_err());
+        assert!(parse_target("10.0.0.0/33", false).is_err());
+    }
+
+    #[test]
+    fn parse_target_list() {
+        assert_eq!(
+            parse_ips("10.0.0.1,10.0.0.8-9,bad..name,0.0.0.0/0"),
+            ips(&["10.0.0.1", "10.0.0.8", "10.0.0.9"])
+        );
+    }
+
+    #[test]
+    fn parse_times() {
+        let before = Local::now().timestamp_millis() as u64;
+        let ago = parse_time("1h").unwrap();
+        let after = Local::now().timestamp_millis() as u64;
+        assert!(ago >= before - 3_600_000 && ago <= after - 3_600_000);
+        assert!(parse_time("0s").unwrap() >= after);
+        assert!(parse_time("2024-01-02 15:04:05").is_ok());
+        assert!(parse_time("2024-01-02").is_err());
+    }
 }
@@ -0,0 +1,354 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use ipnetwork::IpNetwork;
+use log::info;
+
+use crate::mping::multipath;
+use crate::mping::stat::{TargetResult, LATENCY_BOUNDS_MS};
+
+// 一个窗口的结果连续到达, 超过该时间没有新结果时认为该窗口已经结束
+const BATCH_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Grouping 是目标的分组方式
+#[derive(Clone, Debug, PartialEq)]
+pub enum Grouping {
+    // 按前缀长度分组, 例如 24 表示按 /24 分组
+    Prefix(u8),
+    // 按命令行中的原始 CIDR 参数分组
+    Cidr,
+}
+
+impl FromStr for Grouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "cidr" {
+            return Ok(Grouping::Cidr);
+        }
+        let prefix: u8 = s
+            .trim_start_matches('/')
+            .parse()
+            .map_err(|_| format!("invalid grouping, expected a prefix length or cidr: {}", s))?;
+        if prefix > 32 {
+            return Err(format!("prefix length must be at most 32: {}", s));
+        }
+        Ok(Grouping::Prefix(prefix))
+    }
+}
+
+// Grouper 把目标映射到所属的组
+#[derive(Clone)]
+pub struct Grouper {
+    grouping: Grouping,
+    // 目标到原始参数的映射, 只在按 CIDR 分组时使用
+    specs: HashMap<String, String>,
+    // 每组输出的最差成员数
+    worst: usize,
+    // 只统计该长度窗口的结果
+    window: Duration,
+}
+
+impl Grouper {
+    // 创建一个 Grouper, specs 是每个原始参数及其展开后的地址
+    pub fn new_grouper(
+        grouping: Grouping,
+        specs: &[(String, Vec<IpAddr>)],
+        worst: usize,
+        window: Duration,
+    ) -> Grouper {
+        let mut map = HashMap::new();
+        for (spec, addrs) in specs {
+            for addr in addrs {
+                map.entry(addr.to_string()).or_insert_with(|| spec.clone());
+            }
+        }
+
+        Grouper {
+            grouping,
+            specs: map,
+            worst,
+            window,
+        }
+    }
+
+    // 返回目标所属的组, 多路径结果按地址分组
+    pub fn group_of(&self, target: &str) -> String {
+        let (target, _) = multipath::split_flow(target);
+        match self.grouping {
+            Grouping::Cidr => self
+                .specs
+                .get(target)
+                .cloned()
+                .unwrap_or_else(|| target.to_string()),
+            Grouping::Prefix(prefix) => match target.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => IpNetwork::new(IpAddr::V4(ip), prefix)
+                    .map(|n| format!("{}/{}", n.network(), prefix))
+                    .unwrap_or_else(|_| target.to_string()),
+                _ => target.to_string(),
+            },
+        }
+    }
+}
+
+// GroupResult 是一个组在一个窗口内的统计
+#[derive(Default, Debug)]
+struct GroupResult {
+    sent: u32,
+    received: u32,
+    loss: u32,
+    // 成员的统计结果, 用于计算延迟分布和最差成员
+    members: Vec<TargetResult>,
+}
+
+impl GroupResult {
+    fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.loss as f64 / self.sent as f64
+        }
+    }
+
+    // 合并成员的延迟直方图得到的分布: p50、p90、p99 和最大值, 毫秒
+    // 分位数在所在的桶内线性插值, 不超过最大值, 没有回复时为 None
+    fn latency_distribution(&self) -> Option<[f64; 4]> {
+        let mut buckets = vec![0u32; LATENCY_BOUNDS_MS.len() + 1];
+        let mut max = 0;
+        for tr in &self.members {
+            for (count, other) in buckets.iter_mut().zip(&tr.latency_buckets) {
+                *count += other;
+            }
+            max = max.max(tr.max_latency);
+        }
+        let total: u32 = buckets.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let max = max as f64 / 1_000_000.0;
+        let quantile = |q: f64| {
+            let rank = q * total as f64;
+            let mut seen = 0;
+            for (i, count) in buckets.iter().enumerate() {
+                if *count > 0 && (seen + count) as f64 >= rank {
+                    let lower = if i == 0 {
+                        0.0
+                    } else {
+                        LATENCY_BOUNDS_MS[i - 1]
+                    };
+                    let upper = LATENCY_BOUNDS_MS.get(i).copied().unwrap_or(max);
+                    let value = lower + (upper - lower) * (rank - seen as f64) / *count as f64;
+                    return value.min(max);
+                }
+                seen += count;
+            }
+            max
+        };
+        Some([quantile(0.5), quantile(0.9), quantile(0.99), max])
+    }
+
+    // 丢失率最高的成员, 丢失率相同时延迟高的在前
+    fn worst(&mut self, n: usize) -> &[TargetResult] {
+        self.members.sort_by(|a, b| {
+            b.loss_rate
+                .total_cmp(&a.loss_rate)
+                .then(b.latency.cmp(&a.latency))
+        });
+        &self.members[..n.min(self.members.len())]
+    }
+}
+
+// 把一个窗口的结果按组汇总
+fn aggregate(grouper: &Grouper, batch: Vec<TargetResult>) -> BTreeMap<String, GroupResult> {
+    let mut groups: BTreeMap<String, GroupResult> = BTreeMap::new();
+    for tr in batch {
+        let group = groups.entry(grouper.group_of(&tr.target)).or_default();
+        group.sent += tr.sent();
+        group.loss += tr.loss;
+        group.received += tr.received;
+        group.members.push(tr);
+    }
+    groups
+}
+
+fn output(grouper: &Grouper, batch: Vec<TargetResult>) {
+    for (name, mut group) in aggregate(grouper, batch) {
+        let distribution = match group.latency_distribution() {
+            Some([p50, p90, p99, max]) => format!("{:.2}/{:.2}/{:.2}/{:.2}ms", p50, p90, p99, max),
+            None => "-".to_string(),
+        };
+        let loss_rate = group.loss_rate();
+        let (sent, received, members) = (group.sent, group.received, group.members.len());
+        let worst: Vec<String> = group
+            .worst(grouper.worst)
+            .iter()
+            .map(|tr| {
+                format!(
+                    "{}({:.2}%, {:.2}ms)",
+                    tr.target,
+                    tr.loss_rate * 100.0,
+                    tr.latency as f64 / 1_000_000.0
+                )
+            })
+            .collect();
+
+        info!(
+            "{} [{:?}]: members:{}, sent:{}, recv:{}, loss rate: {:.2}%, latency p50/p90/p99/max: {}, worst: {}",
+            name,
+            grouper.window,
+            members,
+            sent,
+            received,
+            loss_rate * 100.0,
+            distribution,
+            worst.join(" ")
+        );
+    }
+}
+
+/// 按组汇总每个窗口的结果并输出, 直到通道关闭
+pub fn run(rx: Receiver<TargetResult>, grouper: Grouper) {
+    let mut batch: Vec<TargetResult> = Vec::new();
+    let mut batch_start = 0;
+
+    loop {
+        match rx.recv_timeout(BATCH_TIMEOUT) {
+            Ok(tr) => {
+                if tr.window != grouper.window {
+                    continue;
+                }
+                // 新窗口的结果到达, 先输出上一个窗口
+                if tr.window_start != batch_start && !batch.is_empty() {
+                    output(&grouper, std::mem::take(&mut batch));
+                }
+                batch_start = tr.window_start;
+                batch.push(tr);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if !batch.is_empty() {
+                    output(&grouper, std::mem::take(&mut batch));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                if !batch.is_empty() {
+                    output(&grouper, batch);
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grouper(grouping: Grouping) -> Grouper {
+        let specs = vec![(
+            "10.0.0.0/30".to_string(),
+            vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+        )];
+        Grouper::new_grouper(grouping, &specs, 1, Duration::from_secs(1))
+    }
+
+    // latencies 是每个回复的延迟, 毫秒
+    fn member(target: &str, loss: u32, latencies: &[f64]) -> TargetResult {
+        let mut tr = TargetResult {
+            target: target.to_string(),
+            loss,
+            window: Duration::from_secs(1),
+            ..Default::default()
+        };
+        for ms in latencies {
+            let nanos = (ms * 1_000_000.0) as u128;
+            tr.record_latency(nanos);
+            tr.latency += nanos;
+            tr.max_latency = tr.max_latency.max(nanos);
+            tr.received += 1;
+        }
+        tr.finish(Duration::from_secs(1), 0);
+        tr
+    }
+
+    #[test]
+    fn parse_groupings() {
+        assert_eq!("cidr".parse(), Ok(Grouping::Cidr));
+        assert_eq!("24".parse(), Ok(Grouping::Prefix(24)));
+        assert_eq!("/16".parse(), Ok(Grouping::Prefix(16)));
+        assert_eq!("0".parse(), Ok(Grouping::Prefix(0)));
+        assert_eq!("32".parse(), Ok(Grouping::Prefix(32)));
+        for input in ["33", "/64", "-1", "", "prefix"] {
+            assert!(input.parse::<Grouping>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn group_of_targets() {
+        let prefix = grouper(Grouping::Prefix(24));
+        assert_eq!(prefix.group_of("10.0.0.1"), "10.0.0.0/24");
+        assert_eq!(prefix.group_of("10.0.1.200"), "10.0.1.0/24");
+        // 多路径结果按地址分组
+        assert_eq!(
+            prefix.group_of(&multipath::flow_target("10.0.0.1", 3)),
+            "10.0.0.0/24"
+        );
+        assert_eq!(prefix.group_of("::1"), "::1");
+        assert_eq!(prefix.group_of("example.com"), "example.com");
+
+        let cidr = grouper(Grouping::Cidr);
+        assert_eq!(cidr.group_of("10.0.0.2"), "10.0.0.0/30");
+        assert_eq!(
+            cidr.group_of(&multipath::flow_target("10.0.0.1", 1)),
+            "10.0.0.0/30"
+        );
+        assert_eq!(cidr.group_of("10.0.0.9"), "10.0.0.9");
+    }
+
+    #[test]
+    fn aggregate_batch() {
+        let batch = vec![
+            member("10.0.0.1", 1, &[1.0, 1.0, 1.0]),
+            member("10.0.0.2", 0, &[2.0, 2.0]),
+            member("10.0.1.1", 2, &[]),
+        ];
+        let mut groups = aggregate(&grouper(Grouping::Prefix(24)), batch);
+        assert_eq!(groups.len(), 2);
+
+        let group = groups.get_mut("10.0.0.0/24").unwrap();
+        assert_eq!((group.sent, group.received, group.loss), (6, 5, 1));
+        assert_eq!(group.loss_rate(), 1.0 / 6.0);
+        assert_eq!(group.worst(1)[0].target, "10.0.0.1");
+        assert_eq!(group.worst(5).len(), 2);
+
+        let group = &groups["10.0.1.0/24"];
+        assert_eq!(group.loss_rate(), 1.0);
+        assert!(group.latency_distribution().is_none());
+    }
+
+    #[test]
+    fn distribution_merges_histograms() {
+        // 一个成员有 9 个快速的回复, 另一个成员只有 1 个慢的回复
+        // 按成员平均延迟计算时两者权重相同, 按回复合并时 p50 和 p90 都是快的回复
+        let mut group = GroupResult::default();
+        group.members.push(member("a", 0, &[0.8; 9]));
+        group.members.push(member("b", 0, &[40.0]));
+        let [p50, p90, p99, max] = group.latency_distribution().unwrap();
+        assert!(p50 > 0.5 && p50 <= 1.0, "{}", p50);
+        assert!(p90 > 0.5 && p90 <= 1.0, "{}", p90);
+        assert!(p99 > 25.0 && p99 <= 40.0, "{}", p99);
+        assert_eq!(max, 40.0);
+
+        // 超过最大边界的回复以最大延迟为上界
+        let mut group = GroupResult::default();
+        group.members.push(member("a", 0, &[3000.0, 3000.0]));
+        let [p50, _, p99, max] = group.latency_distribution().unwrap();
+        assert!(p50 > 2500.0 && p50 <= 3000.0, "{}", p50);
+        assert!(p99 <= max && max == 3000.0);
+    }
+}
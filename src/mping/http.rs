@@ -0,0 +1,205 @@
+#![cfg(target_os = "linux")]
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+// HTTP 请求的连接和读写超时
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// 请求体的最大长度
+pub const MAX_BODY: usize = 1024 * 1024;
+
+// 把 http://host:port/path 形式的 URL 拆分为 (host:port, path)
+pub fn split_url(url: &str) -> anyhow::Result<(String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("only http:// URLs are supported: {}", url))?;
+
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    Ok((host, path.to_string()))
+}
+
+// 以 POST 方式发送 JSON, 返回 HTTP 状态码
+pub fn post_json(url: &str, body: &str) -> anyhow::Result<u16> {
+    let (host, path) = split_url(url)?;
+    let addr = host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("failed to resolve {}", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    // 只解析状态行, 例如 HTTP/1.1 200 OK
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("invalid HTTP response: {}", status_line.trim()))?;
+
+    Ok(status)
+}
+
+// Request 是一个简化的 HTTP 请求
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+// BodyTooLarge 表示请求体超过了 MAX_BODY, 服务端应返回 413
+#[derive(Debug)]
+pub struct BodyTooLarge(pub usize);
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request body of {} bytes exceeds the limit of {} bytes",
+            self.0, MAX_BODY
+        )
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+// 读取一个 HTTP 请求, 只支持通过 Content-Length 指定长度的请求体
+// 请求体超过 MAX_BODY 时不读取, 返回 BodyTooLarge
+pub fn read_request<R: Read>(stream: R) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or_else(|| anyhow!("empty request"))?
+        .to_string();
+    let path = parts
+        .next()
+        .ok_or_else(|| anyhow!("invalid request line: {}", request_line.trim()))?
+        .to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY {
+        return Err(BodyTooLarge(content_length).into());
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8(body)?,
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+// 写入一个 JSON 响应
+pub fn write_response<W: Write>(stream: &mut W, status: u16, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// 写入流式响应的头部, 之后每行是一个 JSON 对象, 连接关闭时结束
+pub fn write_stream_header<W: Write>(stream: &mut W) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_urls() {
+        assert_eq!(
+            split_url("http://example.com/hook").unwrap(),
+            ("example.com:80".to_string(), "/hook".to_string())
+        );
+        assert_eq!(
+            split_url("http://127.0.0.1:4318").unwrap(),
+            ("127.0.0.1:4318".to_string(), "/".to_string())
+        );
+        assert!(split_url("https://example.com").is_err());
+    }
+
+    #[test]
+    fn read_request_with_body() {
+        let raw = "POST /jobs HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\n{}{}";
+        let req = read_request(raw.as_bytes()).unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/jobs");
+        assert_eq!(req.body, "{}{}");
+    }
+
+    #[test]
+    fn reject_large_body() {
+        let raw = format!(
+            "POST /jobs HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        let Err(err) = read_request(raw.as_bytes()) else {
+            panic!("body over the limit accepted");
+        };
+        assert!(err.downcast_ref::<BodyTooLarge>().is_some());
+    }
+}
@@ -1,5 +1,27 @@
 #![cfg(target_os = "linux")]
 
+pub mod adaptive;
+pub mod agent;
+pub mod alert;
+pub mod anomaly;
+pub mod auth;
+pub mod check;
+pub mod collector;
+pub mod daemon;
+pub mod drops;
 pub mod exec;
+pub mod group;
+pub mod http;
+pub mod multipath;
+pub mod otlp;
+pub mod pacing;
 pub mod ping;
+pub mod probe;
+pub mod significance;
+pub mod sink;
+pub mod stamp;
 pub mod stat;
+pub mod store;
+pub mod sweep;
+pub mod timestamping;
+pub mod tui;
@@ -0,0 +1,113 @@
+#![cfg(target_os = "linux")]
+
+// 多路径模式: 每个目标使用多个流标识, 让 ECMP 把探测散列到不同的成员链路上
+// 同一个流标识的所有探测的五元组 (ICMP 为 Id 和校验和) 保持不变, 始终走同一条路径
+
+// 流标识在目标中的分隔符, 例如 10.0.0.1#3
+const FLOW_SEPARATOR: char = '#';
+// 校验和补偿字的长度, 位于 payload 头部之后, 不参与 bitflip 检查
+pub const COMPENSATION_LEN: usize = 2;
+// 每个目标最多的流标识数, 每个流标识的校验和都不相同
+pub const MAX_FLOWS: u16 = 0x7fff;
+
+// 多路径模式下结果的目标: 地址#流标识
+pub fn flow_target(addr: &str, flow: u16) -> String {
+    format!("{}{}{}", addr, FLOW_SEPARATOR, flow)
+}
+
+// 拆分结果的目标, 返回地址和流标识, 不是多路径结果时流标识为 None
+pub fn split_flow(target: &str) -> (&str, Option<u16>) {
+    match target.rsplit_once(FLOW_SEPARATOR) {
+        Some((addr, flow)) => match flow.parse() {
+            Ok(flow) => (addr, Some(flow)),
+            Err(_) => (target, None),
+        },
+        None => (target, None),
+    }
+}
+
+// 每个流标识固定的 ICMP 校验和, 取值 0x8000 到 0xfffe, 避开 0 和 0xffff 两种零的表示
+pub fn flow_checksum(flow: u16) -> u16 {
+    0x8000 | (flow % MAX_FLOWS)
+}
+
+// 改写 offset 处的 16 位补偿字, 使报文的校验和变为 checksum
+// packet 是校验和已经计算好的 ICMP 报文, offset 必须是偶数
+pub fn compensate(packet: &mut [u8], offset: usize, checksum: u16) {
+    let current = u16::from_be_bytes([packet[2], packet[3]]);
+    let old = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    // 校验和是反码和的反码, 补偿字需要让反码和增加 !checksum - !current (RFC 1624)
+    let word = ones_add(ones_add(old, current), !checksum);
+    packet[offset..offset + COMPENSATION_LEN].copy_from_slice(&word.to_be_bytes());
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+// 16 位反码加法
+fn ones_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_packet::icmp::{self, IcmpPacket};
+
+    // 校验和正确的 ICMP Echo 请求, 补偿字位于 offset
+    fn echo_request(payload_len: usize, ident: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 8 + payload_len];
+        packet[0] = 8;
+        packet[4..6].copy_from_slice(&ident.to_be_bytes());
+        for (i, b) in packet[8..].iter_mut().enumerate() {
+            *b = (i * 37 + 11) as u8;
+        }
+        let checksum = icmp::checksum(&IcmpPacket::new(&packet).unwrap());
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn flow_targets() {
+        let target = flow_target("10.0.0.1", 3);
+        assert_eq!(target, "10.0.0.1#3");
+        assert_eq!(split_flow(&target), ("10.0.0.1", Some(3)));
+        assert_eq!(split_flow("10.0.0.1"), ("10.0.0.1", None));
+        assert_eq!(split_flow("host#name"), ("host#name", None));
+    }
+
+    #[test]
+    fn compensate_fixes_checksum() {
+        for flow in [0, 1, 7, 0x7fff, 0xffff] {
+            for ident in [0, 0x1234, 0xffff] {
+                let mut packet = echo_request(64, ident);
+                let payload = packet[8..].to_vec();
+                let offset = 8 + 52;
+                compensate(&mut packet, offset, flow_checksum(flow));
+
+                assert_eq!(
+                    u16::from_be_bytes([packet[2], packet[3]]),
+                    flow_checksum(flow)
+                );
+                assert_eq!(
+                    icmp::checksum(&IcmpPacket::new(&packet).unwrap()),
+                    flow_checksum(flow)
+                );
+                // 只改写补偿字
+                for (i, b) in packet[8..].iter().enumerate() {
+                    if i != 52 && i != 53 {
+                        assert_eq!(*b, payload[i]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flow_checksum_avoids_zero() {
+        for flow in [0, 1, 0x7ffe, 0x7fff, 0x8000, 0xffff] {
+            let checksum = flow_checksum(flow);
+            assert!(checksum != 0 && checksum != 0xffff);
+        }
+        assert_ne!(flow_checksum(0), flow_checksum(MAX_FLOWS - 1));
+    }
+}
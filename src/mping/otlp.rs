@@ -0,0 +1,356 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::mping::group::Grouper;
+use crate::mping::http;
+use crate::mping::multipath;
+use crate::mping::sink::Sink;
+use crate::mping::stat::{TargetResult, LATENCY_BOUNDS_MS};
+use crate::mping::timestamping;
+
+// OTLP 的累计聚合时间性, 每个数据点是从 startTimeUnixNano 开始的累计值
+const CUMULATIVE: u8 = 2;
+
+// 导出到 OTLP/HTTP 的端点, 没有路径时使用标准的 /v1/metrics
+pub fn metrics_url(endpoint: &str) -> anyhow::Result<String> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let (_, path) = http::split_url(endpoint)?;
+    if path == "/" {
+        Ok(format!("{}/v1/metrics", endpoint))
+    } else {
+        Ok(endpoint.to_string())
+    }
+}
+
+// Series 是一个目标从启动以来的累计值
+#[derive(Default)]
+struct Series {
+    sent: u64,
+    received: u64,
+    lost: u64,
+    bitflip: u64,
+    // 与 LATENCY_BOUNDS_MS 对应的累计直方图
+    buckets: Vec<u64>,
+    // 所有回复的延迟之和, 毫秒
+    rtt_sum: f64,
+    // 最后一个窗口的结束时间, Unix 纳秒
+    time: u128,
+}
+
+// OtlpSink 把每个目标的统计作为 OpenTelemetry 指标导出:
+// mping.sent、mping.received、mping.lost、mping.bitflip 计数器和 mping.rtt 直方图
+pub struct OtlpSink {
+    url: String,
+    // 只导出该长度窗口的结果, 避免 roll-up 窗口被重复计数
+    window: Duration,
+    // 配置了分组时为每个目标加上 group 属性
+    grouper: Option<Grouper>,
+    resource: Value,
+    // 累计值的开始时间, Unix 纳秒
+    start: u128,
+    series: BTreeMap<String, Series>,
+    // 每个目标的出口网卡, 只查询一次
+    interfaces: HashMap<String, Option<String>>,
+}
+
+impl OtlpSink {
+    pub fn new_otlp_sink(
+        url: String,
+        window: Duration,
+        grouper: Option<Grouper>,
+        source: &str,
+    ) -> OtlpSink {
+        OtlpSink {
+            url,
+            window,
+            grouper,
+            resource: json!({
+                "attributes": [
+                    string_attribute("service.name", "mping"),
+                    string_attribute("host.name", source),
+                ]
+            }),
+            start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+            series: BTreeMap::new(),
+            interfaces: HashMap::new(),
+        }
+    }
+
+    // 目标的属性: target, 以及可以确定时的 flow、group 和 interface
+    fn attributes(&mut self, target: &str) -> Value {
+        let (addr, flow) = multipath::split_flow(target);
+        let mut attributes = vec![string_attribute("target", addr)];
+        if let Some(flow) = flow {
+            attributes.push(json!({"key": "flow", "value": {"intValue": flow.to_string()}}));
+        }
+        if let Some(grouper) = &self.grouper {
+            attributes.push(string_attribute("group", &grouper.group_of(target)));
+        }
+        let interface = self.interfaces.entry(addr.to_string()).or_insert_with(|| {
+            addr.parse::<IpAddr>()
+                .ok()
+                .and_then(timestamping::egress_interface)
+        });
+        if let Some(interface) = interface {
+            attributes.push(string_attribute("interface", interface));
+        }
+        Value::Array(attributes)
+    }
+
+    // 把本批结果中更新过的目标编码为 ExportMetricsServiceRequest
+    fn request(&mut self, targets: &[String]) -> Value {
+        let start = self.start.to_string();
+        let mut counters: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+        let mut histograms = Vec::new();
+
+        for target in targets {
+            let attributes = self.attributes(target);
+            let series = &self.series[target];
+            let time = series.time.to_string();
+            for (name, value) in [
+                ("mping.sent", series.sent),
+                ("mping.received", series.received),
+                ("mping.lost", series.lost),
+                ("mping.bitflip", series.bitflip),
+            ] {
+                counters.entry(name).or_default().push(json!({
+                    "attributes": attributes,
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": time,
+                    "asInt": value.to_string(),
+                }));
+            }
+
+            let mut buckets = series.buckets.clone();
+            buckets.resize(LATENCY_BOUNDS_MS.len() + 1, 0);
+            histograms.push(json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start,
+                "timeUnixNano": time,
+                "count": series.received.to_string(),
+                "sum": series.rtt_sum,
+                "bucketCounts": buckets.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+                "explicitBounds": LATENCY_BOUNDS_MS,
+            }));
+        }
+
+        let mut metrics: Vec<Value> = counters
+            .into_iter()
+            .map(|(name, points)| {
+                json!({
+                    "name": name,
+                    "unit": "{packet}",
+                    "sum": {
+                        "aggregationTemporality": CUMULATIVE,
+                        "isMonotonic": true,
+                        "dataPoints": points,
+                    }
+                })
+            })
+            .collect();
+        metrics.push(json!({
+            "name": "mping.rtt",
+            "unit": "ms",
+            "histogram": {
+                "aggregationTemporality": CUMULATIVE,
+                "dataPoints": histograms,
+            }
+        }));
+
+        json!({
+            "resourceMetrics": [{
+                "resource": self.resource,
+                "scopeMetrics": [{
+                    "scope": {"name": "mping"},
+                    "metrics": metrics,
+                }]
+            }]
+        })
+    }
+}
+
+impl Sink for OtlpSink {
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+        let mut targets = Vec::new();
+        for tr in batch.iter().filter(|tr| tr.window == self.window) {
+            let series = self.series.entry(tr.target.clone()).or_default();
+            series.sent += tr.sent() as u64;
+            series.received += tr.received as u64;
+            series.lost += tr.loss as u64;
+            series.bitflip += tr.bitflip_count as u64;
+            if series.buckets.len() < tr.latency_buckets.len() {
+                series.buckets.resize(tr.latency_buckets.len(), 0);
+            }
+            for (count, c) in series.buckets.iter_mut().zip(&tr.latency_buckets) {
+                *count += *c as u64;
+            }
+            series.rtt_sum += (tr.latency * tr.received as u128) as f64 / 1_000_000.0;
+            series.time = (tr.timestamp as u128 * 1_000_000 + tr.window.as_nanos()).max(self.start);
+            if !targets.contains(&tr.target) {
+                targets.push(tr.target.clone());
+            }
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let body = self.request(&targets).to_string();
+        match http::post_json(&self.url, &body) {
+            Ok(status) if (200..300).contains(&status) => Ok(()),
+            Ok(status) => Err(io::Error::other(format!(
+                "{} returned HTTP {}",
+                self.url, status
+            ))),
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mping::group::Grouping;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // 一个基础窗口的结果, latency 是平均延迟, 纳秒
+    fn result(target: &str, window: Duration, latencies: &[u128], loss: u32) -> TargetResult {
+        let mut tr = TargetResult {
+            target: target.to_string(),
+            window,
+            timestamp: 1_700_000_000_000,
+            received: latencies.len() as u32,
+            loss,
+            ..Default::default()
+        };
+        for latency in latencies {
+            tr.record_latency(*latency);
+        }
+        tr.latency = latencies.iter().sum::<u128>() / latencies.len().max(1) as u128;
+        tr
+    }
+
+    // 在本地端口上接收一个请求并返回 200, 返回请求体
+    fn serve_once(listener: TcpListener) -> thread::JoinHandle<http::Request> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = http::read_request(&stream).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            request
+        })
+    }
+
+    fn metric<'a>(request: &'a Value, name: &str) -> &'a Value {
+        request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["name"] == name)
+            .unwrap()
+    }
+
+    fn attribute<'a>(point: &'a Value, key: &str) -> Option<&'a Value> {
+        point["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["key"] == key)
+            .map(|a| &a["value"])
+    }
+
+    #[test]
+    fn metrics_url_adds_path() {
+        assert_eq!(
+            metrics_url("http://127.0.0.1:4318/").unwrap(),
+            "http://127.0.0.1:4318/v1/metrics"
+        );
+        assert_eq!(
+            metrics_url("http://127.0.0.1:4318/otlp").unwrap(),
+            "http://127.0.0.1:4318/otlp"
+        );
+    }
+
+    #[test]
+    fn export_cumulative_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/metrics", listener.local_addr().unwrap());
+        let server = serve_once(listener);
+
+        let window = Duration::from_secs(1);
+        let grouper = Grouper::new_grouper(Grouping::Prefix(24), &[], 3, window);
+        let mut sink = OtlpSink::new_otlp_sink(url.clone(), window, Some(grouper), "probe-1");
+        let target = multipath::flow_target("127.0.0.1", 2);
+        let batch = [
+            result(&target, window, &[1_000_000, 5_000_000], 1),
+            result(&target, window, &[300_000_000], 0),
+            // roll-up 窗口的结果不导出
+            result(&target, Duration::from_secs(60), &[1_000_000; 3], 0),
+        ];
+        sink.write(&batch).unwrap();
+
+        let request = server.join().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/metrics");
+        let request: Value = serde_json::from_str(&request.body).unwrap();
+
+        let resource = &request["resourceMetrics"][0]["resource"];
+        assert_eq!(
+            attribute(resource, "host.name").unwrap()["stringValue"],
+            "probe-1"
+        );
+
+        for (name, value) in [
+            ("mping.sent", "4"),
+            ("mping.received", "3"),
+            ("mping.lost", "1"),
+            ("mping.bitflip", "0"),
+        ] {
+            let sum = &metric(&request, name)["sum"];
+            assert_eq!(sum["aggregationTemporality"], CUMULATIVE);
+            assert_eq!(sum["isMonotonic"], true);
+            let point = &sum["dataPoints"][0];
+            assert_eq!(point["asInt"], value, "{}", name);
+            assert_eq!(
+                attribute(point, "target").unwrap()["stringValue"],
+                "127.0.0.1"
+            );
+            assert_eq!(attribute(point, "flow").unwrap()["intValue"], "2");
+            assert_eq!(
+                attribute(point, "group").unwrap()["stringValue"],
+                "127.0.0.0/24"
+            );
+        }
+
+        let point = &metric(&request, "mping.rtt")["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "3");
+        let buckets: Vec<u64> = point["bucketCounts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c.as_str().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(buckets.len(), LATENCY_BOUNDS_MS.len() + 1);
+        assert_eq!(buckets.iter().sum::<u64>(), 3);
+        assert_eq!(
+            point["explicitBounds"].as_array().unwrap().len(),
+            LATENCY_BOUNDS_MS.len()
+        );
+        assert!((point["sum"].as_f64().unwrap() - 306.0).abs() < 1e-6);
+    }
+}
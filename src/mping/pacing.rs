@@ -0,0 +1,183 @@
+#![cfg(target_os = "linux")]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use libc::{c_long, clock_nanosleep, time_t, timespec, CLOCK_MONOTONIC, TIMER_ABSTIME};
+use rand::Rng;
+
+use crate::mping::ping::monotonic_nanos;
+
+// 距离计划时间小于该值时改为忙等, 弥补 sleep 的唤醒误差
+const SPIN_NANOS: u128 = 200_000;
+// 相邻探测的间隔不小于该值时才忙等, 忙等最多占用一个核的 10%
+const SPIN_MIN_SLOT: u128 = 10 * SPIN_NANOS;
+// 每次 sleep 的最长时间, 保证 stop 被设置后能及时退出
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+// Pacer 把每一轮的探测均匀分布在一个周期内, 避免突发流量触发路由器的 ICMP 限速
+// 每个目标每轮发送一个包, 第 i 个目标的相位偏移为 i 个间隔
+pub struct Pacer {
+    // 一轮的周期, 纳秒
+    period: u128,
+    // 相邻两个探测的间隔, 纳秒
+    slot: u128,
+    targets: usize,
+    // 抖动幅度, 占间隔的比例, 0 表示不加抖动
+    jitter: f64,
+    // 第一轮的开始时间, 落后太多时会后移
+    start: u128,
+    round: u64,
+    index: usize,
+}
+
+impl Pacer {
+    // 创建一个 Pacer
+    // rate_for_all 为 true 时 rate 是所有目标合计的速率, 否则是每个目标的速率
+    pub fn new_pacer(targets: usize, rate: u64, rate_for_all: bool, jitter: f64) -> Pacer {
+        let targets = targets.max(1);
+        let rate = rate.max(1) as u128;
+        let period = if rate_for_all {
+            targets as u128 * 1_000_000_000 / rate
+        } else {
+            1_000_000_000 / rate
+        };
+
+        Pacer {
+            period,
+            slot: period / targets as u128,
+            targets,
+            jitter: jitter.clamp(0.0, 1.0),
+            start: monotonic_nanos(),
+            round: 0,
+            index: 0,
+        }
+    }
+
+    // 返回下一个探测的目标下标、轮次和计划发送时间
+    // 落后超过一个周期时不再追赶, 从当前时间重新开始, 避免补发造成突发
+    pub fn next(&mut self, now: u128) -> (usize, u64, u128) {
+        let mut due =
+            self.start + self.round as u128 * self.period + self.index as u128 * self.slot;
+        if due + self.period < now {
+            self.start += now - due;
+            due = now;
+        }
+        if self.jitter > 0.0 && self.slot > 0 {
+            let max = (self.slot as f64 * self.jitter) as u128;
+            if max > 0 {
+                due += rand::thread_rng().gen_range(0..max);
+            }
+        }
+
+        let next = (self.index, self.round, due);
+        self.index += 1;
+        if self.index == self.targets {
+            self.index = 0;
+            self.round += 1;
+        }
+        next
+    }
+
+    // 是否在计划时间前忙等, 目标很多、间隔很小时忙等会一直占用一个核, 只 sleep
+    pub fn spin(&self) -> bool {
+        self.slot >= SPIN_MIN_SLOT
+    }
+}
+
+// 等待到计划时间, stop 被设置时返回 false
+// 按绝对时间 sleep, spin 为 true 时最后 SPIN_NANOS 改为忙等
+pub fn wait_until(due: u128, spin: bool, stop: &AtomicBool) -> bool {
+    let margin = if spin { SPIN_NANOS } else { 0 };
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = monotonic_nanos();
+        if now >= due {
+            return true;
+        }
+        if due - now > margin {
+            sleep_until((due - margin).min(now + MAX_SLEEP.as_nanos()));
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// 用 clock_nanosleep 在 CLOCK_MONOTONIC 上 sleep 到 deadline (纳秒), 被信号打断时提前返回
+fn sleep_until(deadline: u128) {
+    let ts = timespec {
+        tv_sec: (deadline / 1_000_000_000) as time_t,
+        tv_nsec: (deadline % 1_000_000_000) as c_long,
+    };
+    unsafe {
+        clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &ts, std::ptr::null_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spread_targets_in_period() {
+        // 4 个目标, 每个目标 10pps: 周期 100ms, 间隔 25ms
+        let mut pacer = Pacer::new_pacer(4, 10, false, 0.0);
+        let start = pacer.start;
+        let dues: Vec<(usize, u64, u128)> = (0..6).map(|_| pacer.next(start)).collect();
+        assert_eq!(
+            dues,
+            vec![
+                (0, 0, start),
+                (1, 0, start + 25_000_000),
+                (2, 0, start + 50_000_000),
+                (3, 0, start + 75_000_000),
+                (0, 1, start + 100_000_000),
+                (1, 1, start + 125_000_000),
+            ]
+        );
+        assert!(pacer.spin());
+    }
+
+    #[test]
+    fn rate_for_all_targets() {
+        // 合计 1000pps 分给 1000 个目标: 周期 1s, 间隔 1ms, 间隔太小不忙等
+        let pacer = Pacer::new_pacer(1000, 1000, true, 0.0);
+        assert_eq!(pacer.period, 1_000_000_000);
+        assert_eq!(pacer.slot, 1_000_000);
+        assert!(!pacer.spin());
+    }
+
+    #[test]
+    fn no_catch_up_burst() {
+        let mut pacer = Pacer::new_pacer(2, 10, false, 0.0);
+        let start = pacer.start;
+        // 落后超过一个周期, 从当前时间重新开始
+        let now = start + 1_000_000_000;
+        assert_eq!(pacer.next(now), (0, 0, now));
+        assert_eq!(pacer.next(now), (1, 0, now + 50_000_000));
+    }
+
+    #[test]
+    fn jitter_within_slot() {
+        let mut pacer = Pacer::new_pacer(2, 10, false, 0.5);
+        let start = pacer.start;
+        for _ in 0..100 {
+            let (index, round, due) = pacer.next(start);
+            let planned = start + round as u128 * 100_000_000 + index as u128 * 50_000_000;
+            assert!(due >= planned && due < planned + 25_000_000);
+        }
+    }
+
+    #[test]
+    fn wait_until_stops() {
+        let stop = AtomicBool::new(false);
+        let due = monotonic_nanos() + 2_000_000;
+        assert!(wait_until(due, false, &stop));
+        assert!(monotonic_nanos() >= due);
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(!wait_until(monotonic_nanos() + 1_000_000_000, true, &stop));
+    }
+}
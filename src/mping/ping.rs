@@ -1,28 +1,24 @@
 #![cfg(target_os = "linux")]
 
 use core::result::Result::Ok;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use cfg_if::cfg_if;
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
         use libc::{
-            c_int, c_void, cmsghdr, iovec, msghdr, recvmsg, setsockopt, timespec, timeval, MSG_DONTWAIT,
-            MSG_ERRQUEUE, SOL_SOCKET, SO_TIMESTAMP, SO_TIMESTAMPING,
-        };
-        use libc::{
-            SCM_TIMESTAMPING, SOF_TIMESTAMPING_OPT_CMSG, SOF_TIMESTAMPING_OPT_TSONLY,
-            SOF_TIMESTAMPING_RAW_HARDWARE, SOF_TIMESTAMPING_RX_HARDWARE, SOF_TIMESTAMPING_RX_SOFTWARE,
-            SOF_TIMESTAMPING_SOFTWARE, SOF_TIMESTAMPING_SYS_HARDWARE, SOF_TIMESTAMPING_TX_HARDWARE,
-            SOF_TIMESTAMPING_TX_SOFTWARE,
+            c_int, c_void, clock_gettime, cmsghdr, iovec, msghdr, recvmsg, setsockopt, timespec, timeval,
+            CLOCK_MONOTONIC, MSG_DONTWAIT, MSG_ERRQUEUE, SOL_SOCKET, SO_RXQ_OVFL, SO_TIMESTAMP, SO_TIMESTAMPING,
         };
+        use libc::SCM_TIMESTAMPING;
         use std::mem;
     } else {
         use libc::{ c_void, iovec, msghdr, recvmsg};
@@ -33,15 +29,36 @@ use std::os::unix::io::AsRawFd;
 
 use log::{error, info, warn};
 use rand::Rng;
-use rate_limit::SyncLimiter;
 use ticker::Ticker;
 
 use pnet_packet::icmp::{self, echo_reply, echo_request, IcmpTypes};
 use pnet_packet::ipv4::Ipv4Packet;
 use pnet_packet::Packet;
-use socket2::{Domain, Protocol, Socket, Type};
-
-use crate::mping::stat::{Buckets, Result, TargetResult};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::mping::adaptive::RateController;
+use crate::mping::anomaly::{AnomalyDetector, AnomalyOption};
+use crate::mping::auth::{Session, Verdict, AUTH_LEN};
+use crate::mping::drops::{self, DropTracker};
+use crate::mping::multipath;
+use crate::mping::pacing::{self, Pacer};
+use crate::mping::probe::{self, ProbeType};
+use crate::mping::significance::SignificanceTracker;
+use crate::mping::stat::{Buckets, Result, Rollup, TargetResult, Timestamp, TimestampSource};
+use crate::mping::timestamping::{self, TimestampingMode};
+
+// payload 头部: 16 字节的发送时间戳 (CLOCK_MONOTONIC), 之后是 8 字节的扩展序列号和
+// 4 字节的原始目标地址, 最后是 8 字节的会话 cookie 和 16 字节的 MAC
+const TIMESTAMP_LEN: usize = 16;
+const SEQ_LEN: usize = 8;
+const DEST_LEN: usize = 4;
+// 时间戳、序列号和目标地址, MAC 覆盖的部分
+const SIGNED_LEN: usize = TIMESTAMP_LEN + SEQ_LEN + DEST_LEN;
+pub const PAYLOAD_HEADER_LEN: usize = SIGNED_LEN + AUTH_LEN;
+
+// 暂时性发送错误的最大重试次数和初始退避时间
+const SEND_RETRIES: u32 = 3;
+const SEND_BACKOFF: Duration = Duration::from_micros(100);
 
 /// Ping option struct for ping function.
 /// ``` rust
@@ -56,8 +73,20 @@ use crate::mping::stat::{Buckets, Result, TargetResult};
 ///    len: 56,
 ///    rate: 100,
 ///    rate_for_all: false,
+///    jitter: 0.0,
+///    rcvbuf: 4 * 1024 * 1024,
+///    hmac_key: None,
 ///    delay: 3,
 ///    count: None,
+///    timestamping: TimestampingMode::Auto,
+///    probe: ProbeType::Echo,
+///    flows: 1,
+///    adaptive: false,
+///    anomaly: None,
+///    significance: None,
+///    quiet_targets: false,
+///    interval: Duration::from_secs(1),
+///    rollups: vec![Duration::from_secs(60), Duration::from_secs(300)],
 /// };
 /// ```
 #[derive(Default, Clone, Debug)]
@@ -78,10 +107,36 @@ pub struct PingOption {
     // 如果为 true，每个目标将以固定的速率发送数据包
     // 如果为假，每个目标将以各自的速率发送数据包
     pub rate_for_all: bool,
+    // 发送时间的随机抖动, 占相邻两个探测间隔的比例, 取值 0 到 1
+    pub jitter: f64,
+    // socket 接收缓冲区的大小, 字节, 为 0 时使用系统默认值
+    pub rcvbuf: usize,
+    // 计算回复 MAC 的密钥, None 时只检查会话 cookie
+    pub hmac_key: Option<Vec<u8>>,
     // 输出 ping 结果的延迟
     pub delay: u64,
     // 每个目标的最大 ping 计数, None 时不检查
     pub count: Option<i64>,
+    // 时间戳模式: 软件、硬件或自动检测
+    // ping 启动后会被替换为实际生效的模式 (软件或硬件)
+    pub timestamping: TimestampingMode,
+    // 探测类型: ICMP Echo 或 ICMP Timestamp
+    pub probe: ProbeType,
+    // 多路径模式下每个目标的流标识数, 小于 2 时不改变流标识
+    // 每个流标识有各自的 ICMP Id 和固定的校验和, 结果按 (目标, 流标识) 统计
+    pub flows: u16,
+    // 按每个目标的丢包情况自适应地降低速率, 用于识别 ICMP 限速造成的假丢包
+    pub adaptive: bool,
+    // 按每个目标学习到的基线检测延迟和丢失率的异常, None 时不检测
+    pub anomaly: Option<AnomalyOption>,
+    // 只输出相对最近若干个窗口统计显著变差的结果, 值是参考窗口包含的基础窗口数, None 时输出所有结果
+    pub significance: Option<usize>,
+    // 不打印每个目标的统计行, 只看分组汇总时使用, 丢弃、拒绝和异常等告警仍然输出
+    pub quiet_targets: bool,
+    // 基础聚合窗口的长度, 为 0 时使用 1 秒
+    pub interval: Duration,
+    // 在基础聚合窗口之上同时计算的更大窗口, 必须是 interval 的整数倍
+    pub rollups: Vec<Duration>,
 }
 
 /// Ping function.
@@ -92,14 +147,96 @@ pub struct PingOption {
 /// - `popt` 是 PingOption struct
 /// - `enable_print_stat` 是一个 bool 值，用于在日志中打印 ping 状态
 /// - `tx`` 是发送 ping 结果的发件人, 如果 tx 为 None，ping 将不会发送结果
-
+///
+/// 设置了 `count` 时, 发送完成并输出最后的统计结果后返回, 否则一直运行
+///
+/// 发送在调用者的线程中运行, 接收和统计在单独的线程中运行. ping 返回而不是直接结束进程,
+/// 调用者可以等待 `tx` 的消费者 (告警、存储、sink 等) 处理完最后的结果后再退出
 pub fn ping(
     addrs: Vec<IpAddr>,
     popt: PingOption,
     enable_print_stat: bool,
     tx: Option<Sender<TargetResult>>,
 ) -> anyhow::Result<()> {
-    let pid = popt.ident as u16;
+    ping_until(
+        addrs,
+        popt,
+        enable_print_stat,
+        tx,
+        Arc::new(AtomicBool::new(false)),
+    )
+}
+
+/// 与 `ping` 相同, 但 `stop` 被设置后也会返回
+/// 返回时发送、接收和统计线程都会退出, 剩余的窗口和 roll-up 窗口都已经输出
+/// 用于在同一个进程中运行多个 ping 会话
+pub fn ping_until(
+    addrs: Vec<IpAddr>,
+    mut popt: PingOption,
+    enable_print_stat: bool,
+    tx: Option<Sender<TargetResult>>,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    // 每个会话有随机的 cookie, 用于区分 Id 相同的其它 mping 进程的回复
+    let session = Session::new_session(popt.ident as u16, popt.hmac_key.clone());
+    let read_session = session.clone();
+
+    if popt.len < PAYLOAD_HEADER_LEN {
+        return Err(anyhow::anyhow!(
+            "payload size must be at least {} bytes",
+            PAYLOAD_HEADER_LEN
+        ));
+    }
+
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("no targets to ping"));
+    }
+
+    // 多路径模式需要在 payload 中放置校验和补偿字, ICMP Timestamp 请求没有可用的字段
+    if popt.flows > multipath::MAX_FLOWS {
+        return Err(anyhow::anyhow!(
+            "at most {} flows per target are supported",
+            multipath::MAX_FLOWS
+        ));
+    }
+    if popt.flows > 1 {
+        if popt.probe != ProbeType::Echo {
+            return Err(anyhow::anyhow!(
+                "multiple flows are only supported for echo probes"
+            ));
+        }
+        if popt.len < PAYLOAD_HEADER_LEN + multipath::COMPENSATION_LEN {
+            return Err(anyhow::anyhow!(
+                "payload size must be at least {} bytes with multiple flows",
+                PAYLOAD_HEADER_LEN + multipath::COMPENSATION_LEN
+            ));
+        }
+    }
+
+    if popt.interval.is_zero() {
+        popt.interval = Duration::from_secs(1);
+    }
+    for rollup in &popt.rollups {
+        if rollup.is_zero() || rollup.as_nanos() % popt.interval.as_nanos() != 0 {
+            return Err(anyhow::anyhow!(
+                "rollup window {:?} must be a multiple of interval {:?}",
+                rollup,
+                popt.interval
+            ));
+        }
+    }
+
+    // 自适应速率控制, 基础速率是每个 (目标, 流标识) 的速率
+    let controller = popt.adaptive.then(|| {
+        let slots = (addrs.len() * popt.flows.max(1) as usize) as f64;
+        let base_rate = if popt.rate_for_all {
+            popt.rate as f64 / slots
+        } else {
+            popt.rate as f64
+        };
+        Arc::new(RateController::new_rate_controller(base_rate))
+    });
+    let send_controller = controller.clone();
 
     let rand_payload = random_bytes(popt.len);
     let read_rand_payload = rand_payload.clone();
@@ -113,8 +250,8 @@ pub fn ping(
     // 状态打印
     let stat_buckets = buckets.clone();
 
-    // 创建了一个新的套接字，指定了 IPv4 地址族、原始类型（RAW）以及 ICMPv4 协议。如果创建失败，会通过 unwrap() 抛出错误
-    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).unwrap();
+    // 创建了一个新的套接字，指定了 IPv4 地址族、原始类型（RAW）以及 ICMPv4 协议。如果创建失败，返回错误
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
     // 设置套接字的 TTL（Time-To-Live）值为 popt.ttl，即生存时间。如果设置失败，会通过 unwrap() 抛出错误
     socket.set_ttl(popt.ttl).unwrap();
     // 设置套接字的写超时时间为 popt.timeout。如果设置失败，会通过 unwrap() 抛出错误
@@ -123,34 +260,81 @@ pub fn ping(
     if let Some(tos_value) = popt.tos {
         socket.set_tos(tos_value).unwrap();
     }
+    // 增大接收缓冲区, 并开启接收队列溢出计数, 用于区分本机丢弃和网络丢包
+    drops::setup_rcvbuf(&socket, popt.rcvbuf);
+    drops::enable_rxq_ovfl(&socket);
+    // read 线程从控制消息中读到的接收队列累计丢弃数
+    let rxq_drops = Arc::new(AtomicU32::new(0));
+    let read_rxq_drops = rxq_drops.clone();
     // 尝试克隆套接字，如果克隆失败，则打印错误信息并终止程序
     let socket2 = socket.try_clone().expect("Failed to clone socket");
-    // 检查是否设置接收, 使用 is_some() 方法检查其是否有值，如果有值则返回 true，否则返回 false, 结果存储在 has_tx 变量中
-    let has_tx = tx.is_some();
-
-    // send
-    let send_opt = popt.clone();
-    thread::spawn(move || {
-        send(
-            socket,
-            addrs,
-            send_opt,
-            send_buckets,
-            rand_payload,
-            pid,
-            has_tx,
-        )
-    });
+    // 检测出口网卡的时间戳能力, 决定实际使用硬件还是软件时间戳
+    // 返回前一直持有, 结束时恢复网卡原来的时间戳配置
+    let timestamping = timestamping::setup(popt.timestamping, &addrs)?;
+    popt.timestamping = if timestamping.hardware {
+        TimestampingMode::Hardware
+    } else {
+        TimestampingMode::Software
+    };
+
+    // 发送结束后通知 read 和统计线程退出, 与外部的 stop 分开, 退出前还要等待最后的回复
+    let done = Arc::new(AtomicBool::new(false));
 
     // 打印
     let print_opt = popt.clone();
-    thread::spawn(move || print_stat(stat_buckets, print_opt, enable_print_stat, tx.clone()));
+    let print_stop = done.clone();
+    let print_handle = thread::spawn(move || {
+        print_stat(
+            stat_buckets,
+            print_opt,
+            enable_print_stat,
+            tx,
+            rxq_drops,
+            controller,
+            print_stop,
+        )
+    });
 
     // read
     let read_opt = popt.clone();
-    let _ = read(socket2, read_opt, read_buckets, pid, read_rand_payload);
+    let read_stop = done.clone();
+    let read_handle = thread::spawn(move || {
+        read(
+            socket2,
+            read_opt,
+            read_buckets,
+            read_session,
+            read_rand_payload,
+            read_rxq_drops,
+            read_stop,
+        )
+    });
 
-    Ok(())
+    // send, 发送结束后等待 timeout 收取最后的回复, 然后通知其它线程退出
+    // 统计线程退出前输出剩余的所有窗口, ping 返回时结果已经全部发送给通道
+    let timeout = popt.timeout;
+    let result = send(
+        socket,
+        addrs,
+        popt,
+        send_buckets,
+        rand_payload,
+        session,
+        send_controller,
+        &stop,
+    );
+    if result.is_ok() {
+        thread::sleep(timeout);
+    }
+    done.store(true, Ordering::Relaxed);
+    let read_result = read_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("read thread panicked"))?;
+    let print_result = print_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("print_stat thread panicked"))?;
+    drop(timestamping);
+    result.and(read_result).and(print_result)
 }
 
 fn random_bytes(len: usize) -> Vec<u8> {
@@ -161,10 +345,24 @@ fn random_bytes(len: usize) -> Vec<u8> {
     return vec;
 }
 
+// 读取 CLOCK_MONOTONIC 时钟, 返回纳秒
+// 与墙上时钟不同, 它不会因为 NTP 校时而跳变, 用于计算用户态的 RTT
+pub fn monotonic_nanos() -> u128 {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        clock_gettime(CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u128 * 1_000_000_000 + ts.tv_nsec as u128
+}
+
 // 用于从 Linux socket 消息头中提取时间戳的函数
-// 接受一个 msghdr, 返回一个 Option<SystemTime>
+// 接受一个 msghdr, 返回一个 Option<Timestamp>, 其中记录了时间戳的来源
+// 软件时间戳来自 CLOCK_REALTIME, 硬件时间戳来自网卡的 PHC 时钟, 二者不能混用
 #[cfg(target_os = "linux")]
-fn get_timestamp(msghdr: &mut msghdr) -> Option<SystemTime> {
+pub fn get_timestamp(msghdr: &mut msghdr) -> Option<Timestamp> {
     // 获取 CMSG 指针
     // 使用 libc::CMSG_FIRSTHDR 获取第一个 CMSG（控制消息）头的指针
     // 在后续的循环中，将迭代 CMSG 消息头
@@ -174,29 +372,35 @@ fn get_timestamp(msghdr: &mut msghdr) -> Option<SystemTime> {
         // 判断 CMSG 消息头的 level 和 type，并提取时间戳
         // 分别处理 SO_TIMESTAMP 和 SCM_TIMESTAMPING 两种情况
         // 处理 SO_TIMESTAMP
-        // 如果 CMSG 消息头的 level 是 SOL_SOCKET, type 是 SO_TIMESTAMP, 则提取 timeval 结构体, 将其转换为 SystemTime
+        // 如果 CMSG 消息头的 level 是 SOL_SOCKET, type 是 SO_TIMESTAMP, 则提取 timeval 结构体, 它是内核软件时间戳
         if unsafe { (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SO_TIMESTAMP } {
             let tv: *mut timeval = unsafe { libc::CMSG_DATA(cmsg) } as *mut timeval;
             let timestamp = unsafe { *tv };
-            return Some(
-                SystemTime::UNIX_EPOCH
-                    + Duration::new(timestamp.tv_sec as u64, timestamp.tv_usec as u32 * 1000),
-            );
+            return Some(Timestamp {
+                nanos: timestamp.tv_sec as u128 * 1_000_000_000 + timestamp.tv_usec as u128 * 1000,
+                source: TimestampSource::Software,
+            });
         }
 
         // 处理 SCM_TIMESTAMPING
-        // 如果 CMSG 消息头的 level 是 SOL_SOCKET, type 是 SCM_TIMESTAMPING, 则提取 [timespec; 3] 数组，遍历其中的 timespec, 将其转换为 SystemTime
+        // 如果 CMSG 消息头的 level 是 SOL_SOCKET, type 是 SCM_TIMESTAMPING, 则提取 [timespec; 3] 数组，遍历其中的 timespec
+        // 第一个是软件时间戳, 第二个已废弃, 第三个是网卡的原始硬件时间戳, 优先使用硬件时间戳
         if unsafe { (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_TIMESTAMPING } {
             let tv: *mut [timespec; 3] = unsafe { libc::CMSG_DATA(cmsg) } as *mut [timespec; 3];
             let timestamps = unsafe { *tv };
 
-            for timestamp in &timestamps {
+            for (i, timestamp) in timestamps.iter().enumerate().rev() {
                 if timestamp.tv_sec != 0 || timestamp.tv_nsec != 0 {
-                    let seconds = Duration::from_secs(timestamp.tv_sec as u64);
-                    let nanoseconds = Duration::from_nanos(timestamp.tv_nsec as u64);
-                    if let Some(duration) = seconds.checked_add(nanoseconds) {
-                        return Some(SystemTime::UNIX_EPOCH + duration);
-                    }
+                    let source = if i == 0 {
+                        TimestampSource::Software
+                    } else {
+                        TimestampSource::Hardware
+                    };
+                    return Some(Timestamp {
+                        nanos: timestamp.tv_sec as u128 * 1_000_000_000
+                            + timestamp.tv_nsec as u128,
+                        source,
+                    });
                 }
             }
         }
@@ -209,14 +413,32 @@ fn get_timestamp(msghdr: &mut msghdr) -> Option<SystemTime> {
     None
 }
 
+// 从 SO_RXQ_OVFL 控制消息中获取接收队列的累计丢弃数
+#[cfg(target_os = "linux")]
+pub fn get_rxq_drops(msghdr: &mut msghdr) -> Option<u32> {
+    let mut cmsg: *mut cmsghdr = unsafe { libc::CMSG_FIRSTHDR(msghdr) };
+
+    while !cmsg.is_null() {
+        if unsafe { (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SO_RXQ_OVFL } {
+            let dropped = unsafe { *(libc::CMSG_DATA(cmsg) as *const u32) };
+            return Some(dropped);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msghdr, cmsg) };
+    }
+
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
 fn send(
     socket: Socket,
     addrs: Vec<IpAddr>,
     popt: PingOption,
     send_buckets: Arc<Mutex<Buckets>>,
     rand_payload: Vec<u8>,
-    pid: u16,
-    has_tx: bool,
+    session: Session,
+    controller: Option<Arc<RateController>>,
+    stop: &AtomicBool,
 ) -> anyhow::Result<()> {
     // 条件化的 Linux socket 设置
     cfg_if! {
@@ -226,13 +448,9 @@ fn send(
             // 如果设置失败，会将 support_tx_timestamping 置为 false
             let mut support_tx_timestamping = true;
             let raw_fd = socket.as_raw_fd();
-            let enable = SOF_TIMESTAMPING_SOFTWARE
-                | SOF_TIMESTAMPING_TX_SOFTWARE
-                | SOF_TIMESTAMPING_SYS_HARDWARE
-                | SOF_TIMESTAMPING_TX_HARDWARE
-                | SOF_TIMESTAMPING_RAW_HARDWARE
-                | SOF_TIMESTAMPING_OPT_CMSG
-                | SOF_TIMESTAMPING_OPT_TSONLY;
+            let enable = timestamping::timestamping_flags(
+                popt.timestamping == TimestampingMode::Hardware,
+            );
             let ret = unsafe {
                 setsockopt(
                     raw_fd,
@@ -263,11 +481,15 @@ fn send(
     // 随机数据 + 全零数据 + 全一数据 + 全 0x5A 数据组成 payloads
     let payloads: [&[u8]; 4] = [&rand_payload, &zero_payload, &one_payload, &fivea_payload];
 
-    // SyncLimiter 的初始化
-    // 使用 SyncLimiter 类型创建了一个速率限制器，用于控制发送速率
-    let limiter = SyncLimiter::full(popt.rate, Duration::from_millis(1000));
-    let mut seq = 1u16;
+    // Pacer 的初始化
+    // 每一轮的探测均匀分布在周期内, 每个目标有各自的相位偏移
+    // 多路径模式下每个 (目标, 流标识) 占一个位置, 按各自的速率发送
+    let flows = popt.flows.max(1) as usize;
+    let slots = addrs.len() * flows;
+    let mut pacer = Pacer::new_pacer(slots, popt.rate, popt.rate_for_all, popt.jitter);
     let mut sent_count = 0;
+    // 已经打印过的 (目标, errno)
+    let mut send_errors = HashSet::new();
 
     // Linux 环境下的缓冲区初始化
     // 主要初始化了用于网络通信的缓冲区和相关的结构体，如 iovec 和 msghdr
@@ -293,44 +515,37 @@ fn send(
     }
 
     // 发送循环
-    loop {
-        // 根据启动参数选择是否启用限速器
-        if !popt.rate_for_all {
-            limiter.take();
+    while !stop.load(Ordering::Relaxed) {
+        // 等待下一个探测的计划发送时间
+        let (index, round, due) = pacer.next(monotonic_nanos());
+        if !pacing::wait_until(due, pacer.spin(), stop) {
+            break;
         }
+        // 扩展序列号, 每轮加一, ICMP 报文中只携带它的低 16 位
+        let seq = round + 1;
         let payload = payloads[seq as usize % payloads.len()];
-        // 遍历目标地址集合，发送 ICMP Echo 请求
-        for ip in &addrs {
-            if popt.rate_for_all {
-                limiter.take();
-            }
-
-            // 构造 ICMP Echo 请求包
-            let mut buf = vec![0; 8 + payload.len()]; // 8 bytes of header, then payload
-            let mut packet = echo_request::MutableEchoRequestPacket::new(&mut buf[..]).unwrap();
-            packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
-            packet.set_identifier(pid);
-            packet.set_sequence_number(seq);
-
-            let now = SystemTime::now();
-            let since_the_epoch = now.duration_since(UNIX_EPOCH).unwrap();
-            let timestamp = since_the_epoch.as_nanos();
+        let ip = &addrs[index / flows];
+        let flow = (flows > 1).then_some((index % flows) as u16);
+        let timestamp = monotonic_nanos();
 
-            let ts_bytes = timestamp.to_be_bytes();
-            let mut send_payload = vec![0; payload.len()];
-            send_payload[..16].copy_from_slice(&ts_bytes[..16]);
-            send_payload[16..].copy_from_slice(&payload[16..]);
-
-            packet.set_payload(&send_payload);
+        // ICMP Timestamp 请求没有 payload, 发送时间放在 originate 中
+        let buf = if popt.probe == ProbeType::Timestamp {
+            probe::timestamp_request(session.ident, seq as u16, timestamp)
+        } else {
+            echo_request_packet(ip, seq, timestamp, payload, &session, flow)
+        };
 
-            let icmp_packet = icmp::IcmpPacket::new(packet.packet()).unwrap();
-            let checksum = icmp::checksum(&icmp_packet);
-            packet.set_checksum(checksum);
+        let dest = SocketAddr::new(*ip, 0);
+        let key = timestamp / popt.interval.as_nanos();
+        let target = match flow {
+            Some(flow) => multipath::flow_target(&dest.ip().to_string(), flow),
+            None => dest.ip().to_string(),
+        };
 
-            let dest = SocketAddr::new(*ip, 0);
-            let key = timestamp / 1_000_000_000;
-            let target = dest.ip().to_string();
+        // 自适应速率控制跳过的探测不发送, 也不计入统计
+        let skipped = controller.as_ref().is_some_and(|c| !c.allow(&target));
 
+        if !skipped {
             let data = send_buckets.lock().unwrap();
             data.add(
                 key,
@@ -341,31 +556,45 @@ fn send(
                     latency: 0,
                     received: false,
                     bitflip: false,
+                    send_error: timestamp.saturating_sub(due),
                     ..Default::default()
                 },
             );
             drop(data);
+        }
 
-            // 发送 ICMP Echo 请求包
-            match socket.send_to(&buf, &dest.into()) {
-                Ok(_) => {}
+        // 发送 ICMP Echo 请求包, 失败时只记录该目标的这一次探测, 继续发送其它探测
+        let sent = !skipped
+            && match send_with_retry(&socket, &buf, &dest.into()) {
+                Ok(()) => true,
                 Err(e) => {
-                    error!("Error in send: {:?}", e);
-                    return Err(e.into());
+                    // 每个目标的每种错误只打印一次, 之后只计入 send failed
+                    if send_errors.insert((*ip, e.raw_os_error().unwrap_or(0))) {
+                        warn!(
+                            "send to {} failed: {}, later failures are counted as send failed",
+                            ip, e
+                        );
+                    }
+                    let data = send_buckets.lock().unwrap();
+                    data.mark_send_failed(key, target.clone(), seq);
+                    drop(data);
+                    false
                 }
-            }
+            };
 
-            // 如果支持时间戳，接收并处理
-            if support_tx_timestamping {
-                cfg_if! {
-                    if #[cfg(target_os = "linux")] {
-                        unsafe {
-                            let _ = recvmsg(raw_fd, &mut msghdr, MSG_ERRQUEUE | MSG_DONTWAIT);
-                        }
+        // 如果支持时间戳，接收并处理
+        if sent && support_tx_timestamping {
+            cfg_if! {
+                if #[cfg(target_os = "linux")] {
+                    // 内核会改写 msg_controllen, 每次接收前需要重置
+                    msghdr.msg_controllen = control_buf.len();
+                    let nbytes = unsafe {
+                        recvmsg(raw_fd, &mut msghdr, MSG_ERRQUEUE | MSG_DONTWAIT)
+                    };
+                    if nbytes >= 0 {
                         if let Some(txts) = get_timestamp(&mut msghdr) {
-                            let ts = txts.duration_since(UNIX_EPOCH).unwrap().as_nanos();
                             let data = send_buckets.lock().unwrap();
-                            data.update_txts(key, target, seq, ts);
+                            data.update_txts(key, target, seq, txts);
                             drop(data);
                         }
                     }
@@ -373,18 +602,86 @@ fn send(
             }
         }
 
-        // 更新序列号和发送计数
-        seq += 1;
+        // 一轮的所有目标发送完后更新发送计数
+        if index + 1 < slots {
+            continue;
+        }
         sent_count += 1;
 
         // 如果设置了发送次数限制，达到次数后退出循环
+        // 最后的窗口由统计线程在退出前输出
         if popt.count.is_some() && sent_count >= popt.count.unwrap() {
-            thread::sleep(Duration::from_secs(popt.delay));
             info!("reached {} and exit", sent_count);
-            if has_tx {
-                return Ok(());
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+// 构造 ICMP Echo 请求包
+// 指定流标识时 Id 为会话 Id 加流标识, 并用 payload 头部之后的补偿字把校验和固定为该流的值
+fn echo_request_packet(
+    ip: &IpAddr,
+    seq: u64,
+    timestamp: u128,
+    payload: &[u8],
+    session: &Session,
+    flow: Option<u16>,
+) -> Vec<u8> {
+    let mut buf = vec![0; 8 + payload.len()]; // 8 bytes of header, then payload
+    let mut packet = echo_request::MutableEchoRequestPacket::new(&mut buf[..]).unwrap();
+    packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+    packet.set_identifier(session.ident.wrapping_add(flow.unwrap_or(0)));
+    packet.set_sequence_number(seq as u16);
+
+    let ts_bytes = timestamp.to_be_bytes();
+    let mut send_payload = vec![0; payload.len()];
+    send_payload[..TIMESTAMP_LEN].copy_from_slice(&ts_bytes[..TIMESTAMP_LEN]);
+    send_payload[TIMESTAMP_LEN..TIMESTAMP_LEN + SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+    // 回复可能来自其它地址, 用 payload 中的目标地址找回原始目标
+    if let IpAddr::V4(v4) = ip {
+        send_payload[TIMESTAMP_LEN + SEQ_LEN..SIGNED_LEN].copy_from_slice(&v4.octets());
+    }
+    let auth = session.seal(&send_payload[..SIGNED_LEN]);
+    send_payload[SIGNED_LEN..PAYLOAD_HEADER_LEN].copy_from_slice(&auth);
+    send_payload[PAYLOAD_HEADER_LEN..].copy_from_slice(&payload[PAYLOAD_HEADER_LEN..]);
+
+    packet.set_payload(&send_payload);
+
+    let icmp_packet = icmp::IcmpPacket::new(packet.packet()).unwrap();
+    let checksum = icmp::checksum(&icmp_packet);
+    packet.set_checksum(checksum);
+    if let Some(flow) = flow {
+        multipath::compensate(
+            &mut buf,
+            8 + PAYLOAD_HEADER_LEN,
+            multipath::flow_checksum(flow),
+        );
+    }
+    buf
+}
+
+// 暂时性的发送错误, 例如发送缓冲区满时的 ENOBUFS, 稍后重试可能成功
+fn is_transient(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock
+        || e.kind() == ErrorKind::Interrupted
+        || e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+// 发送一个数据包, 暂时性错误时按指数退避重试
+fn send_with_retry(socket: &Socket, buf: &[u8], dest: &SockAddr) -> std::io::Result<()> {
+    let mut backoff = SEND_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match socket.send_to(buf, dest) {
+            Ok(_) => return Ok(()),
+            Err(e) if is_transient(&e) && retries < SEND_RETRIES => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
             }
-            std::process::exit(0);
+            Err(e) => return Err(e),
         }
     }
 }
@@ -394,8 +691,10 @@ fn read(
     socket2: Socket,
     popt: PingOption,
     read_buckets: Arc<Mutex<Buckets>>,
-    pid: u16,
+    session: Session,
     read_rand_payload: Vec<u8>,
+    rxq_drops: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     // 同 send 的 Payload 初始化
     let zero_payload = vec![0; popt.len];
@@ -417,15 +716,10 @@ fn read(
     // 同 send
     cfg_if! {
         if #[cfg(target_os = "linux")] {
-            let enable = SOF_TIMESTAMPING_SOFTWARE
-                | SOF_TIMESTAMPING_TX_SOFTWARE
-                | SOF_TIMESTAMPING_RX_SOFTWARE
-                | SOF_TIMESTAMPING_SYS_HARDWARE
-                | SOF_TIMESTAMPING_TX_HARDWARE
-                | SOF_TIMESTAMPING_RX_HARDWARE
-                | SOF_TIMESTAMPING_RAW_HARDWARE
-                | SOF_TIMESTAMPING_OPT_CMSG
-                | SOF_TIMESTAMPING_OPT_TSONLY;
+            // 与 send 使用相同的选项, 两个 socket 共享同一个底层套接字
+            let enable = timestamping::timestamping_flags(
+                popt.timestamping == TimestampingMode::Hardware,
+            );
             let ret = unsafe {
                 setsockopt(
                     raw_fd,
@@ -457,6 +751,9 @@ fn read(
     let mut buffer: [u8; 2048] = [0; 2048];
     let mut control_buf = [0; 1024];
 
+    // 每个目标已收到的最大扩展序列号, 用于检测乱序到达的回复
+    let mut max_seqs: HashMap<String, u64> = HashMap::new();
+
     let mut iovec = iovec {
         iov_base: buffer.as_mut_ptr() as *mut c_void,
         iov_len: buffer.len(),
@@ -486,8 +783,12 @@ fn read(
         }
     }
 
-    loop {
+    // 读取超时后检查是否需要退出
+    while !stop.load(Ordering::Relaxed) {
+        msghdr.msg_controllen = control_buf.len();
         let nbytes = unsafe { recvmsg(raw_fd, &mut msghdr, 0) };
+        // 收到报文后立即记录用户态的单调时钟时间戳
+        let rxts = monotonic_nanos();
         if nbytes == -1 {
             let err = Error::last_os_error();
             if err.kind() == ErrorKind::WouldBlock {
@@ -500,10 +801,60 @@ fn read(
 
         let buf = &buffer[..nbytes as usize];
 
+        // 每个报文都带有接收队列的累计丢弃数, 包括不属于本进程的 ICMP 报文
+        cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                if let Some(dropped) = get_rxq_drops(&mut msghdr) {
+                    rxq_drops.store(dropped, Ordering::Relaxed);
+                }
+            }
+        }
+
         // 解析 ICMP Echo 回复消息
         let ipv4_packet = Ipv4Packet::new(buf).unwrap();
         let icmp_packet = pnet_packet::icmp::IcmpPacket::new(ipv4_packet.payload()).unwrap();
 
+        cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                let kernel_rxts = get_timestamp(&mut msghdr);
+            } else {
+                let kernel_rxts = None;
+            }
+        }
+
+        // ICMP Timestamp 回复没有 payload, 无法检查会话 cookie, 只按 Id 过滤
+        // 回复的源地址就是目标, 扩展序列号由 16 位序列号还原
+        if icmp_packet.get_icmp_type() == IcmpTypes::TimestampReply {
+            let reply = match probe::parse_timestamp_reply(icmp_packet.packet(), rxts) {
+                Some(reply) if reply.ident == session.ident => reply,
+                _ => continue,
+            };
+            let target = ipv4_packet.get_source().to_string();
+            let max_seq = max_seqs.entry(target.clone()).or_insert(0);
+            let seq = probe::unwrap_seq(reply.seq, *max_seq);
+            let out_of_order = seq < *max_seq;
+            *max_seq = (*max_seq).max(seq);
+
+            let buckets = read_buckets.lock().unwrap();
+            buckets.add_reply(
+                reply.txts / popt.interval.as_nanos(),
+                Result {
+                    txts: reply.txts,
+                    rxts,
+                    kernel_rxts,
+                    target: target.clone(),
+                    responder: target,
+                    seq,
+                    received: true,
+                    out_of_order,
+                    remote_timestamps: Some((reply.receive, reply.transmit)),
+                    rx_wall_ms: probe::ms_of_day(),
+                    ..Default::default()
+                },
+            );
+            continue;
+        }
+
         // 判断 ICMP 报文类型和代码
         if icmp_packet.get_icmp_type() != IcmpTypes::EchoReply
             || icmp_packet.get_icmp_code() != echo_reply::IcmpCodes::NoCode
@@ -520,106 +871,151 @@ fn read(
         };
 
         // 根据 Echo 回复消息中的信息进行处理，例如比较标识符、序列号等
-        if echo_reply.get_identifier() != pid {
+        // 多路径模式下 Id 与会话 Id 的差就是流标识
+        let flow = echo_reply.get_identifier().wrapping_sub(session.ident);
+        if flow >= popt.flows.max(1) {
+            continue;
+        }
+
+        // Id 相同时再检查 cookie 和 MAC, 不通过的回复不参与统计
+        let payload = echo_reply.payload();
+        // 实际发出回复的地址, 可能与原始目标不同 (anycast、NAT、多宿主路由器)
+        let responder = ipv4_packet.get_source();
+        let verdict = if payload.len() < PAYLOAD_HEADER_LEN {
+            Verdict::Foreign
+        } else {
+            session.check(
+                &payload[..SIGNED_LEN],
+                &payload[SIGNED_LEN..PAYLOAD_HEADER_LEN],
+            )
+        };
+        if verdict != Verdict::Valid {
+            let buckets = read_buckets.lock().unwrap();
+            buckets.add_rejected(responder.to_string(), verdict);
             continue;
         }
 
+        let ts_bytes = &payload[..TIMESTAMP_LEN];
+        let txts = u128::from_be_bytes(ts_bytes.try_into().unwrap());
+        let seq_bytes = &payload[TIMESTAMP_LEN..TIMESTAMP_LEN + SEQ_LEN];
+        let seq = u64::from_be_bytes(seq_bytes.try_into().unwrap());
+        let dest_bytes: [u8; DEST_LEN] = payload[TIMESTAMP_LEN + SEQ_LEN..SIGNED_LEN]
+            .try_into()
+            .unwrap();
+        let dest_ip = Ipv4Addr::from(dest_bytes);
+
+        // 多路径模式下的补偿字随报文变化, 不参与比较
+        let pattern_start = if popt.flows > 1 {
+            PAYLOAD_HEADER_LEN + multipath::COMPENSATION_LEN
+        } else {
+            PAYLOAD_HEADER_LEN
+        };
         let mut bitflip = false;
-        if payloads[echo_reply.get_sequence_number() as usize % payloads.len()][16..]
-            != echo_reply.payload()[16..]
+        if payloads[seq as usize % payloads.len()].get(pattern_start..)
+            != payload.get(pattern_start..)
         {
-            warn!(
-                "bitflip detected! seq={:?},",
-                echo_reply.get_sequence_number()
-            );
+            warn!("bitflip detected! seq={:?},", seq);
             bitflip = true;
         }
 
-        let payload = echo_reply.payload();
-        let ts_bytes = &payload[..16];
-        let txts = u128::from_be_bytes(ts_bytes.try_into().unwrap());
-        let dest_ip = ipv4_packet.get_source();
+        let target = if popt.flows > 1 {
+            multipath::flow_target(&dest_ip.to_string(), flow)
+        } else {
+            dest_ip.to_string()
+        };
 
-        let now = SystemTime::now();
-        let since_the_epoch = now.duration_since(UNIX_EPOCH).unwrap();
-
-        cfg_if! {
-            if #[cfg(target_os = "linux")] {
-                let mut timestamp = since_the_epoch.as_nanos();
-                if let Some(rxts) = get_timestamp(&mut msghdr) {
-                    timestamp = rxts.duration_since(UNIX_EPOCH).unwrap().as_nanos();
-                }
-            } else {
-                let timestamp = since_the_epoch.as_nanos();
-            }
-        }
+        // 序列号小于该目标已收到的最大序列号, 说明回复乱序到达
+        let max_seq = max_seqs.entry(target.clone()).or_insert(0);
+        let out_of_order = seq < *max_seq;
+        *max_seq = (*max_seq).max(seq);
 
         // 记录结果到数据结构中
         let buckets = read_buckets.lock().unwrap();
         // 将回复信息加入 bucket 结构
         buckets.add_reply(
-            txts / 1_000_000_000,
+            txts / popt.interval.as_nanos(),
             Result {
                 txts,
-                rxts: timestamp,
-                target: dest_ip.to_string(),
-                seq: echo_reply.get_sequence_number(),
+                rxts,
+                kernel_rxts,
+                target,
+                responder: responder.to_string(),
+                seq,
                 latency: 0,
                 received: true,
                 bitflip,
+                out_of_order,
+                ..Default::default()
             },
         );
     }
+
+    Ok(())
 }
 
-fn print_stat(
+pub fn print_stat(
     buckets: Arc<Mutex<Buckets>>,
     popt: PingOption,
     enable_print_stat: bool,
     tx: Option<Sender<TargetResult>>,
+    rxq_drops: Arc<AtomicU32>,
+    controller: Option<Arc<RateController>>,
+    stop: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     // 统计打印的初始化和配置
-    // 包括延迟、最后一个 key、是否有发送通道以及一个 Ticker，用于每秒钟进行定期操作
+    // 包括延迟、最后一个 key、聚合窗口以及一个 Ticker，用于每个聚合窗口进行定期操作
     let delay = Duration::from_secs(popt.delay).as_nanos(); // 5s
+    let timeout = popt.timeout.as_nanos();
+    let interval = popt.interval.as_nanos();
     let mut last_key = 0;
 
-    let has_sender = tx.is_some();
+    // 基础窗口之上的 roll-up 窗口
+    let mut rollups: Vec<Rollup> = popt
+        .rollups
+        .iter()
+        .map(|window| Rollup::new_rollup(*window))
+        .collect();
 
-    let ticker = Ticker::new(0.., Duration::from_secs(1));
+    // 每个目标的基线, 用于检测异常窗口
+    let mut detector = popt
+        .anomaly
+        .clone()
+        .map(AnomalyDetector::new_anomaly_detector);
+    // 每个目标最近若干个窗口的统计, 用于判断变差是否显著
+    let mut tracker = popt
+        .significance
+        .map(SignificanceTracker::new_significance_tracker);
+
+    // 每个窗口的本机丢弃数
+    let mut drop_tracker = DropTracker::new_tracker(rxq_drops);
+
+    let ticker = Ticker::new(0.., popt.interval);
 
     //定期统计信息输出
     // 使用 Ticker 来定期执行循环体，获取当前存储 bucket 的信息
     // 检查是否为空，然后进行后续统计逻辑
+    // 每次处理所有已到期的 bucket, 停止后不再等待 delay, 统计剩余的所有 bucket 和 roll-up 窗口
     for _ in ticker {
-        let buckets = buckets.lock().unwrap();
-        let bucket = buckets.last();
-        if bucket.is_none() {
-            continue;
-        }
+        let done = stop.load(Ordering::Relaxed);
 
+        let buckets = buckets.lock().unwrap();
         // bucket 信息的处理
         // 检查 bucket key，并进行后续统计逻辑
-        let bucket = bucket.unwrap();
-        // 如果 bucket key 小于等于上一次处理的 key，则弹出该存储桶，继续下一个循环
-        if bucket.key <= last_key {
-            buckets.pop();
-            continue;
-        }
+        while let Some(bucket) = buckets.last() {
+            // 如果 bucket key 小于等于上一次处理的 key，则弹出该存储桶，继续下一个循环
+            if bucket.key <= last_key {
+                buckets.pop();
+                continue;
+            }
+
+            // 然后检查 bucket 是否在指定的时间范围内，如果是，执行后续的统计逻辑
+            // bucket 的 key 以聚合窗口为单位, 它结束 delay 之后才进行统计
+            if !done && (bucket.key + 1) * interval + delay > monotonic_nanos() {
+                break;
+            }
 
-        // 然后检查 bucket 是否在指定的时间范围内，如果是，执行后续的统计逻辑
-        if bucket.key
-            <= SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos()
-                - delay
-        {
             // 计算统计信息并输出
             if let Some(pop) = buckets.pop() {
-                if pop.key < bucket.key {
-                    continue;
-                }
-
                 last_key = pop.key;
 
                 // cacl stat
@@ -628,12 +1024,30 @@ fn print_stat(
                 for r in pop.values() {
                     let target_result = target_results
                         .entry(r.target.clone())
-                        .or_insert_with(TargetResult::default);
-
-                    target_result.latency += r.latency;
-
-                    if r.received {
+                        .or_insert_with(|| TargetResult {
+                            target: r.target.clone(),
+                            ..Default::default()
+                        });
+
+                    // 发送失败的探测单独计数, 不算作网络丢包
+                    // 延迟超过 timeout 的回复记为迟到, 同时按丢包统计
+                    if r.send_failed {
+                        target_result.send_failed += 1;
+                        continue;
+                    } else if r.received && r.latency > timeout {
+                        target_result.late += 1;
+                        target_result.loss += 1;
+                    } else if r.received {
+                        target_result.latency += r.latency;
+                        target_result.max_latency = target_result.max_latency.max(r.latency);
+                        target_result.record_latency(r.latency);
                         target_result.received += 1;
+                        if let Some(one_way) = r.one_way {
+                            target_result.forward_delay += one_way.forward;
+                            target_result.reverse_delay += one_way.reverse;
+                            target_result.clock_offset += one_way.offset;
+                            target_result.one_way_samples += 1;
+                        }
                     } else {
                         target_result.loss += 1;
                     }
@@ -641,52 +1055,177 @@ fn print_stat(
                     if r.bitflip {
                         target_result.bitflip_count += 1;
                     }
+                    if r.out_of_order {
+                        target_result.out_of_order += 1;
+                    }
+                    // 多路径结果的目标带有流标识, 只比较地址
+                    if r.received && r.responder != multipath::split_flow(&r.target).0 {
+                        target_result.mismatched += 1;
+                        target_result.responders.insert(r.responder.clone());
+                    }
+                    if r.received {
+                        match r.rx_source {
+                            TimestampSource::User => target_result.user_timestamps += 1,
+                            TimestampSource::Software => target_result.software_timestamps += 1,
+                            TimestampSource::Hardware => target_result.hardware_timestamps += 1,
+                        }
+                    }
+                    target_result.duplicate += r.dup_count;
+                    target_result.send_error += r.send_error;
+                    target_result.max_send_error = target_result.max_send_error.max(r.send_error);
                 }
 
-                // 输出和通道发送
-                for (target, tr) in &target_results {
-                    let total = tr.received + tr.loss;
-                    let loss_rate = if total == 0 {
-                        0.0
-                    } else {
-                        (tr.loss as f64) / (total as f64)
-                    };
+                // Bucket 弹出后才到达的回复, 计入该目标本次输出的迟到计数
+                for (target, target_result) in target_results.iter_mut() {
+                    target_result.late += buckets.take_late(target);
+                }
 
-                    if enable_print_stat {
-                        if tr.received == 0 {
-                            info!(
-                                "{}: sent:{}, recv:{}, loss rate: {:.2}%, latency: {}ms",
-                                target,
-                                total,
-                                tr.received,
-                                loss_rate * 100.0,
-                                0
-                            )
-                        } else {
-                            info!(
-                                "{}: sent:{}, recv:{},  loss rate: {:.2}%, latency: {:.2}ms",
-                                target,
-                                total,
-                                tr.received,
-                                loss_rate * 100.0,
-                                Duration::from_nanos(tr.latency as u64 / (tr.received as u64))
-                                    .as_secs_f64()
-                                    * 1000.0
-                            )
-                        }
+                // 没有通过 cookie 或 MAC 检查的回复
+                let (mut foreign, mut spoofed) = (0, 0);
+                for (target, rejected) in buckets.take_rejected() {
+                    foreign += rejected.foreign;
+                    spoofed += rejected.spoofed;
+                    if let Some(target_result) = target_results.get_mut(&target) {
+                        target_result.foreign += rejected.foreign;
+                        target_result.spoofed += rejected.spoofed;
                     }
+                }
+                if enable_print_stat && (foreign > 0 || spoofed > 0) {
+                    warn!("rejected replies: foreign:{}, spoofed:{}", foreign, spoofed);
+                }
 
-                    // 如果有发送接收，将结果发送过去
-                    if has_sender {
-                        let mut tr = tr.clone();
-                        tr.target = target.clone();
-                        tr.loss_rate = loss_rate;
-                        let _ = tx.as_ref().unwrap().send(tr);
+                // 接收队列的丢弃无法对应到目标, 按丢包数的比例分给各个目标并从丢包中扣除
+                // 各目标之和就是本窗口的丢弃数, roll-up 和分组合并时不会重复计数
+                let local = drop_tracker.take();
+                let losses: Vec<u32> = target_results.values().map(|tr| tr.loss).collect();
+                let shares = drops::allocate(local.socket, &losses);
+                for (target_result, share) in target_results.values_mut().zip(shares) {
+                    target_result.loss -= share;
+                    target_result.local_drops = share;
+                }
+                if enable_print_stat
+                    && (local.socket > 0 || local.icmp_errors > 0 || local.icmp_csum_errors > 0)
+                {
+                    warn!(
+                        "local drops: socket queue:{}, icmp errors:{}, icmp checksum errors:{}, socket queue drops are not counted as loss",
+                        local.socket, local.icmp_errors, local.icmp_csum_errors
+                    );
+                }
+
+                // 自适应速率控制根据基础窗口调整速率, 并标注疑似限速的目标
+                let start = pop.key * interval;
+                if let Some(controller) = &controller {
+                    for target_result in target_results.values_mut() {
+                        controller.observe(target_result, start);
                     }
                 }
+                if let Some(detector) = detector.as_mut() {
+                    for target_result in target_results.values_mut() {
+                        detector.check(target_result);
+                    }
+                }
+                if let Some(tracker) = tracker.as_mut() {
+                    for target_result in target_results.values_mut() {
+                        tracker.check(target_result);
+                    }
+                }
+
+                // 先把基础窗口的结果合并到 roll-up 窗口, 再输出基础窗口
+                let rolled: Vec<Vec<TargetResult>> = rollups
+                    .iter_mut()
+                    .filter_map(|rollup| rollup.add(start, &target_results))
+                    .collect();
+
+                let results: Vec<TargetResult> = target_results
+                    .into_values()
+                    .map(|mut tr| {
+                        tr.finish(popt.interval, start);
+                        tr
+                    })
+                    .collect();
+                output_stat(&results, enable_print_stat, &popt, &tx);
+
+                for results in &rolled {
+                    output_stat(results, enable_print_stat, &popt, &tx);
+                }
+            }
+        }
+
+        if done {
+            for rollup in rollups.iter_mut() {
+                if let Some(results) = rollup.flush() {
+                    output_stat(&results, enable_print_stat, &popt, &tx);
+                }
             }
+            break;
         }
     }
 
     Ok(())
 }
+
+// 输出一个窗口的统计结果, 并发送给通道
+// 设置了 significance 时只打印显著变差的结果, 设置了 quiet_targets 时不打印统计行, 所有结果仍然发送给通道
+fn output_stat(
+    results: &[TargetResult],
+    enable_print_stat: bool,
+    popt: &PingOption,
+    tx: &Option<Sender<TargetResult>>,
+) {
+    for tr in results {
+        let enable_print_stat =
+            enable_print_stat && (popt.significance.is_none() || !tr.degradations.is_empty());
+        let print_target = enable_print_stat && !popt.quiet_targets;
+        if enable_print_stat && tr.mismatched > 0 {
+            warn!(
+                "{} [{:?}]: {} replies from other addresses: {:?}",
+                tr.target, tr.window, tr.mismatched, tr.responders
+            );
+        }
+        if print_target {
+            info!(
+                "{} [{:?}]: sent:{}, recv:{}, loss rate: {:.2}%, latency: {:.2}ms, dup:{}, ooo:{}, late:{}, send failed:{}, rate: {:.1}pps, send error: {:.3}ms",
+                tr.target,
+                tr.window,
+                tr.sent(),
+                tr.received,
+                tr.loss_rate * 100.0,
+                Duration::from_nanos(tr.latency as u64).as_secs_f64() * 1000.0,
+                tr.duplicate,
+                tr.out_of_order,
+                tr.late,
+                tr.send_failed,
+                tr.send_rate,
+                Duration::from_nanos(tr.send_error as u64).as_secs_f64() * 1000.0
+            )
+        }
+        if enable_print_stat {
+            for anomaly in &tr.anomalies {
+                warn!("{} [{:?}]: anomaly: {}", tr.target, tr.window, anomaly);
+            }
+            for degradation in &tr.degradations {
+                warn!(
+                    "{} [{:?}]: significant degradation: {}",
+                    tr.target, tr.window, degradation
+                );
+            }
+        }
+        if print_target && tr.rate_limited {
+            info!(
+                "{} [{:?}]: rate-limited suspect, sustainable rate: {:.1}pps",
+                tr.target, tr.window, tr.sustainable_rate
+            );
+        }
+        if print_target && tr.one_way_samples > 0 {
+            info!(
+                "{} [{:?}]: forward: {:.2}ms, reverse: {:.2}ms, clock offset: {:.2}ms",
+                tr.target, tr.window, tr.forward_delay, tr.reverse_delay, tr.clock_offset
+            );
+        }
+
+        // 如果有发送接收，将结果发送过去
+        if let Some(tx) = tx {
+            let _ = tx.send(tr.clone());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+#![cfg(target_os = "linux")]
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pnet_packet::icmp::{self, IcmpTypes};
+use serde::{Deserialize, Serialize};
+
+// ICMP Timestamp 请求和回复的长度: 8 字节头部之后是 originate、receive 和 transmit 三个时间戳
+pub const TIMESTAMP_PACKET_LEN: usize = 20;
+// 时间戳的最高位置位表示不是 UTC 零点以来的毫秒数 (RFC 792), 无法用于估计单向延迟
+const NON_STANDARD: u32 = 0x8000_0000;
+const DAY_MS: f64 = 86_400_000.0;
+
+/// 探测类型
+/// - `Echo` ICMP Echo 请求 (type 8), 测量 RTT
+/// - `Timestamp` ICMP Timestamp 请求 (type 13), 同时根据对端的时间戳估计单向延迟和时钟偏差
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeType {
+    #[default]
+    Echo,
+    Timestamp,
+}
+
+// 构造 ICMP Timestamp 请求
+// 对端只原样返回 originate, 它被用来携带发送时的单调时钟 (微秒, 低 32 位), 用于找回发送时间
+pub fn timestamp_request(ident: u16, seq: u16, txts: u128) -> Vec<u8> {
+    let mut buf = vec![0u8; TIMESTAMP_PACKET_LEN];
+    buf[0] = IcmpTypes::Timestamp.0;
+    buf[4..6].copy_from_slice(&ident.to_be_bytes());
+    buf[6..8].copy_from_slice(&seq.to_be_bytes());
+    buf[8..12].copy_from_slice(&((txts / 1000) as u32).to_be_bytes());
+
+    let checksum = icmp::checksum(&icmp::IcmpPacket::new(&buf).unwrap());
+    buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+    buf
+}
+
+// TimestampReply 是解析后的 ICMP Timestamp 回复
+pub struct TimestampReply {
+    pub ident: u16,
+    pub seq: u16,
+    // 请求的发送时间, 由 originate 和收到回复时的单调时钟还原, 纳秒
+    pub txts: u128,
+    // 对端收到请求和发出回复的时间, UTC 零点以来的毫秒数
+    pub receive: u32,
+    pub transmit: u32,
+}
+
+// 解析 ICMP Timestamp 回复, rxts 是收到回复时的单调时钟
+pub fn parse_timestamp_reply(packet: &[u8], rxts: u128) -> Option<TimestampReply> {
+    if packet.len() < TIMESTAMP_PACKET_LEN || packet[0] != IcmpTypes::TimestampReply.0 {
+        return None;
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().unwrap());
+
+    // originate 在 2^32 微秒 (约 71 分钟) 后回绕, 回复总是在请求之后到达
+    let rx_us = rxts / 1000;
+    let elapsed = (rx_us as u32).wrapping_sub(u32_at(8)) as u128;
+    Some(TimestampReply {
+        ident: u16::from_be_bytes([packet[4], packet[5]]),
+        seq: u16::from_be_bytes([packet[6], packet[7]]),
+        txts: rx_us.checked_sub(elapsed)? * 1000,
+        receive: u32_at(12),
+        transmit: u32_at(16),
+    })
+}
+
+// 把报文中的 16 位序列号还原为扩展序列号, 取与该目标已知最大序列号最接近的值
+pub fn unwrap_seq(seq: u16, max_seq: u64) -> u64 {
+    let base = max_seq & !0xffff;
+    [base.wrapping_sub(0x10000), base, base + 0x10000]
+        .into_iter()
+        .map(|high| high | seq as u64)
+        .min_by_key(|candidate| candidate.abs_diff(max_seq))
+        .unwrap()
+}
+
+// 当前的墙上时钟, UTC 零点以来的毫秒数
+pub fn ms_of_day() -> f64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_nanos() % (DAY_MS as u128 * 1_000_000)) as f64 / 1_000_000.0
+}
+
+// OneWay 是一次 ICMP Timestamp 探测估计的单向延迟和对端时钟偏差, 毫秒
+// 单向延迟包含了两端的时钟偏差, 时钟同步时才是真实的单向延迟
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OneWay {
+    // 去程: 对端接收时间 - 本端发送时间
+    pub forward: f64,
+    // 回程: 本端接收时间 - 对端发送时间
+    pub reverse: f64,
+    // 对端时钟相对本端的偏差, 假设去程和回程的延迟相同
+    pub offset: f64,
+}
+
+impl OneWay {
+    // 根据四个时间戳估计, sent 和 received 是本端的墙上时钟, 都是 UTC 零点以来的毫秒数
+    // 对端的时间戳不是标准格式时返回 None
+    pub fn estimate(sent: f64, receive: u32, transmit: u32, received: f64) -> Option<OneWay> {
+        if receive & NON_STANDARD != 0 || transmit & NON_STANDARD != 0 {
+            return None;
+        }
+        // 对端的时间戳被截断到毫秒, 取该毫秒的中点, 避免估计值整体偏差半毫秒
+        let forward = wrap_day(receive as f64 + 0.5 - sent);
+        let reverse = wrap_day(received - (transmit as f64 + 0.5));
+        Some(OneWay {
+            forward,
+            reverse,
+            offset: (forward - reverse) / 2.0,
+        })
+    }
+}
+
+// 跨过 UTC 零点时把差值调整到 (-12h, 12h] 内
+fn wrap_day(diff: f64) -> f64 {
+    if diff > DAY_MS / 2.0 {
+        diff - DAY_MS
+    } else if diff <= -DAY_MS / 2.0 {
+        diff + DAY_MS
+    } else {
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwrap_seq_nearest() {
+        assert_eq!(unwrap_seq(5, 0), 5);
+        assert_eq!(unwrap_seq(100, 0x2_0050), 0x2_0064);
+        // 序列号回绕后仍然向前
+        assert_eq!(unwrap_seq(2, 0xffff), 0x1_0002);
+        // 回绕之前发出的探测的回复较晚到达
+        assert_eq!(unwrap_seq(0xfffe, 0x1_0001), 0xfffe);
+    }
+
+    #[test]
+    fn timestamp_reply_restores_send_time() {
+        let txts = 5_000_123_456_789;
+        let mut packet = timestamp_request(0x1234, 7, txts);
+        packet[0] = IcmpTypes::TimestampReply.0;
+        packet[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        packet[16..20].copy_from_slice(&1001u32.to_be_bytes());
+
+        let reply = parse_timestamp_reply(&packet, txts + 2_000_000).unwrap();
+        assert_eq!((reply.ident, reply.seq), (0x1234, 7));
+        assert_eq!(reply.txts, txts / 1000 * 1000);
+        assert_eq!((reply.receive, reply.transmit), (1000, 1001));
+
+        // 不是 Timestamp 回复
+        assert!(parse_timestamp_reply(&packet[..12], txts).is_none());
+        packet[0] = IcmpTypes::Timestamp.0;
+        assert!(parse_timestamp_reply(&packet, txts).is_none());
+    }
+
+    #[test]
+    fn one_way_estimate() {
+        let one_way = OneWay::estimate(1000.0, 1010, 1011, 1020.0).unwrap();
+        assert_eq!(one_way.forward, 10.5);
+        assert_eq!(one_way.reverse, 8.5);
+        assert_eq!(one_way.offset, 1.0);
+
+        // 对端在 UTC 零点之后收到请求
+        let one_way = OneWay::estimate(DAY_MS - 5.0, 5, 6, 10.0).unwrap();
+        assert_eq!(one_way.forward, 10.5);
+        assert_eq!(one_way.reverse, 3.5);
+
+        assert!(OneWay::estimate(1000.0, NON_STANDARD | 1010, 1011, 1020.0).is_none());
+        assert!(OneWay::estimate(1000.0, 1010, NON_STANDARD | 1011, 1020.0).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mping::anomaly::Metric;
+use crate::mping::stat::TargetResult;
+
+// 95% 置信度的正态分布分位数
+const Z_95: f64 = 1.96;
+// 95% 置信度的 t 分布分位数, 第 i 个是自由度 i+1, 自由度更大时使用 Z_95
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+// 丢失率的 95% Wilson 区间, 探测数少或丢失率接近 0 和 1 时也不会越界
+// 没有探测时为 (0, 1)
+pub fn wilson_interval(loss: u32, total: u32) -> (f64, f64) {
+    if total == 0 {
+        return (0.0, 1.0);
+    }
+    let n = total as f64;
+    let p = loss as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let denom = 1.0 + z2 / n;
+    let center = (p + z2 / (2.0 * n)) / denom;
+    let half = Z_95 * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denom;
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+// 平均延迟的 95% 区间, 由延迟之和与平方和按 t 分布计算, 纳秒
+// 回复少于 2 个时无法估计, 为 (0, 0)
+pub fn mean_interval(sum: u128, squares: u128, n: u32) -> (u128, u128) {
+    if n < 2 {
+        return (0, 0);
+    }
+    let count = n as f64;
+    let mean = sum as f64 / count;
+    let variance = ((squares as f64 - count * mean * mean) / (count - 1.0)).max(0.0);
+    let t = T_95.get(n as usize - 2).copied().unwrap_or(Z_95);
+    let half = t * (variance / count).sqrt();
+    ((mean - half).max(0.0) as u128, (mean + half) as u128)
+}
+
+// Degradation 是一个窗口中相对参考窗口统计显著变差的指标
+// 延迟为毫秒, 丢失率为 0 到 1, 区间都是 95% 置信区间
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Degradation {
+    pub metric: Metric,
+    pub observed: f64,
+    pub observed_ci: (f64, f64),
+    pub reference: f64,
+    pub reference_ci: (f64, f64),
+}
+
+impl fmt::Display for Degradation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.metric {
+            Metric::Latency => write!(
+                f,
+                "latency {:.2}ms [{:.2}ms, {:.2}ms], reference {:.2}ms [{:.2}ms, {:.2}ms]",
+                self.observed,
+                self.observed_ci.0,
+                self.observed_ci.1,
+                self.reference,
+                self.reference_ci.0,
+                self.reference_ci.1
+            ),
+            Metric::Loss => write!(
+                f,
+                "loss rate {:.2}% [{:.2}%, {:.2}%], reference {:.2}% [{:.2}%, {:.2}%]",
+                self.observed * 100.0,
+                self.observed_ci.0 * 100.0,
+                self.observed_ci.1 * 100.0,
+                self.reference * 100.0,
+                self.reference_ci.0 * 100.0,
+                self.reference_ci.1 * 100.0
+            ),
+        }
+    }
+}
+
+// Sample 是一个窗口的丢包计数和延迟之和, 用于合并出参考窗口
+#[derive(Default, Clone, Copy)]
+struct Sample {
+    loss: u32,
+    received: u32,
+    latency: u128,
+    latency_squares: u128,
+}
+
+impl Sample {
+    fn of(tr: &TargetResult) -> Sample {
+        Sample {
+            loss: tr.loss,
+            received: tr.received,
+            latency: tr.latency,
+            latency_squares: tr.latency_squares,
+        }
+    }
+
+    fn add(&mut self, other: &Sample) {
+        self.loss += other.loss;
+        self.received += other.received;
+        self.latency += other.latency;
+        self.latency_squares += other.latency_squares;
+    }
+
+    fn loss_rate(&self) -> (f64, (f64, f64)) {
+        let total = self.loss + self.received;
+        let rate = if total == 0 {
+            0.0
+        } else {
+            self.loss as f64 / total as f64
+        };
+        (rate, wilson_interval(self.loss, total))
+    }
+
+    // 平均延迟和区间, 毫秒
+    fn latency_ms(&self) -> (f64, (f64, f64)) {
+        let ms = |nanos: u128| nanos as f64 / 1_000_000.0;
+        let mean = self.latency / self.received.max(1) as u128;
+        let (lower, upper) = mean_interval(self.latency, self.latency_squares, self.received);
+        (ms(mean), (ms(lower), ms(upper)))
+    }
+}
+
+// SignificanceTracker 把每个基础窗口与该目标之前的若干个窗口合并成的参考窗口比较
+// 只有该窗口的 95% 区间整体高于参考窗口的 95% 区间时, 才认为丢失率或延迟显著变差
+pub struct SignificanceTracker {
+    // 参考窗口包含的基础窗口数
+    windows: usize,
+    history: HashMap<String, VecDeque<Sample>>,
+}
+
+impl SignificanceTracker {
+    pub fn new_significance_tracker(windows: usize) -> SignificanceTracker {
+        SignificanceTracker {
+            windows,
+            history: HashMap::new(),
+        }
+    }
+
+    // 检查一个基础窗口的结果, 把显著变差的指标记入 tr.degradations, 然后把该窗口加入参考窗口
+    // tr 是尚未 finish 的统计, latency 和 latency_squares 是之和
+    pub fn check(&mut self, tr: &mut TargetResult) {
+        let sample = Sample::of(tr);
+        if sample.loss + sample.received == 0 {
+            return;
+        }
+        let history = self.history.entry(tr.target.clone()).or_default();
+
+        // 参考窗口积累满之后才开始判断
+        if history.len() >= self.windows {
+            let mut reference = Sample::default();
+            for s in history.iter() {
+                reference.add(s);
+            }
+
+            let (observed, observed_ci) = sample.loss_rate();
+            let (base, base_ci) = reference.loss_rate();
+            if observed_ci.0 > base_ci.1 {
+                tr.degradations.push(Degradation {
+                    metric: Metric::Loss,
+                    observed,
+                    observed_ci,
+                    reference: base,
+                    reference_ci: base_ci,
+                });
+            }
+
+            if sample.received >= 2 && reference.received >= 2 {
+                let (observed, observed_ci) = sample.latency_ms();
+                let (base, base_ci) = reference.latency_ms();
+                if observed_ci.0 > base_ci.1 {
+                    tr.degradations.push(Degradation {
+                        metric: Metric::Latency,
+                        observed,
+                        observed_ci,
+                        reference: base,
+                        reference_ci: base_ci,
+                    });
+                }
+            }
+        }
+
+        history.push_back(sample);
+        while history.len() > self.windows {
+            history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn wilson_interval_bounds() {
+        assert_eq!(wilson_interval(0, 0), (0.0, 1.0));
+        assert_close(wilson_interval(5, 10), (0.2366, 0.7634));
+        // 丢失率为 0 和 1 时区间不越界, 且不退化为一个点
+        assert_close(wilson_interval(0, 10), (0.0, 0.2775));
+        assert_close(wilson_interval(10, 10), (0.7225, 1.0));
+        // 探测越多区间越窄
+        let (lower, upper) = wilson_interval(50, 100);
+        assert!(lower > 0.2366 && upper < 0.7634);
+    }
+
+    #[test]
+    fn mean_interval_uses_t_distribution() {
+        assert_eq!(mean_interval(0, 0, 0), (0, 0));
+        assert_eq!(mean_interval(10, 100, 1), (0, 0));
+
+        // 10us, 11us, 12us: 标准差 1us, 自由度 2 的 t = 4.303
+        let values = [10_000u128, 11_000, 12_000];
+        let sum = values.iter().sum();
+        let squares = values.iter().map(|v| v * v).sum();
+        assert_eq!(mean_interval(sum, squares, 3), (8515, 13484));
+
+        // 下界不低于 0
+        assert_eq!(mean_interval(3, 5, 3).0, 0);
+    }
+
+    #[test]
+    fn mean_interval_falls_back_to_normal() {
+        // 一半 10us 一半 12us, 标准差约 1us
+        let interval = |n: u32| {
+            let values: Vec<u128> = (0..n)
+                .map(|i| if i % 2 == 0 { 10_000 } else { 12_000 })
+                .collect();
+            let sum: u128 = values.iter().sum();
+            let squares: u128 = values.iter().map(|v| v * v).sum();
+            let (lower, upper) = mean_interval(sum, squares, n);
+            let mean = sum as f64 / n as f64;
+            let variance = (squares as f64 - n as f64 * mean * mean) / (n as f64 - 1.0);
+            (upper - lower) as f64 / 2.0 / (variance / n as f64).sqrt()
+        };
+        assert!((interval(31) - 2.042).abs() < 0.01);
+        assert!((interval(32) - Z_95).abs() < 0.01);
+
+        // 延迟都相同时区间退化为均值
+        assert_eq!(
+            mean_interval(100 * 5_000, 100 * 5_000 * 5_000, 100),
+            (5_000, 5_000)
+        );
+    }
+}
@@ -0,0 +1,479 @@
+#![cfg(target_os = "linux")]
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::mping::stat::TargetResult;
+
+// 单个 UDP 数据报的最大长度, 避免 IP 分片
+const MAX_DATAGRAM: usize = 1400;
+// Graphite 连接和写入的超时时间
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sink 把每个窗口的结果写到外部的时序数据库
+pub trait Sink: Send {
+    // 写入一批结果, 失败时由调用者记录日志, 这批结果被丢弃
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()>;
+}
+
+// SinkSpec 是命令行中的 sink 配置, 格式为 <类型>:<地址或路径>
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkSpec {
+    // InfluxDB 行协议, 追加写入文件
+    InfluxFile(PathBuf),
+    // InfluxDB 行协议, 通过 UDP 发送
+    InfluxUdp(String),
+    // StatsD gauge, 通过 UDP 发送
+    Statsd(String),
+    // Graphite 明文协议, 通过 TCP 发送
+    Graphite(String),
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, target) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid sink, expected <kind>:<address or path>: {}", s))?;
+        if target.is_empty() {
+            return Err(format!("missing sink address or path: {}", s));
+        }
+        match kind {
+            "influx-file" => Ok(SinkSpec::InfluxFile(PathBuf::from(target))),
+            "influx-udp" => Ok(SinkSpec::InfluxUdp(target.to_string())),
+            "statsd" => Ok(SinkSpec::Statsd(target.to_string())),
+            "graphite" => Ok(SinkSpec::Graphite(target.to_string())),
+            _ => Err(format!(
+                "unknown sink kind {}, expected influx-file, influx-udp, statsd or graphite",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SinkSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkSpec::InfluxFile(path) => write!(f, "influx-file:{}", path.display()),
+            SinkSpec::InfluxUdp(addr) => write!(f, "influx-udp:{}", addr),
+            SinkSpec::Statsd(addr) => write!(f, "statsd:{}", addr),
+            SinkSpec::Graphite(addr) => write!(f, "graphite:{}", addr),
+        }
+    }
+}
+
+impl SinkSpec {
+    // 打开 sink, 文件无法打开或地址无法解析时返回错误
+    pub fn open(&self) -> anyhow::Result<Box<dyn Sink>> {
+        let sink: Box<dyn Sink> = match self {
+            SinkSpec::InfluxFile(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Box::new(InfluxFileSink {
+                    writer: BufWriter::new(file),
+                })
+            }
+            SinkSpec::InfluxUdp(addr) => Box::new(InfluxUdpSink {
+                socket: connect_udp(addr)?,
+            }),
+            SinkSpec::Statsd(addr) => Box::new(StatsdSink {
+                socket: connect_udp(addr)?,
+            }),
+            SinkSpec::Graphite(addr) => Box::new(GraphiteSink {
+                addr: addr.clone(),
+                stream: None,
+            }),
+        };
+        Ok(sink)
+    }
+}
+
+fn connect_udp(addr: &str) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+// 窗口长度的标签, 例如 1s、500ms、1m
+fn window_label(window: Duration) -> String {
+    let ms = window.as_millis();
+    if ms.is_multiple_of(60_000) {
+        format!("{}m", ms / 60_000)
+    } else if ms.is_multiple_of(1000) {
+        format!("{}s", ms / 1000)
+    } else {
+        format!("{}ms", ms)
+    }
+}
+
+// 每个结果输出的指标: 名称和值
+fn fields(tr: &TargetResult) -> [(&'static str, f64); 17] {
+    [
+        ("loss_rate", tr.loss_rate),
+        ("loss_rate_lower", tr.loss_ci.0),
+        ("loss_rate_upper", tr.loss_ci.1),
+        ("latency_ms", tr.latency as f64 / 1_000_000.0),
+        ("latency_ms_lower", tr.latency_ci.0 as f64 / 1_000_000.0),
+        ("latency_ms_upper", tr.latency_ci.1 as f64 / 1_000_000.0),
+        ("max_latency_ms", tr.max_latency as f64 / 1_000_000.0),
+        ("received", tr.received as f64),
+        ("loss", tr.loss as f64),
+        ("bitflip", tr.bitflip_count as f64),
+        ("duplicate", tr.duplicate as f64),
+        ("out_of_order", tr.out_of_order as f64),
+        ("late", tr.late as f64),
+        ("send_failed", tr.send_failed as f64),
+        ("local_drops", tr.local_drops as f64),
+        ("anomalies", tr.anomalies.len() as f64),
+        ("degradations", tr.degradations.len() as f64),
+    ]
+}
+
+// InfluxDB 行协议: mping,target=<目标>,window=<窗口> <字段> <时间戳 ns>
+// 计数字段使用整数类型
+fn influx_line(tr: &TargetResult) -> String {
+    let values: Vec<String> = fields(tr)
+        .iter()
+        .map(|(name, value)| match *name {
+            "loss_rate" | "loss_rate_lower" | "loss_rate_upper" | "latency_ms"
+            | "latency_ms_lower" | "latency_ms_upper" | "max_latency_ms" => {
+                format!("{}={}", name, value)
+            }
+            _ => format!("{}={}i", name, *value as u64),
+        })
+        .collect();
+    format!(
+        "mping,target={},window={} {} {}\n",
+        tr.target.replace([',', ' ', '='], "_"),
+        window_label(tr.window),
+        values.join(","),
+        tr.timestamp as u128 * 1_000_000
+    )
+}
+
+// 指标路径中的目标, 把 . 和 : 替换为 _, 避免被当作层级分隔符
+fn metric_target(target: &str) -> String {
+    target.replace(['.', ':'], "_")
+}
+
+// 把多行合并到尽量少的 UDP 数据报中发送
+fn send_lines(socket: &UdpSocket, lines: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+            socket.send(datagram.as_bytes())?;
+            datagram.clear();
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes())?;
+    }
+    Ok(())
+}
+
+struct InfluxFileSink {
+    writer: BufWriter<File>,
+}
+
+impl Sink for InfluxFileSink {
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+        for tr in batch {
+            self.writer.write_all(influx_line(tr).as_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+struct InfluxUdpSink {
+    socket: UdpSocket,
+}
+
+impl Sink for InfluxUdpSink {
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+        send_lines(&self.socket, batch.iter().map(influx_line))
+    }
+}
+
+// StatsD 没有时间戳, 每个指标作为 gauge 发送: mping.<窗口>.<目标>.<字段>:<值>|g
+struct StatsdSink {
+    socket: UdpSocket,
+}
+
+impl Sink for StatsdSink {
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+        let lines = batch.iter().flat_map(|tr| {
+            let prefix = format!(
+                "mping.{}.{}",
+                window_label(tr.window),
+                metric_target(&tr.target)
+            );
+            fields(tr)
+                .into_iter()
+                .map(move |(name, value)| format!("{}.{}:{}|g\n", prefix, name, value))
+        });
+        send_lines(&self.socket, lines)
+    }
+}
+
+// Graphite 明文协议: mping.<窗口>.<目标>.<字段> <值> <时间戳 s>
+// 连接断开后在下一批结果到达时重连
+struct GraphiteSink {
+    addr: String,
+    stream: Option<TcpStream>,
+}
+
+impl GraphiteSink {
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not resolved", self.addr),
+            )
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        info!("graphite sink connected to {}", self.addr);
+        Ok(stream)
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+        let mut buf = String::new();
+        for tr in batch {
+            let prefix = format!(
+                "mping.{}.{}",
+                window_label(tr.window),
+                metric_target(&tr.target)
+            );
+            for (name, value) in fields(tr) {
+                buf.push_str(&format!(
+                    "{}.{} {} {}\n",
+                    prefix,
+                    name,
+                    value,
+                    tr.timestamp / 1000
+                ));
+            }
+        }
+
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let result = self.stream.as_mut().unwrap().write_all(buf.as_bytes());
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+}
+
+// SinkQueue 是一个 sink 的有界队列, 队列满时丢弃结果而不是阻塞调用者
+pub struct SinkQueue {
+    name: String,
+    tx: SyncSender<TargetResult>,
+    dropped: Arc<AtomicU64>,
+    handle: JoinHandle<()>,
+}
+
+impl SinkQueue {
+    // 放入一个结果, 队列满时丢弃并计数, 第一次和之后每 1000 次丢弃时输出警告
+    pub fn offer(&self, tr: TargetResult) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(tr) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped == 1 || dropped.is_multiple_of(1000) {
+                warn!(
+                    "sink {} is falling behind, {} results dropped",
+                    self.name, dropped
+                );
+            }
+        }
+    }
+
+    // 关闭队列, 等待 sink 线程写完队列中剩余的结果
+    pub fn close(self) {
+        drop(self.tx);
+        let _ = self.handle.join();
+    }
+}
+
+// 打开命令行配置的 sink 并在单独的线程中运行, 返回它的队列
+pub fn spawn(spec: &SinkSpec, capacity: usize) -> anyhow::Result<SinkQueue> {
+    Ok(spawn_sink(spec.to_string(), spec.open()?, capacity))
+}
+
+// 在单独的线程中运行 sink, 返回它的队列
+pub fn spawn_sink(name: String, sink: Box<dyn Sink>, capacity: usize) -> SinkQueue {
+    let (tx, rx) = mpsc::sync_channel(capacity.max(1));
+    let thread_name = name.clone();
+    let handle = thread::spawn(move || run(rx, sink, thread_name));
+
+    SinkQueue {
+        name,
+        tx,
+        dropped: Arc::new(AtomicU64::new(0)),
+        handle,
+    }
+}
+
+// 每次取出队列中所有的结果作为一批写入, 直到队列关闭
+fn run(rx: Receiver<TargetResult>, mut sink: Box<dyn Sink>, name: String) {
+    while let Ok(tr) = rx.recv() {
+        let mut batch = vec![tr];
+        batch.extend(rx.try_iter());
+        if let Err(e) = sink.write(&batch) {
+            warn!(
+                "sink {} failed, {} results dropped: {}",
+                name,
+                batch.len(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    fn result(target: &str) -> TargetResult {
+        TargetResult {
+            target: target.to_string(),
+            window: Duration::from_secs(1),
+            timestamp: 1_700_000_000_000,
+            received: 9,
+            loss: 1,
+            loss_rate: 0.1,
+            latency: 2_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_sink_specs() {
+        assert_eq!(
+            "influx-file:/tmp/mping.lp".parse::<SinkSpec>(),
+            Ok(SinkSpec::InfluxFile(PathBuf::from("/tmp/mping.lp")))
+        );
+        assert_eq!(
+            "statsd:127.0.0.1:8125".parse::<SinkSpec>(),
+            Ok(SinkSpec::Statsd("127.0.0.1:8125".to_string()))
+        );
+        let spec: SinkSpec = "graphite:localhost:2003".parse().unwrap();
+        assert_eq!(spec.to_string(), "graphite:localhost:2003");
+        assert!("influx-udp:".parse::<SinkSpec>().is_err());
+        assert!("prometheus:localhost:9090".parse::<SinkSpec>().is_err());
+        assert!("statsd".parse::<SinkSpec>().is_err());
+    }
+
+    #[test]
+    fn influx_line_format() {
+        let line = influx_line(&result("10.0.0.1"));
+        assert!(line.starts_with("mping,target=10.0.0.1,window=1s loss_rate=0.1,"));
+        assert!(line.ends_with(" 1700000000000000000\n"));
+        assert!(line.contains(",latency_ms=2,"));
+        assert!(line.contains(",received=9i,loss=1i,"));
+
+        // 标签中的分隔符被替换
+        let line = influx_line(&result("a b,c=d"));
+        assert!(line.starts_with("mping,target=a_b_c_d,window=1s "));
+    }
+
+    #[test]
+    fn statsd_gauges() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let mut sink = StatsdSink {
+            socket: connect_udp(&server.local_addr().unwrap().to_string()).unwrap(),
+        };
+        sink.write(&[result("10.0.0.1")]).unwrap();
+
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let n = server.recv(&mut buf).unwrap();
+        let datagram = String::from_utf8_lossy(&buf[..n]);
+        let lines: Vec<&str> = datagram.lines().collect();
+        assert_eq!(lines[0], "mping.1s.10_0_0_1.loss_rate:0.1|g");
+        assert!(lines.contains(&"mping.1s.10_0_0_1.received:9|g"));
+        assert_eq!(lines.len(), fields(&result("10.0.0.1")).len());
+    }
+
+    #[test]
+    fn graphite_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = GraphiteSink {
+            addr: listener.local_addr().unwrap().to_string(),
+            stream: None,
+        };
+        let read_line = |listener: &TcpListener| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            line
+        };
+
+        sink.write(&[result("10.0.0.1")]).unwrap();
+        assert_eq!(
+            read_line(&listener),
+            "mping.1s.10_0_0_1.loss_rate 0.1 1700000000\n"
+        );
+
+        // 服务端关闭连接后写入失败, 下一批结果到达时重新连接
+        let mut failed = false;
+        for _ in 0..50 {
+            if sink.write(&[result("10.0.0.1")]).is_err() {
+                failed = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(failed);
+        assert!(sink.stream.is_none());
+
+        sink.write(&[result("10.0.0.2")]).unwrap();
+        assert_eq!(
+            read_line(&listener),
+            "mping.1s.10_0_0_2.loss_rate 0.1 1700000000\n"
+        );
+    }
+
+    struct MemorySink(Arc<Mutex<Vec<String>>>);
+
+    impl Sink for MemorySink {
+        fn write(&mut self, batch: &[TargetResult]) -> io::Result<()> {
+            thread::sleep(Duration::from_millis(10));
+            let mut targets = self.0.lock().unwrap();
+            targets.extend(batch.iter().map(|tr| tr.target.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn close_flushes_queue() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let queue = spawn_sink(
+            "memory".to_string(),
+            Box::new(MemorySink(written.clone())),
+            16,
+        );
+        for i in 0..10 {
+            queue.offer(result(&format!("10.0.0.{}", i)));
+        }
+        queue.close();
+        assert_eq!(written.lock().unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,636 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc::{
+    c_int, c_void, iovec, msghdr, recvmsg, setsockopt, sockaddr_in, IPPROTO_IP, IP_RECVTTL, IP_TTL,
+    MSG_DONTWAIT, MSG_ERRQUEUE, SOF_TIMESTAMPING_TX_SOFTWARE, SOL_SOCKET, SO_TIMESTAMPING,
+};
+use log::{info, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::mping::drops;
+use crate::mping::multipath;
+use crate::mping::pacing::{self, Pacer};
+use crate::mping::ping::{self, monotonic_nanos, PingOption};
+use crate::mping::probe::OneWay;
+use crate::mping::stat::{Buckets, Result, TargetResult, Timestamp};
+use crate::mping::timestamping;
+
+// STAMP 的默认端口 (RFC 8762)
+pub const STAMP_PORT: u16 = 862;
+// 非认证模式下 Session-Sender 和 Session-Reflector 测试报文的最小长度
+pub const STAMP_LEN: usize = 44;
+// NTP 纪元 (1900 年) 与 Unix 纪元之间的秒数
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+// 误差估计 (RFC 4656): S=0 时钟未与外部时钟同步, Z=0 NTP 格式, Scale=22, Multiplier=1, 约 1ms
+const ERROR_ESTIMATE: u16 = 0x1601;
+
+// 当前的墙上时钟, Unix 纳秒
+fn realtime_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+// Unix 纳秒转换为 64 位 NTP 时间戳, 小数部分向上取整, 保证转换回来得到相同的纳秒数
+fn to_ntp(nanos: u128) -> u64 {
+    let secs = (nanos / 1_000_000_000) as u64 + NTP_UNIX_OFFSET;
+    let frac = ((nanos % 1_000_000_000) << 32).div_ceil(1_000_000_000) as u64;
+    (secs << 32) + frac
+}
+
+// 64 位 NTP 时间戳转换为 Unix 纳秒
+fn from_ntp(ntp: u64) -> u128 {
+    let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET) as u128;
+    let frac = ((ntp & 0xffff_ffff) as u128 * 1_000_000_000) >> 32;
+    secs * 1_000_000_000 + frac
+}
+
+// 构造 Session-Sender 测试报文: 序列号、发送时间戳 T1、误差估计, 之后全部为 0
+fn sender_packet(seq: u32, t1: u128, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len.max(STAMP_LEN)];
+    buf[0..4].copy_from_slice(&seq.to_be_bytes());
+    buf[4..12].copy_from_slice(&to_ntp(t1).to_be_bytes());
+    buf[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+    buf
+}
+
+// 构造 Session-Reflector 测试报文, 长度与收到的报文相同
+// 无状态模式: 反射端的序列号直接使用发送端的序列号
+fn reflector_packet(request: &[u8], t2: u128, t3: u128, ttl: u8) -> Vec<u8> {
+    let mut buf = vec![0u8; request.len()];
+    buf[0..4].copy_from_slice(&request[0..4]);
+    buf[4..12].copy_from_slice(&to_ntp(t3).to_be_bytes());
+    buf[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+    buf[16..24].copy_from_slice(&to_ntp(t2).to_be_bytes());
+    // 发送端的序列号、时间戳和误差估计
+    buf[24..38].copy_from_slice(&request[0..14]);
+    buf[40] = ttl;
+    buf
+}
+
+// Reflected 是解析后的 Session-Reflector 测试报文, 时间戳都是 Unix 纳秒
+struct Reflected {
+    // 发送端的序列号
+    seq: u32,
+    // 发送端发送时间
+    t1: u128,
+    // 反射端接收时间
+    t2: u128,
+    // 反射端发送时间
+    t3: u128,
+}
+
+fn parse_reflected(buf: &[u8]) -> Option<Reflected> {
+    if buf.len() < STAMP_LEN {
+        return None;
+    }
+    let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+    Some(Reflected {
+        seq: u32::from_be_bytes(buf[24..28].try_into().unwrap()),
+        t1: from_ntp(u64_at(28)),
+        t2: from_ntp(u64_at(16)),
+        t3: from_ntp(u64_at(4)),
+    })
+}
+
+// 把报文中的 32 位序列号还原为扩展序列号, 取与该目标已知最大序列号最接近的值
+fn unwrap_seq(seq: u32, max_seq: u64) -> u64 {
+    let base = max_seq & !0xffff_ffff;
+    [base.wrapping_sub(1 << 32), base, base + (1 << 32)]
+        .into_iter()
+        .map(|high| high | seq as u64)
+        .min_by_key(|candidate| candidate.abs_diff(max_seq))
+        .unwrap()
+}
+
+// 开启 SO_TIMESTAMPING, tx 为 false 时只请求接收时间戳
+fn enable_timestamping(socket: &Socket, tx: bool) -> bool {
+    let mut flags = timestamping::timestamping_flags(false);
+    if !tx {
+        flags &= !SOF_TIMESTAMPING_TX_SOFTWARE;
+    }
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_TIMESTAMPING,
+            &flags as *const _ as *const c_void,
+            mem::size_of_val(&flags) as u32,
+        )
+    };
+    if ret == -1 {
+        warn!("Failed to set SO_TIMESTAMPING, user space timestamps are used");
+        return false;
+    }
+    true
+}
+
+// Received 是 recvmsg 收到的一个 UDP 报文及其控制消息
+struct Received {
+    len: usize,
+    from: SocketAddr,
+    kernel_rxts: Option<Timestamp>,
+    ttl: Option<u8>,
+    rxq_drops: Option<u32>,
+}
+
+// 接收一个 UDP 报文, 同时取出内核接收时间戳、TTL 和接收队列丢弃数
+fn recv(socket: &Socket, buf: &mut [u8], flags: c_int) -> std::io::Result<Received> {
+    let mut control_buf = [0u8; 1024];
+    let mut addr: sockaddr_in = unsafe { mem::zeroed() };
+    let mut iovec = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut msghdr = msghdr {
+        msg_name: &mut addr as *mut _ as *mut c_void,
+        msg_namelen: mem::size_of::<sockaddr_in>() as u32,
+        msg_iov: &mut iovec,
+        msg_iovlen: 1,
+        msg_control: control_buf.as_mut_ptr() as *mut c_void,
+        msg_controllen: control_buf.len(),
+        msg_flags: 0,
+    };
+
+    let nbytes = unsafe { recvmsg(socket.as_raw_fd(), &mut msghdr, flags) };
+    if nbytes == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    let from = SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ));
+    Ok(Received {
+        len: nbytes as usize,
+        from,
+        kernel_rxts: ping::get_timestamp(&mut msghdr),
+        ttl: get_ttl(&mut msghdr),
+        rxq_drops: ping::get_rxq_drops(&mut msghdr),
+    })
+}
+
+// 从 IP_TTL 控制消息中获取收到的报文的 TTL, 需要开启 IP_RECVTTL
+fn get_ttl(msghdr: &mut msghdr) -> Option<u8> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msghdr) };
+
+    while !cmsg.is_null() {
+        if unsafe { (*cmsg).cmsg_level == IPPROTO_IP && (*cmsg).cmsg_type == IP_TTL } {
+            let ttl = unsafe { *(libc::CMSG_DATA(cmsg) as *const c_int) };
+            return Some(ttl as u8);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msghdr, cmsg) };
+    }
+
+    None
+}
+
+/// 运行 STAMP Session-Reflector, 把收到的每个测试报文加上接收和发送时间戳后发回, 一直运行
+pub fn run_reflector(listen: &str) -> anyhow::Result<()> {
+    let addr: SocketAddr = listen.parse()?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.bind(&addr.into())?;
+    enable_timestamping(&socket, false);
+    let enable: c_int = 1;
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            IPPROTO_IP,
+            IP_RECVTTL,
+            &enable as *const _ as *const c_void,
+            mem::size_of_val(&enable) as u32,
+        )
+    };
+    if ret == -1 {
+        warn!("Failed to set IP_RECVTTL, sender TTL is reported as 0");
+    }
+    info!("STAMP reflector listening on {}", addr);
+
+    let mut buf = [0u8; 2048];
+    let mut senders = HashSet::new();
+    loop {
+        let received = match recv(&socket, &mut buf, 0) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let t2 = received
+            .kernel_rxts
+            .map(|ts| ts.nanos)
+            .unwrap_or_else(realtime_nanos);
+        if received.len < STAMP_LEN {
+            continue;
+        }
+        if senders.insert(received.from) {
+            info!("STAMP session from {}", received.from);
+        }
+
+        let reply = reflector_packet(
+            &buf[..received.len],
+            t2,
+            realtime_nanos(),
+            received.ttl.unwrap_or(0),
+        );
+        if let Err(e) = socket.send_to(&reply, &received.from.into()) {
+            warn!("failed to reflect to {}: {}", received.from, e);
+        }
+    }
+}
+
+/// 运行 STAMP Session-Sender, 统计结果与 ICMP ping 相同, 通过 `tx` 发送并在日志中输出
+/// 设置了 `popt.count` 时发送完成后返回, 否则直到 `stop` 被设置
+pub fn run_sender(
+    addrs: Vec<SocketAddr>,
+    mut popt: PingOption,
+    enable_print_stat: bool,
+    tx: Option<Sender<TargetResult>>,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("no reflectors to send to"));
+    }
+    if popt.interval.is_zero() {
+        popt.interval = Duration::from_secs(1);
+    }
+
+    // 多路径模式下每个流标识使用一个 socket, 即一个源端口
+    let flows = popt.flows.max(1);
+    let mut sockets = Vec::new();
+    let mut tx_timestamping = true;
+    for _ in 0..flows {
+        let (socket, timestamping) = sender_socket(&popt)?;
+        tx_timestamping &= timestamping;
+        sockets.push(socket);
+    }
+
+    let buckets = Arc::new(Mutex::new(Buckets::new_buckets()));
+    let rxq_drops = Arc::new(AtomicU32::new(0));
+
+    // 发送结束后通知 read 和统计线程退出, 退出前还要等待最后的回复
+    let done = Arc::new(AtomicBool::new(false));
+
+    let print_buckets = buckets.clone();
+    let print_opt = popt.clone();
+    let print_drops = rxq_drops.clone();
+    let print_stop = done.clone();
+    let print_handle = thread::spawn(move || {
+        ping::print_stat(
+            print_buckets,
+            print_opt,
+            enable_print_stat,
+            tx,
+            print_drops,
+            None,
+            print_stop,
+        )
+    });
+
+    let mut read_handles = Vec::new();
+    for (flow, socket) in sockets.iter().enumerate() {
+        let read_socket = socket.try_clone()?;
+        let flow = (flows > 1).then_some(flow as u16);
+        let read_buckets = buckets.clone();
+        let interval = popt.interval.as_nanos();
+        let read_drops = rxq_drops.clone();
+        let read_stop = done.clone();
+        read_handles.push(thread::spawn(move || {
+            read(
+                read_socket,
+                flow,
+                read_buckets,
+                interval,
+                read_drops,
+                read_stop,
+            )
+        }));
+    }
+
+    // 与 ICMP ping 相同, 发送结束后等待 timeout 收取最后的回复, 统计线程输出剩余的窗口后退出
+    let timeout = popt.timeout;
+    let mut result = send(sockets, addrs, popt, buckets, tx_timestamping, &stop);
+    if result.is_ok() {
+        thread::sleep(timeout);
+    }
+    done.store(true, Ordering::Relaxed);
+    for handle in read_handles {
+        let read_result = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("read thread panicked"))?;
+        result = result.and(read_result);
+    }
+    let print_result = print_handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("print_stat thread panicked"))?;
+    result.and(print_result)
+}
+
+// 创建 Session-Sender 的 socket, 返回是否支持内核发送时间戳
+fn sender_socket(popt: &PingOption) -> anyhow::Result<(Socket, bool)> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).into())?;
+    socket.set_ttl(popt.ttl)?;
+    if let Some(tos) = popt.tos {
+        socket.set_tos(tos)?;
+    }
+    socket.set_read_timeout(Some(popt.timeout))?;
+    drops::setup_rcvbuf(&socket, popt.rcvbuf);
+    drops::enable_rxq_ovfl(&socket);
+    let tx_timestamping = enable_timestamping(&socket, true);
+    Ok((socket, tx_timestamping))
+}
+
+fn send(
+    sockets: Vec<Socket>,
+    addrs: Vec<SocketAddr>,
+    popt: PingOption,
+    buckets: Arc<Mutex<Buckets>>,
+    tx_timestamping: bool,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    // 每个 (反射端, 流标识) 占一个位置, 按各自的速率发送
+    let flows = sockets.len();
+    let slots = addrs.len() * flows;
+    let mut pacer = Pacer::new_pacer(slots, popt.rate, popt.rate_for_all, popt.jitter);
+    let mut sent_count = 0;
+    let mut send_errors = HashSet::new();
+    let mut errqueue = [0u8; 2048];
+
+    while !stop.load(Ordering::Relaxed) {
+        let (index, round, due) = pacer.next(monotonic_nanos());
+        if !pacing::wait_until(due, pacer.spin(), stop) {
+            break;
+        }
+        let seq = round + 1;
+        let addr = addrs[index / flows];
+        let socket = &sockets[index % flows];
+        let target = if flows > 1 {
+            multipath::flow_target(&addr.to_string(), (index % flows) as u16)
+        } else {
+            addr.to_string()
+        };
+
+        let txts = monotonic_nanos();
+        let buf = sender_packet(seq as u32, realtime_nanos(), popt.len);
+        let key = txts / popt.interval.as_nanos();
+        buckets.lock().unwrap().add(
+            key,
+            Result {
+                txts,
+                target: target.clone(),
+                seq,
+                send_error: txts.saturating_sub(due),
+                ..Default::default()
+            },
+        );
+
+        match socket.send_to(&buf, &SockAddr::from(addr)) {
+            Ok(_) => {
+                // 内核的软件发送时间戳与接收时间戳来自同一个时钟, 用于计算更准确的 RTT
+                if tx_timestamping {
+                    if let Ok(sent) = recv(socket, &mut errqueue, MSG_ERRQUEUE | MSG_DONTWAIT) {
+                        if let Some(ts) = sent.kernel_rxts {
+                            buckets.lock().unwrap().update_txts(key, target, seq, ts);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                if send_errors.insert((addr, e.raw_os_error().unwrap_or(0))) {
+                    warn!(
+                        "send to {} failed: {}, later failures are counted as send failed",
+                        addr, e
+                    );
+                }
+                buckets.lock().unwrap().mark_send_failed(key, target, seq);
+            }
+        }
+
+        if index + 1 < slots {
+            continue;
+        }
+        sent_count += 1;
+        if popt.count.is_some() && sent_count >= popt.count.unwrap() {
+            info!("reached {} and exit", sent_count);
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+fn read(
+    socket: Socket,
+    flow: Option<u16>,
+    buckets: Arc<Mutex<Buckets>>,
+    interval: u128,
+    rxq_drops: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 2048];
+    let mut max_seqs: HashMap<String, u64> = HashMap::new();
+    // 本 socket 的接收队列累计丢弃数, 多个 socket 的增量累加到 rxq_drops
+    let mut last_dropped = 0u32;
+
+    while !stop.load(Ordering::Relaxed) {
+        let received = match recv(&socket, &mut buf, 0) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let rxts = monotonic_nanos();
+        if let Some(dropped) = received.rxq_drops {
+            rxq_drops.fetch_add(dropped.wrapping_sub(last_dropped), Ordering::Relaxed);
+            last_dropped = dropped;
+        }
+        let reflected = match parse_reflected(&buf[..received.len]) {
+            Some(reflected) => reflected,
+            None => continue,
+        };
+
+        let target = match flow {
+            Some(flow) => multipath::flow_target(&received.from.to_string(), flow),
+            None => received.from.to_string(),
+        };
+        let max_seq = max_seqs.entry(target.clone()).or_insert(0);
+        let seq = unwrap_seq(reflected.seq, *max_seq);
+        let out_of_order = seq < *max_seq;
+        *max_seq = (*max_seq).max(seq);
+
+        // T4 优先使用内核接收时间戳, 与 T1 一样是墙上时钟
+        let t4 = received
+            .kernel_rxts
+            .map(|ts| ts.nanos)
+            .unwrap_or_else(realtime_nanos);
+        let ms = |a: u128, b: u128| (a as f64 - b as f64) / 1_000_000.0;
+        let forward = ms(reflected.t2, reflected.t1);
+        let reverse = ms(t4, reflected.t3);
+
+        // 回复只带回墙上时钟的 T1, 换算成单调时钟后在相邻的窗口中查找请求
+        let elapsed = realtime_nanos().saturating_sub(reflected.t1);
+        let txts = rxts.saturating_sub(elapsed);
+        let buckets = buckets.lock().unwrap();
+        let key = buckets.key_near(txts / interval, &target, seq);
+        buckets.add_reply(
+            key,
+            Result {
+                txts,
+                rxts,
+                kernel_rxts: received.kernel_rxts,
+                target,
+                responder: received.from.to_string(),
+                seq,
+                received: true,
+                out_of_order,
+                turnaround: reflected.t3.saturating_sub(reflected.t2),
+                one_way: Some(OneWay {
+                    forward,
+                    reverse,
+                    offset: (forward - reverse) / 2.0,
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    Ok(())
+}
+
+// 解析反射端地址, 没有端口时使用 STAMP 的默认端口
+// Session-Sender 的 socket 是 IPv4 的, IPv6 地址返回 None
+pub fn parse_reflector(s: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return addr.is_ipv4().then_some(addr);
+    }
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return ip.is_ipv4().then_some(SocketAddr::new(ip, STAMP_PORT));
+    }
+    use std::net::ToSocketAddrs;
+    let with_port = if s.contains(':') {
+        s.to_string()
+    } else {
+        format!("{}:{}", s, STAMP_PORT)
+    };
+    with_port
+        .to_socket_addrs()
+        .ok()?
+        .find(|addr| addr.is_ipv4())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+
+    #[test]
+    fn ntp_round_trip() {
+        assert_eq!(to_ntp(0), NTP_UNIX_OFFSET << 32);
+        assert_eq!(
+            to_ntp(1_500_000_000),
+            ((NTP_UNIX_OFFSET + 1) << 32) | 0x8000_0000
+        );
+        for nanos in [0, 1, 999_999_999, 1_700_000_000_123_456_789] {
+            assert_eq!(from_ntp(to_ntp(nanos)), nanos);
+        }
+        // NTP 纪元之后、Unix 纪元之前的时间戳
+        assert_eq!(from_ntp(1 << 32), 0);
+    }
+
+    #[test]
+    fn unwrap_seq_nearest() {
+        assert_eq!(unwrap_seq(5, 0), 5);
+        assert_eq!(unwrap_seq(2, 0xffff_ffff), 0x1_0000_0002);
+        assert_eq!(unwrap_seq(0xffff_fffe, 0x1_0000_0001), 0xffff_fffe);
+    }
+
+    #[test]
+    fn parse_reflector_addresses() {
+        assert_eq!(
+            parse_reflector("10.0.0.1"),
+            Some("10.0.0.1:862".parse().unwrap())
+        );
+        assert_eq!(
+            parse_reflector("10.0.0.1:8620"),
+            Some("10.0.0.1:8620".parse().unwrap())
+        );
+        assert_eq!(
+            parse_reflector("localhost"),
+            Some("127.0.0.1:862".parse().unwrap())
+        );
+        assert_eq!(parse_reflector("::1"), None);
+        assert_eq!(parse_reflector("[::1]:862"), None);
+        assert_eq!(parse_reflector("not a host"), None);
+    }
+
+    #[test]
+    fn reflected_packet_carries_sender_fields() {
+        let request = sender_packet(7, 1_700_000_000_000_000_000, STAMP_LEN);
+        let reply = reflector_packet(
+            &request,
+            1_700_000_000_001_000_000,
+            1_700_000_000_002_000_000,
+            63,
+        );
+        assert_eq!(reply.len(), STAMP_LEN);
+        assert_eq!(reply[40], 63);
+        let reflected = parse_reflected(&reply).unwrap();
+        assert_eq!(reflected.seq, 7);
+        assert_eq!(reflected.t1, 1_700_000_000_000_000_000);
+        assert_eq!(reflected.t2, 1_700_000_000_001_000_000);
+        assert_eq!(reflected.t3, 1_700_000_000_002_000_000);
+        assert!(parse_reflected(&reply[..STAMP_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn sender_and_reflector_on_loopback() {
+        // 取一个空闲端口给反射端, 反射端一直运行到测试进程结束
+        let listen = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let reflector_listen = listen.to_string();
+        thread::spawn(move || run_reflector(&reflector_listen));
+        thread::sleep(Duration::from_millis(100));
+
+        let popt = PingOption {
+            timeout: Duration::from_secs(1),
+            ttl: 64,
+            len: STAMP_LEN,
+            rate: 20,
+            delay: 1,
+            count: Some(3),
+            interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        run_sender(
+            vec![listen],
+            popt,
+            false,
+            Some(tx),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let results: Vec<TargetResult> = rx.into_iter().collect();
+        assert!(results.iter().all(|tr| tr.target == listen.to_string()));
+        let received: u32 = results.iter().map(|tr| tr.received).sum();
+        let loss: u32 = results.iter().map(|tr| tr.loss).sum();
+        assert_eq!((received, loss), (3, 0));
+        assert!(results.iter().any(|tr| tr.one_way_samples > 0));
+    }
+}
@@ -1,12 +1,30 @@
 #![cfg(target_os = "linux")]
 
 use std::cmp::Ordering;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
+    collections::BTreeMap,
+    collections::BTreeSet,
     collections::BinaryHeap,
     collections::HashMap,
     sync::{Mutex, RwLock},
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::mping::anomaly::Anomaly;
+use crate::mping::auth::Verdict;
+use crate::mping::ping::monotonic_nanos;
+use crate::mping::probe::OneWay;
+use crate::mping::significance::{self, Degradation};
+
+// 内核 RTT 比单调时钟的 RTT 大出该值 (纳秒) 以上时, 认为内核时间戳不可信
+const KERNEL_RTT_TOLERANCE: u128 = 100_000;
+// 单调时钟的 RTT 包含用户态的调度延迟, 但比内核 RTT 大出该值 (纳秒) 以上时, 认为内核时间戳不可信
+const KERNEL_RTT_MAX_GAP: u128 = 50_000_000;
+
 // Buckets 用于存储所有未处理的 Bucket
 #[derive(Default)]
 pub struct Buckets {
@@ -14,6 +32,21 @@ pub struct Buckets {
     pub buckets: Mutex<BinaryHeap<Bucket>>,
     // 利用 HashMap 通过 key 快速查找 Bucket
     pub map: Mutex<HashMap<u128, Bucket>>,
+    // 已经弹出的最大 key, 比它更小的 Bucket 已经统计过了
+    pub popped: Mutex<u128>,
+    // 所属 Bucket 已被弹出后才到达的回复计数, 按目标统计
+    pub late: Mutex<HashMap<String, u32>>,
+    // 没有通过会话检查的回复计数, 按回复的源地址统计
+    pub rejected: Mutex<HashMap<String, Rejected>>,
+}
+
+// Rejected 是没有通过会话检查的回复计数
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Rejected {
+    // cookie 不匹配, 属于其它会话的回复
+    pub foreign: u32,
+    // cookie 匹配但 MAC 校验失败的回复
+    pub spoofed: u32,
 }
 
 impl Buckets {
@@ -22,10 +55,13 @@ impl Buckets {
         Buckets {
             buckets: Mutex::new(BinaryHeap::new()),
             map: Mutex::new(HashMap::new()),
+            popped: Mutex::new(0),
+            late: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
         }
     }
 
-    // 将 ping 结果添加到 Buckets 中, Bucket 中的 key 是以秒为单位的时间戳
+    // 将 ping 结果添加到 Buckets 中, Bucket 中的 key 是以聚合窗口为单位的时间戳
     pub fn add(&self, key: u128, value: Result) {
         let mut map = self.map.lock().unwrap();
         map.entry(key).or_insert_with(|| {
@@ -38,10 +74,16 @@ impl Buckets {
         bucket.add(value);
     }
 
-    // 将 ping 回复添加到 Buckets 中, Bucket 中的 key 是以秒为单位的时间戳
+    // 将 ping 回复添加到 Buckets 中, Bucket 中的 key 是以聚合窗口为单位的时间戳
+    // 如果对应的 Bucket 已经被弹出, 则只记为迟到的回复
     pub fn add_reply(&self, key: u128, result: Result) {
         let mut map = self.map.lock().unwrap();
 
+        if !map.contains_key(&key) && key <= *self.popped.lock().unwrap() {
+            *self.late.lock().unwrap().entry(result.target).or_insert(0) += 1;
+            return;
+        }
+
         map.entry(key).or_insert_with(|| {
             self.buckets.lock().unwrap().push(Bucket::new_bucket(key));
             Bucket::new_bucket(key)
@@ -51,9 +93,31 @@ impl Buckets {
         bucket.add_reply(result);
     }
 
+    // 回复的发送时间只能近似得到时, 在相邻的 Bucket 中查找对应的请求, 找不到时返回 key
+    pub fn key_near(&self, key: u128, target: &str, seq: u64) -> u128 {
+        let map = self.map.lock().unwrap();
+        let id = format!("{}-{}", target, seq);
+        [key, key.saturating_sub(1), key + 1]
+            .into_iter()
+            .find(|k| {
+                map.get(k)
+                    .is_some_and(|bucket| bucket.value.read().unwrap().contains_key(&id))
+            })
+            .unwrap_or(key)
+    }
+
+    // 把一次探测标记为发送失败
+    pub fn mark_send_failed(&self, key: u128, target: String, seq: u64) {
+        let map = self.map.lock().unwrap();
+
+        if let Some(bucket) = map.get(&key) {
+            bucket.mark_send_failed(target, seq);
+        }
+    }
+
     // 发送后更新 ping 结果的 txts（软件/硬件时间戳）
-    // Bucket 中的 key 是以秒为单位的时间戳
-    pub fn update_txts(&self, key: u128, target: String, seq: u16, txts: u128) {
+    // Bucket 中的 key 是以聚合窗口为单位的时间戳
+    pub fn update_txts(&self, key: u128, target: String, seq: u64, txts: Timestamp) {
         let map = self.map.lock().unwrap();
 
         if let Some(bucket) = map.get(&key) {
@@ -66,9 +130,33 @@ impl Buckets {
         let mut buckets = self.buckets.lock().unwrap();
         let bucket = buckets.pop()?;
         let bucket = self.map.lock().unwrap().remove(&bucket.key).unwrap();
+        let mut popped = self.popped.lock().unwrap();
+        *popped = (*popped).max(bucket.key);
         Some(bucket)
     }
 
+    // 取出并清空一个目标的迟到回复计数
+    // 没有取出的计数一直保留, 直到该目标出现在之后弹出的 Bucket 中
+    pub fn take_late(&self, target: &str) -> u32 {
+        self.late.lock().unwrap().remove(target).unwrap_or(0)
+    }
+
+    // 记录一个没有通过会话检查的回复
+    pub fn add_rejected(&self, target: String, verdict: Verdict) {
+        let mut rejected = self.rejected.lock().unwrap();
+        let entry = rejected.entry(target).or_default();
+        match verdict {
+            Verdict::Foreign => entry.foreign += 1,
+            Verdict::Spoofed => entry.spoofed += 1,
+            Verdict::Valid => {}
+        }
+    }
+
+    // 取出并清空没有通过会话检查的回复计数
+    pub fn take_rejected(&self) -> HashMap<String, Rejected> {
+        std::mem::take(&mut *self.rejected.lock().unwrap())
+    }
+
     // 用最小的 key 获取 bucket, bucket 还在堆和 map 中
     pub fn last(&self) -> Option<Bucket> {
         let buckets = self.buckets.lock().unwrap();
@@ -76,10 +164,10 @@ impl Buckets {
     }
 }
 
-// Bucket 用于存储所有目标一秒钟内的 ping 结果.
+// Bucket 用于存储所有目标一个聚合窗口内的 ping 结果.
 #[derive(Default)]
 pub struct Bucket {
-    // key 是时间戳，以秒为单位
+    // key 是时间戳，以聚合窗口为单位
     pub key: u128,
     // 值是 Bucket 中所有目标 ping 结果.
     pub value: RwLock<HashMap<String, Result>>,
@@ -133,24 +221,42 @@ impl Bucket {
     }
 
     // 往 Bucket 添加一个 ping 回复
+    // 同一个 target-seq 已经收到过回复时, 只增加重复计数, 不覆盖第一次的结果
     pub fn add_reply(&self, mut reply: Result) {
         let mut map = self.value.write().unwrap();
 
         let key = format!("{}-{}", reply.target, reply.seq);
-        if let Some(req) = map.get(&key) {
+        if let Some(req) = map.get_mut(&key) {
+            if req.received {
+                req.dup_count += 1;
+                return;
+            }
             reply.txts = req.txts;
+            reply.kernel_txts = req.kernel_txts;
+            reply.send_error = req.send_error;
             reply.calc_latency();
+            reply.calc_one_way();
+        }
+        map.insert(key, reply);
+    }
+
+    // 把一次探测标记为发送失败
+    pub fn mark_send_failed(&self, target: String, seq: u64) {
+        let mut map = self.value.write().unwrap();
+
+        let key = format!("{}-{}", target, seq);
+        if let Some(result) = map.get_mut(&key) {
+            result.send_failed = true;
         }
-        map.insert(key, reply.clone());
     }
 
     // 发送后, 更新 ping 结果的 txts（软件/硬件时间戳）
-    pub fn update_txts(&self, target: String, seq: u16, txts: u128) {
+    pub fn update_txts(&self, target: String, seq: u64, txts: Timestamp) {
         let mut map = self.value.write().unwrap();
 
         let key = format!("{}-{}", target, seq);
         if let Some(result) = map.get_mut(&key) {
-            result.txts = txts;
+            result.kernel_txts = Some(txts);
         }
     }
 
@@ -161,30 +267,75 @@ impl Bucket {
     }
 }
 
+// TimestampSource 表示时间戳的来源
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampSource {
+    // 用户态读取的 CLOCK_MONOTONIC 时钟
+    #[default]
+    User,
+    // 内核软件时间戳 (SO_TIMESTAMP / SO_TIMESTAMPING)
+    Software,
+    // 网卡硬件时间戳
+    Hardware,
+}
+
+// Timestamp 是内核提供的时间戳, 以纳秒为单位
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamp {
+    pub nanos: u128,
+    pub source: TimestampSource,
+}
+
 // Result 用于存储一个目标的一次 ping 结果.
 // 结果由目标和序列号标识.
 #[derive(Default, Clone, Debug)]
 pub struct Result {
-    // 发送 ping 请求的时间戳.
+    // 发送 ping 请求的时间戳, 来自 CLOCK_MONOTONIC.
     pub txts: u128,
-    // 收到 ping 回复时的时间戳.
+    // 收到 ping 回复时的时间戳, 来自 CLOCK_MONOTONIC.
     pub rxts: u128,
-    // ping 请求的序列号.
-    pub seq: u16,
-    // ping 结果的目标.
+    // 内核提供的发送时间戳.
+    pub kernel_txts: Option<Timestamp>,
+    // 内核提供的接收时间戳.
+    pub kernel_rxts: Option<Timestamp>,
+    // 计算延迟所用的发送时间戳的来源.
+    pub tx_source: TimestampSource,
+    // 计算延迟所用的接收时间戳的来源.
+    pub rx_source: TimestampSource,
+    // ping 请求的扩展序列号, 不会像 ICMP 报文中的 u16 序列号一样回绕.
+    pub seq: u64,
+    // ping 结果的目标, 即请求的原始目标地址.
     pub target: String,
+    // 发出回复的地址, 可能与 target 不同.
+    pub responder: String,
     // ping 结果的延迟.
     pub latency: u128,
     // 如果收到了 ping 回复，则 received 为 true.
     pub received: bool,
     // 如果收到 ping 回复但数据已损坏，则 bitflip 为真.
     pub bitflip: bool,
+    // 同一个请求收到的重复回复数.
+    pub dup_count: u32,
+    // 如果回复的序列号小于该目标已收到的最大序列号，则 out_of_order 为真.
+    pub out_of_order: bool,
+    // 实际发送时间与计划发送时间的偏差, 纳秒.
+    pub send_error: u128,
+    // 如果 ping 请求没能发送出去，则 send_failed 为 true.
+    pub send_failed: bool,
+    // ICMP Timestamp 回复中对端的接收和发送时间戳, UTC 零点以来的毫秒数.
+    pub remote_timestamps: Option<(u32, u32)>,
+    // 收到 ICMP Timestamp 回复时的墙上时钟, UTC 零点以来的毫秒数.
+    pub rx_wall_ms: f64,
+    // ICMP Timestamp 或 STAMP 探测估计的单向延迟和对端时钟偏差.
+    pub one_way: Option<OneWay>,
+    // STAMP 反射端从收到请求到发出回复的时间, 纳秒, 计算延迟时扣除.
+    pub turnaround: u128,
 }
 
 impl Result {
     // 创建一个 ping 结果
     #[allow(unused)]
-    fn new_result(txts: u128, target: &str, seq: u16) -> Result {
+    fn new_result(txts: u128, target: &str, seq: u64) -> Result {
         Result {
             txts,
             target: target.to_string(),
@@ -193,24 +344,583 @@ impl Result {
         }
     }
     // 计算 ping 结果的延迟.
+    // 只有发送和接收的内核时间戳来自同一个时钟时才使用它们, 否则使用用户态的单调时钟时间戳
+    // 内核时间戳是 CLOCK_REALTIME, 时钟被调整时可能相差很多, 所以还要与单调时钟的 RTT 比较:
+    // 内核 RTT 不会比单调时钟的 RTT 大, 也不会小太多, 否则认为时钟被调整过, 使用单调时钟
     pub fn calc_latency(&mut self) {
-        self.latency = self.rxts - self.txts;
+        let user = self.rxts.saturating_sub(self.txts);
+        if let (Some(tx), Some(rx)) = (self.kernel_txts, self.kernel_rxts) {
+            let kernel = rx.nanos.checked_sub(tx.nanos);
+            let consistent = kernel.is_some_and(|kernel| {
+                kernel <= user + KERNEL_RTT_TOLERANCE && user <= kernel + KERNEL_RTT_MAX_GAP
+            });
+            if tx.source == rx.source && consistent {
+                self.latency = kernel.unwrap().saturating_sub(self.turnaround);
+                self.tx_source = tx.source;
+                self.rx_source = rx.source;
+                return;
+            }
+        }
+
+        self.latency = user.saturating_sub(self.turnaround);
+        self.tx_source = TimestampSource::User;
+        self.rx_source = TimestampSource::User;
+    }
+
+    // 根据 ICMP Timestamp 回复估计单向延迟, 发送时的墙上时钟由接收时的墙上时钟减去 RTT 得到
+    pub fn calc_one_way(&mut self) {
+        if let Some((receive, transmit)) = self.remote_timestamps {
+            let sent = self.rx_wall_ms - self.rxts.saturating_sub(self.txts) as f64 / 1_000_000.0;
+            self.one_way = OneWay::estimate(sent, receive, transmit, self.rx_wall_ms);
+        }
     }
 }
 
+// 延迟直方图的桶边界, 毫秒
+pub const LATENCY_BOUNDS_MS: [f64; 14] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0,
+];
+
 // TargetResult 用于存储一个目标的 ping 统计结果
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct TargetResult {
     // ping 结果的目标.
     pub target: String,
     // ping 结果的丢失率
     pub loss_rate: f64,
-    // ping 结果的平均延迟
+    // ping 结果的平均延迟, 统计过程中是延迟之和
     pub latency: u128,
+    // ping 结果的最大延迟
+    pub max_latency: u128,
+    // 延迟的平方和, 纳秒的平方, 用于估计平均延迟的置信区间
+    pub latency_squares: u128,
+    // 丢失率的 95% Wilson 置信区间
+    pub loss_ci: (f64, f64),
+    // 平均延迟的 95% 置信区间, 纳秒, 回复少于 2 个时为 (0, 0)
+    pub latency_ci: (u128, u128),
+    // 延迟直方图, 第 i 个桶是延迟在 (LATENCY_BOUNDS_MS[i-1], LATENCY_BOUNDS_MS[i]] 毫秒内的回复数
+    // 最后一个桶是大于最大边界的回复数, 没有回复时为空
+    pub latency_buckets: Vec<u32>,
     // ping 结果的损失计数
     pub loss: u32,
     // ping 结果的接收计数
     pub received: u32,
     // ping 结果的 bitflip 计数
     pub bitflip_count: u32,
+    // 重复回复计数
+    pub duplicate: u32,
+    // 乱序到达的回复计数
+    pub out_of_order: u32,
+    // 迟到回复计数: 延迟超过 timeout 的回复, 以及 Bucket 弹出后才到达的回复
+    // 迟到的探测已经按丢包统计, late 表示其中有多少最终收到了回复
+    // Bucket 弹出后才到达的回复计入该目标之后的窗口, 而不是探测所在的窗口
+    pub late: u32,
+    // 发送失败的探测计数, 不计入 loss
+    pub send_failed: u32,
+    // 本机 socket 接收队列溢出丢弃的回复数, 按丢包数的比例分给各个目标, 不计入 loss
+    // 这些探测的回复已经到达本机, 计入发送数但不计入丢失率
+    pub local_drops: u32,
+    // 会话 cookie 不匹配的回复计数, 例如同时运行的其它 mping 进程的回复
+    pub foreign: u32,
+    // 会话 cookie 匹配但 MAC 校验失败的回复计数
+    pub spoofed: u32,
+    // 来自目标以外地址的回复计数, 这些回复仍然计入该目标的统计
+    pub mismatched: u32,
+    // 目标以外的回复地址
+    pub responders: BTreeSet<String>,
+    // 使用用户态单调时钟计算延迟的回复计数
+    pub user_timestamps: u32,
+    // 使用内核软件时间戳计算延迟的回复计数
+    pub software_timestamps: u32,
+    // 使用网卡硬件时间戳计算延迟的回复计数
+    pub hardware_timestamps: u32,
+    // 该窗口内实际达到的发送速率, 包/秒
+    pub send_rate: f64,
+    // 发送时间偏差的平均值, 统计过程中是偏差之和, 纳秒
+    pub send_error: u128,
+    // 发送时间偏差的最大值, 纳秒
+    pub max_send_error: u128,
+    // ICMP Timestamp 探测估计的去程延迟、回程延迟和对端时钟偏差的平均值, 毫秒, 统计过程中是之和
+    pub forward_delay: f64,
+    pub reverse_delay: f64,
+    pub clock_offset: f64,
+    // 能够估计单向延迟的 ICMP Timestamp 回复计数
+    pub one_way_samples: u32,
+    // 自适应速率控制认为该目标的丢包疑似来自 ICMP 限速
+    pub rate_limited: bool,
+    // 自适应速率控制发现的可持续速率, 包/秒, 未启用时为 0
+    pub sustainable_rate: f64,
+    // 偏离该目标基线的指标, 未启用异常检测时为空
+    pub anomalies: Vec<Anomaly>,
+    // 相对最近若干个窗口统计显著变差的指标, 未启用时为空
+    pub degradations: Vec<Degradation>,
+    // 统计窗口的长度
+    pub window: Duration,
+    // 统计窗口的开始时间, CLOCK_MONOTONIC 纳秒
+    pub window_start: u128,
+    // 统计窗口的开始时间, Unix 时间戳, 毫秒
+    pub timestamp: u64,
+}
+
+impl TargetResult {
+    // 合并另一个窗口的统计结果, 两者的 latency 和 send_error 都必须是之和
+    pub fn merge(&mut self, other: &TargetResult) {
+        self.latency += other.latency;
+        self.max_latency = self.max_latency.max(other.max_latency);
+        self.latency_squares += other.latency_squares;
+        if self.latency_buckets.len() < other.latency_buckets.len() {
+            self.latency_buckets.resize(other.latency_buckets.len(), 0);
+        }
+        for (count, other) in self.latency_buckets.iter_mut().zip(&other.latency_buckets) {
+            *count += other;
+        }
+        self.loss += other.loss;
+        self.received += other.received;
+        self.bitflip_count += other.bitflip_count;
+        self.duplicate += other.duplicate;
+        self.out_of_order += other.out_of_order;
+        self.late += other.late;
+        self.send_failed += other.send_failed;
+        self.local_drops += other.local_drops;
+        self.foreign += other.foreign;
+        self.spoofed += other.spoofed;
+        self.mismatched += other.mismatched;
+        self.responders.extend(other.responders.iter().cloned());
+        self.user_timestamps += other.user_timestamps;
+        self.software_timestamps += other.software_timestamps;
+        self.hardware_timestamps += other.hardware_timestamps;
+        self.send_error += other.send_error;
+        self.max_send_error = self.max_send_error.max(other.max_send_error);
+        self.forward_delay += other.forward_delay;
+        self.reverse_delay += other.reverse_delay;
+        self.clock_offset += other.clock_offset;
+        self.one_way_samples += other.one_way_samples;
+        self.rate_limited |= other.rate_limited;
+        if other.sustainable_rate > 0.0 {
+            self.sustainable_rate = other.sustainable_rate;
+        }
+        self.anomalies.extend(other.anomalies.iter().cloned());
+        self.degradations.extend(other.degradations.iter().cloned());
+    }
+
+    // 发送成功的探测数: 收到回复、丢失和回复在本机被丢弃的探测, 不含发送失败的探测
+    pub fn sent(&self) -> u32 {
+        self.received + self.loss + self.local_drops
+    }
+
+    // 把一个回复的延迟 (纳秒) 记入直方图和平方和
+    pub fn record_latency(&mut self, latency: u128) {
+        self.latency_squares += latency * latency;
+        if self.latency_buckets.is_empty() {
+            self.latency_buckets = vec![0; LATENCY_BOUNDS_MS.len() + 1];
+        }
+        let ms = latency as f64 / 1_000_000.0;
+        let i = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        self.latency_buckets[i] += 1;
+    }
+
+    // 结束统计: 标记窗口, 计算丢失率、置信区间和发送速率, 并把延迟和发送偏差之和换算为平均值
+    pub fn finish(&mut self, window: Duration, window_start: u128) {
+        self.window = window;
+        self.window_start = window_start;
+        // 根据当前的墙上时钟和单调时钟的差值换算窗口开始的 Unix 时间
+        let elapsed = monotonic_nanos().saturating_sub(window_start);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        self.timestamp = (now.saturating_sub(elapsed) / 1_000_000) as u64;
+
+        let total = self.sent();
+        self.loss_rate = if total == 0 {
+            0.0
+        } else {
+            (self.loss as f64) / (total as f64)
+        };
+        self.loss_ci = significance::wilson_interval(self.loss, total);
+        self.latency_ci =
+            significance::mean_interval(self.latency, self.latency_squares, self.received);
+        if self.received > 0 {
+            self.latency /= self.received as u128;
+        }
+        if total > 0 {
+            self.send_error /= total as u128;
+        }
+        if self.one_way_samples > 0 {
+            let samples = self.one_way_samples as f64;
+            self.forward_delay /= samples;
+            self.reverse_delay /= samples;
+            self.clock_offset /= samples;
+        }
+        self.send_rate = total as f64 / window.as_secs_f64();
+    }
+}
+
+// Rollup 把连续的基础窗口的统计结果合并为一个更大的窗口, 如 1m、5m
+pub struct Rollup {
+    // 聚合窗口的长度
+    pub window: Duration,
+    // 当前聚合窗口的开始时间
+    start: u128,
+    // 当前聚合窗口内每个目标的统计结果, latency 为延迟之和
+    results: BTreeMap<String, TargetResult>,
+}
+
+impl Rollup {
+    // 创建一个 Rollup
+    pub fn new_rollup(window: Duration) -> Rollup {
+        Rollup {
+            window,
+            start: 0,
+            results: BTreeMap::new(),
+        }
+    }
+
+    // 加入一个基础窗口的统计结果
+    // 如果该基础窗口属于新的聚合窗口, 返回上一个聚合窗口的最终结果
+    pub fn add(
+        &mut self,
+        start: u128,
+        results: &BTreeMap<String, TargetResult>,
+    ) -> Option<Vec<TargetResult>> {
+        let window = self.window.as_nanos();
+        let start = start / window * window;
+
+        let mut done = None;
+        if start != self.start && !self.results.is_empty() {
+            done = Some(self.take());
+        }
+        self.start = start;
+
+        for (target, tr) in results {
+            self.results
+                .entry(target.clone())
+                .or_insert_with(|| TargetResult {
+                    target: target.clone(),
+                    ..Default::default()
+                })
+                .merge(tr);
+        }
+
+        done
+    }
+
+    // 取出尚未结束的聚合窗口的结果, 用于停止时输出最后一个窗口, 没有数据时返回 None
+    pub fn flush(&mut self) -> Option<Vec<TargetResult>> {
+        if self.results.is_empty() {
+            None
+        } else {
+            Some(self.take())
+        }
+    }
+
+    // 取出当前聚合窗口的最终结果
+    fn take(&mut self) -> Vec<TargetResult> {
+        std::mem::take(&mut self.results)
+            .into_values()
+            .map(|mut tr| {
+                tr.finish(self.window, self.start);
+                tr
+            })
+            .collect()
+    }
+}
+
+// HostResult 是一个目标在整个运行期间的统计, 用于 sweep 和 check 输出最终结果
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct HostResult {
+    pub sent: u32,
+    pub received: u32,
+    pub loss: u32,
+    // 所有回复的延迟之和
+    pub latency_sum: u128,
+}
+
+impl HostResult {
+    // 加入一个窗口的结果, tr 是已经 finish 的统计
+    pub fn add(&mut self, tr: &TargetResult) {
+        self.sent += tr.sent();
+        self.received += tr.received;
+        self.loss += tr.loss;
+        self.latency_sum += tr.latency * tr.received as u128;
+    }
+
+    // 丢失率, 百分比
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.loss as f64 * 100.0 / self.sent as f64
+        }
+    }
+
+    // 平均延迟, 毫秒
+    pub fn rtt_ms(&self) -> f64 {
+        if self.received == 0 {
+            0.0
+        } else {
+            (self.latency_sum / self.received as u128) as f64 / 1_000_000.0
+        }
+    }
+}
+
+// 在单独的线程中汇总每个目标长度为 window 的窗口的结果, 忽略 roll-up 窗口
+// 通道关闭 (ping 的统计线程退出) 后返回
+pub fn collect_hosts(
+    rx: Receiver<TargetResult>,
+    window: Duration,
+) -> JoinHandle<HashMap<String, HostResult>> {
+    thread::spawn(move || {
+        let mut hosts: HashMap<String, HostResult> = HashMap::new();
+        for tr in rx {
+            if tr.window == window {
+                hosts.entry(tr.target.clone()).or_default().add(&tr);
+            }
+        }
+        hosts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(target: &str, seq: u64, rxts: u128) -> Result {
+        Result {
+            rxts,
+            target: target.to_string(),
+            seq,
+            received: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn duplicate_reply_keeps_first() {
+        let buckets = Buckets::new_buckets();
+        buckets.add(1, Result::new_result(100, "a", 1));
+        buckets.add_reply(1, reply("a", 1, 300));
+        buckets.add_reply(1, reply("a", 1, 900));
+
+        let values = buckets.pop().unwrap().values();
+        assert_eq!(values.len(), 1);
+        assert!(values[0].received);
+        assert_eq!(values[0].latency, 200);
+        assert_eq!(values[0].dup_count, 1);
+    }
+
+    #[test]
+    fn pop_in_key_order() {
+        let buckets = Buckets::new_buckets();
+        buckets.add(3, Result::new_result(0, "a", 3));
+        buckets.add(1, Result::new_result(0, "a", 1));
+        buckets.add(2, Result::new_result(0, "a", 2));
+
+        let keys: Vec<u128> = std::iter::from_fn(|| buckets.pop().map(|b| b.key)).collect();
+        assert_eq!(keys, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn late_reply_kept_until_target_appears() {
+        let buckets = Buckets::new_buckets();
+        buckets.add(1, Result::new_result(0, "a", 1));
+        buckets.add(1, Result::new_result(0, "b", 1));
+        buckets.pop();
+
+        // 窗口 1 已经弹出, 回复只记为迟到
+        buckets.add_reply(1, reply("a", 1, 10));
+        buckets.add_reply(1, reply("b", 1, 10));
+        buckets.add_reply(1, reply("b", 1, 10));
+        assert!(buckets.last().is_none());
+
+        // 只取出 a 的迟到计数, b 的计数留到之后的窗口
+        assert_eq!(buckets.take_late("a"), 1);
+        assert_eq!(buckets.take_late("a"), 0);
+        assert_eq!(buckets.take_late("b"), 2);
+        assert_eq!(buckets.take_late("b"), 0);
+    }
+
+    fn kernel(nanos: u128) -> Option<Timestamp> {
+        Some(Timestamp {
+            nanos,
+            source: TimestampSource::Software,
+        })
+    }
+
+    #[test]
+    fn kernel_latency_used_when_consistent() {
+        let mut r = Result {
+            txts: 1_000_000,
+            rxts: 3_000_000,
+            kernel_txts: kernel(50_000_000_000),
+            kernel_rxts: kernel(50_001_900_000),
+            ..Default::default()
+        };
+        r.calc_latency();
+        assert_eq!(r.latency, 1_900_000);
+        assert_eq!(r.rx_source, TimestampSource::Software);
+    }
+
+    #[test]
+    fn kernel_latency_rejected_after_clock_step() {
+        // 墙上时钟在发送和接收之间被向前调整了 1s
+        let mut r = Result {
+            txts: 1_000_000,
+            rxts: 3_000_000,
+            kernel_txts: kernel(50_000_000_000),
+            kernel_rxts: kernel(51_001_900_000),
+            ..Default::default()
+        };
+        r.calc_latency();
+        assert_eq!(r.latency, 2_000_000);
+        assert_eq!(r.rx_source, TimestampSource::User);
+
+        // 被向后调整, 内核 RTT 小于 0 或远小于单调时钟的 RTT
+        r.kernel_rxts = kernel(49_999_000_000);
+        r.calc_latency();
+        assert_eq!(r.latency, 2_000_000);
+        r.rxts = 200_000_000;
+        r.kernel_rxts = kernel(50_001_000_000);
+        r.calc_latency();
+        assert_eq!(r.latency, 199_000_000);
+        assert_eq!(r.tx_source, TimestampSource::User);
+    }
+
+    #[test]
+    fn kernel_latency_needs_same_source() {
+        let mut r = Result {
+            txts: 1_000_000,
+            rxts: 3_000_000,
+            kernel_txts: kernel(50_000_000_000),
+            kernel_rxts: Some(Timestamp {
+                nanos: 50_001_900_000,
+                source: TimestampSource::Hardware,
+            }),
+            ..Default::default()
+        };
+        r.calc_latency();
+        assert_eq!(r.latency, 2_000_000);
+    }
+
+    fn window_result(target: &str, received: u32, loss: u32, latency: u128) -> TargetResult {
+        let mut tr = TargetResult {
+            target: target.to_string(),
+            loss,
+            ..Default::default()
+        };
+        for _ in 0..received {
+            tr.latency += latency;
+            tr.max_latency = tr.max_latency.max(latency);
+            tr.record_latency(latency);
+            tr.received += 1;
+        }
+        tr
+    }
+
+    #[test]
+    fn rollup_merges_base_windows() {
+        let second = Duration::from_secs(1).as_nanos();
+        let mut rollup = Rollup::new_rollup(Duration::from_secs(3));
+        let mut window = |start: u128, results: Vec<TargetResult>| {
+            let results = results
+                .into_iter()
+                .map(|tr| (tr.target.clone(), tr))
+                .collect();
+            rollup.add(start * second, &results)
+        };
+
+        assert!(window(3, vec![window_result("a", 2, 0, 1_000_000)]).is_none());
+        assert!(window(
+            4,
+            vec![
+                window_result("a", 1, 1, 4_000_000),
+                window_result("b", 0, 2, 0)
+            ]
+        )
+        .is_none());
+        assert!(window(5, vec![window_result("a", 1, 0, 1_000_000)]).is_none());
+
+        // 第一个属于下一个聚合窗口的基础窗口到达时, 输出上一个聚合窗口
+        let done = window(6, vec![window_result("a", 1, 0, 1_000_000)]).unwrap();
+        assert_eq!(done.len(), 2);
+        let a = &done[0];
+        assert_eq!(a.target, "a");
+        assert_eq!(a.window, Duration::from_secs(3));
+        assert_eq!(a.window_start, 3 * second);
+        assert_eq!((a.received, a.loss), (4, 1));
+        assert_eq!(a.loss_rate, 0.2);
+        assert_eq!(a.latency, 7_000_000 / 4);
+        assert_eq!(a.max_latency, 4_000_000);
+        assert_eq!(a.latency_buckets.iter().sum::<u32>(), 4);
+        assert_eq!(a.send_rate, 5.0 / 3.0);
+        let b = &done[1];
+        assert_eq!((b.received, b.loss, b.loss_rate), (0, 2, 1.0));
+        assert!(b.latency_buckets.is_empty());
+    }
+
+    #[test]
+    fn finish_averages_sums() {
+        let mut tr = window_result("a", 3, 1, 2_000_000);
+        tr.send_error = 4_000;
+        tr.finish(Duration::from_millis(500), 0);
+        assert_eq!(tr.latency, 2_000_000);
+        assert_eq!(tr.loss_rate, 0.25);
+        assert_eq!(tr.send_error, 1_000);
+        assert_eq!(tr.send_rate, 8.0);
+        assert_eq!(tr.latency_buckets[4], 3);
+    }
+
+    #[test]
+    fn send_failed_and_txts_update() {
+        let buckets = Buckets::new_buckets();
+        buckets.add(1, Result::new_result(100, "a", 1));
+        buckets.add(1, Result::new_result(100, "a", 2));
+        buckets.mark_send_failed(1, "a".to_string(), 1);
+        buckets.update_txts(
+            1,
+            "a".to_string(),
+            2,
+            Timestamp {
+                nanos: 150,
+                source: TimestampSource::Software,
+            },
+        );
+
+        let bucket = buckets.pop().unwrap();
+        let values = bucket.value.read().unwrap();
+        assert!(values["a-1"].send_failed);
+        assert!(!values["a-2"].send_failed);
+        assert_eq!(values["a-2"].kernel_txts.unwrap().nanos, 150);
+    }
+
+    #[test]
+    fn collect_hosts_ignores_rollups() {
+        let window = Duration::from_secs(1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = collect_hosts(rx, window);
+        for (received, loss, window) in [(3, 1, window), (1, 3, window), (9, 9, window * 60)] {
+            tx.send(TargetResult {
+                target: "a".to_string(),
+                window,
+                received,
+                loss,
+                local_drops: 1,
+                latency: 2_000_000,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let hosts = handle.join().unwrap();
+        let host = &hosts["a"];
+        assert_eq!((host.sent, host.received, host.loss), (10, 4, 4));
+        assert_eq!(host.loss(), 40.0);
+        assert_eq!(host.rtt_ms(), 2.0);
+        assert_eq!(HostResult::default().loss(), 0.0);
+        assert_eq!(HostResult::default().rtt_ms(), 0.0);
+    }
 }
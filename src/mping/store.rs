@@ -0,0 +1,718 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{Local, TimeZone};
+use log::{error, info, warn};
+
+use crate::mping::stat::TargetResult;
+
+// 结果存储: 每天 (UTC) 一个只追加写入的段文件 results-<天数>.bin
+// 文件头: MAGIC(4) + 版本(1) + 标志(1)
+// 记录: 记录长度(u16) + 记录内容, 均为小端序
+// 只保存基础窗口的结果, roll-up 窗口由基础窗口合并得到, 保存它们会被查询和压缩重复计数
+const MAGIC: &[u8; 4] = b"MPST";
+const VERSION: u8 = 2;
+// 版本 1 的记录没有发送失败、本机丢弃、拒绝的回复和时间戳来源的计数, 仍然可以读取
+const VERSION_1: u8 = 1;
+const HEADER_LEN: usize = 6;
+// 段文件已经被压缩
+const FLAG_COMPACTED: u8 = 1;
+const DAY_MS: u64 = 86_400_000;
+// 已经结束的段被压缩为 1 分钟的窗口
+const COMPACT_WINDOW: Duration = Duration::from_secs(60);
+
+// 记录中 target 之前的固定长度部分
+const FIXED_LEN_V1: usize = 8 + 4 + 4 + 4 + 8 + 8 + 4 * 4;
+const FIXED_LEN: usize = FIXED_LEN_V1 + 4 * 8;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// 把一个窗口的结果编码为一条记录
+fn encode(tr: &TargetResult) -> Vec<u8> {
+    let target = tr.target.as_bytes();
+    let len = FIXED_LEN + target.len();
+
+    let mut buf = Vec::with_capacity(2 + len);
+    buf.extend_from_slice(&(len as u16).to_le_bytes());
+    buf.extend_from_slice(&tr.timestamp.to_le_bytes());
+    buf.extend_from_slice(&(tr.window.as_millis() as u32).to_le_bytes());
+    buf.extend_from_slice(&tr.received.to_le_bytes());
+    buf.extend_from_slice(&tr.loss.to_le_bytes());
+    buf.extend_from_slice(&(tr.latency as u64).to_le_bytes());
+    buf.extend_from_slice(&(tr.max_latency as u64).to_le_bytes());
+    buf.extend_from_slice(&tr.bitflip_count.to_le_bytes());
+    buf.extend_from_slice(&tr.duplicate.to_le_bytes());
+    buf.extend_from_slice(&tr.out_of_order.to_le_bytes());
+    buf.extend_from_slice(&tr.late.to_le_bytes());
+    for count in [
+        tr.send_failed,
+        tr.local_drops,
+        tr.foreign,
+        tr.spoofed,
+        tr.mismatched,
+        tr.user_timestamps,
+        tr.software_timestamps,
+        tr.hardware_timestamps,
+    ] {
+        buf.extend_from_slice(&count.to_le_bytes());
+    }
+    buf.extend_from_slice(target);
+    buf
+}
+
+// 解码一条记录的内容 (不含长度)
+fn decode(buf: &[u8], version: u8) -> Option<TargetResult> {
+    let fixed_len = if version == VERSION_1 {
+        FIXED_LEN_V1
+    } else {
+        FIXED_LEN
+    };
+    if buf.len() < fixed_len {
+        return None;
+    }
+    let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+
+    let mut tr = TargetResult {
+        timestamp: u64_at(0),
+        window: Duration::from_millis(u32_at(8) as u64),
+        received: u32_at(12),
+        loss: u32_at(16),
+        latency: u64_at(20) as u128,
+        max_latency: u64_at(28) as u128,
+        bitflip_count: u32_at(36),
+        duplicate: u32_at(40),
+        out_of_order: u32_at(44),
+        late: u32_at(48),
+        target: String::from_utf8_lossy(&buf[fixed_len..]).into_owned(),
+        ..Default::default()
+    };
+    if version != VERSION_1 {
+        tr.send_failed = u32_at(52);
+        tr.local_drops = u32_at(56);
+        tr.foreign = u32_at(60);
+        tr.spoofed = u32_at(64);
+        tr.mismatched = u32_at(68);
+        tr.user_timestamps = u32_at(72);
+        tr.software_timestamps = u32_at(76);
+        tr.hardware_timestamps = u32_at(80);
+    }
+    let total = tr.sent();
+    if total > 0 {
+        tr.loss_rate = tr.loss as f64 / total as f64;
+    }
+
+    Some(tr)
+}
+
+// 读取一个段文件, 返回文件的版本、是否已压缩以及所有记录
+// 文件末尾不完整的记录 (例如写入时进程退出) 会被忽略
+pub fn read_segment(path: &Path) -> anyhow::Result<(u8, bool, Vec<TargetResult>)> {
+    let (version, compacted, results, _) = read_segment_end(path)?;
+    Ok((version, compacted, results))
+}
+
+// 同 read_segment, 另外返回最后一条完整记录结束的位置
+fn read_segment_end(path: &Path) -> anyhow::Result<(u8, bool, Vec<TargetResult>, u64)> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < HEADER_LEN || &buf[..4] != MAGIC || !(VERSION_1..=VERSION).contains(&buf[4]) {
+        return Err(anyhow::anyhow!("invalid segment file: {}", path.display()));
+    }
+    let version = buf[4];
+    let compacted = buf[5] & FLAG_COMPACTED != 0;
+
+    let mut results = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos + 2 <= buf.len() {
+        let len = u16::from_le_bytes([buf[pos], buf[pos + 1]]) as usize;
+        if pos + 2 + len > buf.len() {
+            break;
+        }
+        if let Some(tr) = decode(&buf[pos + 2..pos + 2 + len], version) {
+            results.push(tr);
+        }
+        pos += 2 + len;
+    }
+
+    Ok((version, compacted, results, pos as u64))
+}
+
+// 用当前版本重写一个段文件
+// 先写入临时文件再重命名, 保证重写过程中进程退出不会损坏数据
+fn write_segment<'a>(
+    path: &Path,
+    compacted: bool,
+    results: impl Iterator<Item = &'a TargetResult>,
+) -> anyhow::Result<()> {
+    let flags = if compacted { FLAG_COMPACTED } else { 0 };
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION, flags])?;
+    for tr in results {
+        writer.write_all(&encode(tr))?;
+    }
+    writer.flush()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// 把多个窗口的结果合并为一个, 延迟按回复数加权
+fn merge_results(acc: &mut TargetResult, tr: &TargetResult) {
+    let mut sum = tr.clone();
+    sum.latency *= tr.received as u128;
+    acc.merge(&sum);
+}
+
+// 合并结束后把延迟之和换算为平均延迟, 并计算丢失率
+fn finish_merged(tr: &mut TargetResult) {
+    let total = tr.sent();
+    tr.loss_rate = if total == 0 {
+        0.0
+    } else {
+        tr.loss as f64 / total as f64
+    };
+    if tr.received > 0 {
+        tr.latency /= tr.received as u128;
+    }
+}
+
+// Store 是按天分段的结果存储
+pub struct Store {
+    dir: PathBuf,
+    // 超过保留期的记录会被删除
+    retention: Duration,
+    // 只保存该长度窗口的结果
+    window: Duration,
+    // 当前写入的段
+    day: u64,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Store {
+    // 打开存储目录, 不存在时创建, 并执行一次保留期清理和压缩
+    // window 是基础窗口的长度, 其它窗口的结果不会被保存
+    pub fn open_store(dir: &Path, retention: Duration, window: Duration) -> anyhow::Result<Store> {
+        fs::create_dir_all(dir)?;
+        let store = Store {
+            dir: dir.to_path_buf(),
+            retention,
+            window,
+            day: 0,
+            writer: None,
+        };
+        store.maintain()?;
+        Ok(store)
+    }
+
+    fn segment_path(&self, day: u64) -> PathBuf {
+        self.dir.join(format!("results-{}.bin", day))
+    }
+
+    // 按天数排序的所有段文件
+    fn segments(&self) -> anyhow::Result<Vec<(u64, PathBuf)>> {
+        list_segments(&self.dir)
+    }
+
+    // 追加一个窗口的结果, 跨天时切换到新的段并整理旧的段
+    // 不是基础窗口的结果被忽略
+    pub fn append(&mut self, tr: &TargetResult) -> anyhow::Result<()> {
+        if tr.window != self.window {
+            return Ok(());
+        }
+
+        let day = tr.timestamp / DAY_MS;
+        if self.writer.is_none() || day != self.day {
+            if let Some(mut writer) = self.writer.take() {
+                writer.flush()?;
+            }
+
+            // 旧版本的段先升级到当前版本, 再追加新的记录
+            let path = self.segment_path(day);
+            if path.exists() {
+                let len = fs::metadata(&path)?.len();
+                let end = if len < HEADER_LEN as u64 {
+                    // 文件头没有写完整, 重新写入
+                    0
+                } else {
+                    let (version, compacted, results, end) = read_segment_end(&path)?;
+                    if version != VERSION {
+                        write_segment(&path, compacted, results.iter())?;
+                        fs::metadata(&path)?.len()
+                    } else {
+                        end
+                    }
+                };
+                // 截掉末尾不完整的记录, 否则之后追加的记录都会错位而无法读取
+                if end < fs::metadata(&path)?.len() {
+                    warn!(
+                        "store: truncate incomplete record at the end of {}",
+                        path.display()
+                    );
+                    OpenOptions::new().write(true).open(&path)?.set_len(end)?;
+                }
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            if file.metadata()?.len() == 0 {
+                file.write_all(MAGIC)?;
+                file.write_all(&[VERSION, 0])?;
+            }
+            self.day = day;
+            self.writer = Some(BufWriter::new(file));
+            self.maintain()?;
+        }
+
+        self.writer.as_mut().unwrap().write_all(&encode(tr))?;
+        Ok(())
+    }
+
+    // 把缓冲的记录写入文件
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // 删除超过保留期的段, 并压缩已经结束且未压缩的段
+    pub fn maintain(&self) -> anyhow::Result<()> {
+        let now = now_ms();
+        let oldest = now.saturating_sub(self.retention.as_millis() as u64);
+        let today = now / DAY_MS;
+
+        for (day, path) in self.segments()? {
+            if (day + 1) * DAY_MS <= oldest {
+                info!("store: remove expired segment {}", path.display());
+                fs::remove_file(&path)?;
+            } else if day < today && day != self.day {
+                self.compact(&path, oldest)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // 把段内的记录合并为 1 分钟的窗口, 同时丢弃超过保留期的记录
+    fn compact(&self, path: &Path, oldest: u64) -> anyhow::Result<()> {
+        let (_, compacted, results) = read_segment(path)?;
+        if compacted {
+            return Ok(());
+        }
+
+        let window = COMPACT_WINDOW.as_millis() as u64;
+        let mut merged: BTreeMap<(u64, String), TargetResult> = BTreeMap::new();
+        for tr in results.iter().filter(|tr| tr.timestamp >= oldest) {
+            let start = tr.timestamp / window * window;
+            let acc = merged
+                .entry((start, tr.target.clone()))
+                .or_insert_with(|| TargetResult {
+                    target: tr.target.clone(),
+                    timestamp: start,
+                    window: COMPACT_WINDOW,
+                    ..Default::default()
+                });
+            merge_results(acc, tr);
+        }
+
+        for tr in merged.values_mut() {
+            finish_merged(tr);
+        }
+        write_segment(path, true, merged.values())?;
+
+        info!(
+            "store: compacted {} ({} -> {} records)",
+            path.display(),
+            results.len(),
+            merged.len()
+        );
+        Ok(())
+    }
+}
+
+fn list_segments(dir: &Path) -> anyhow::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let day = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("results-"))
+            .and_then(|name| name.strip_suffix(".bin"))
+            .and_then(|day| day.parse::<u64>().ok());
+        if let Some(day) = day {
+            segments.push((day, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// 从通道接收每个窗口的结果并写入存储, 直到通道关闭
+pub fn run(rx: Receiver<TargetResult>, mut store: Store) {
+    while let Ok(tr) = rx.recv() {
+        // 一次写入通道中积压的所有结果后再刷新, 减少系统调用
+        for tr in std::iter::once(tr).chain(rx.try_iter()) {
+            if let Err(e) = store.append(&tr) {
+                error!("store: failed to append result: {}", e);
+            }
+        }
+        if let Err(e) = store.flush() {
+            error!("store: failed to flush: {}", e);
+        }
+    }
+}
+
+/// 查询选项
+/// - `from`/`to` 是查询的时间范围, Unix 时间戳, 毫秒
+/// - `target` 只查询包含该字符串的目标
+/// - `worst` 只输出丢失率最高的 N 个目标
+/// - `raw` 输出每个窗口的记录, 而不是汇总结果
+#[derive(Default, Clone, Debug)]
+pub struct Query {
+    pub dir: PathBuf,
+    pub from: u64,
+    pub to: u64,
+    pub target: Option<String>,
+    pub worst: Option<usize>,
+    pub raw: bool,
+}
+
+fn format_time(ms: u64) -> String {
+    match Local.timestamp_millis_opt(ms as i64).single() {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => ms.to_string(),
+    }
+}
+
+fn format_ms(nanos: u128) -> String {
+    format!("{:.2}", nanos as f64 / 1_000_000.0)
+}
+
+// 查询存储的结果并打印到标准输出
+pub fn query(q: &Query) -> anyhow::Result<()> {
+    let mut results = Vec::new();
+    for (day, path) in list_segments(&q.dir)? {
+        // 跳过与查询范围不相交的段
+        if (day + 1) * DAY_MS <= q.from || day * DAY_MS > q.to {
+            continue;
+        }
+        let (_, _, records) = read_segment(&path)?;
+        results.extend(records.into_iter().filter(|tr| {
+            tr.timestamp >= q.from
+                && tr.timestamp <= q.to
+                && q.target.as_ref().is_none_or(|t| tr.target.contains(t))
+        }));
+    }
+    results.sort_by_key(|tr| tr.timestamp);
+
+    if results.is_empty() {
+        println!(
+            "no results between {} and {}",
+            format_time(q.from),
+            format_time(q.to)
+        );
+        return Ok(());
+    }
+
+    let first = results.first().unwrap().timestamp;
+    let last = results.last().unwrap();
+    println!(
+        "time range: {} - {}, {} windows",
+        format_time(first),
+        format_time(last.timestamp + last.window.as_millis() as u64),
+        results.len()
+    );
+
+    if q.raw {
+        println!(
+            "{:<20} {:<18} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10} {:>8} {:>6}",
+            "time",
+            "target",
+            "window",
+            "sent",
+            "recv",
+            "loss%",
+            "avg(ms)",
+            "max(ms)",
+            "bitflip",
+            "failed"
+        );
+        for tr in &results {
+            println!(
+                "{:<20} {:<18} {:>8} {:>8} {:>8} {:>8.2} {:>10} {:>10} {:>8} {:>6}",
+                format_time(tr.timestamp),
+                tr.target,
+                format!("{:?}", tr.window),
+                tr.sent(),
+                tr.received,
+                tr.loss_rate * 100.0,
+                format_ms(tr.latency),
+                format_ms(tr.max_latency),
+                tr.bitflip_count,
+                tr.send_failed
+            );
+        }
+        return Ok(());
+    }
+
+    // 每个目标的汇总结果
+    let mut targets: BTreeMap<String, TargetResult> = BTreeMap::new();
+    for tr in &results {
+        let acc = targets
+            .entry(tr.target.clone())
+            .or_insert_with(|| TargetResult {
+                target: tr.target.clone(),
+                ..Default::default()
+            });
+        merge_results(acc, tr);
+    }
+    let mut targets: Vec<TargetResult> = targets
+        .into_values()
+        .map(|mut tr| {
+            finish_merged(&mut tr);
+            tr
+        })
+        .collect();
+
+    if let Some(n) = q.worst {
+        targets.sort_by(|a, b| {
+            b.loss_rate
+                .total_cmp(&a.loss_rate)
+                .then(b.latency.cmp(&a.latency))
+        });
+        targets.truncate(n);
+        println!("worst {} targets:", n);
+    }
+
+    println!(
+        "{:<18} {:>10} {:>10} {:>8} {:>10} {:>10} {:>8} {:>6} {:>6} {:>6} {:>6}",
+        "target",
+        "sent",
+        "recv",
+        "loss%",
+        "avg(ms)",
+        "max(ms)",
+        "bitflip",
+        "dup",
+        "ooo",
+        "late",
+        "failed"
+    );
+    for tr in &targets {
+        println!(
+            "{:<18} {:>10} {:>10} {:>8.2} {:>10} {:>10} {:>8} {:>6} {:>6} {:>6} {:>6}",
+            tr.target,
+            tr.sent(),
+            tr.received,
+            tr.loss_rate * 100.0,
+            format_ms(tr.latency),
+            format_ms(tr.max_latency),
+            tr.bitflip_count,
+            tr.duplicate,
+            tr.out_of_order,
+            tr.late,
+            tr.send_failed
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mping-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn result(target: &str, window: Duration, timestamp: u64) -> TargetResult {
+        TargetResult {
+            target: target.to_string(),
+            window,
+            timestamp,
+            received: 9,
+            loss: 1,
+            latency: 2_000_000,
+            max_latency: 3_000_000,
+            late: 1,
+            send_failed: 2,
+            local_drops: 3,
+            foreign: 4,
+            spoofed: 5,
+            mismatched: 6,
+            user_timestamps: 7,
+            software_timestamps: 8,
+            hardware_timestamps: 9,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let tr = result("10.0.0.1", Duration::from_secs(1), 1_700_000_000_000);
+        let buf = encode(&tr);
+        assert_eq!(u16::from_le_bytes([buf[0], buf[1]]) as usize, buf.len() - 2);
+
+        let decoded = decode(&buf[2..], VERSION).unwrap();
+        assert_eq!(decoded.target, tr.target);
+        assert_eq!(decoded.window, tr.window);
+        assert_eq!(decoded.timestamp, tr.timestamp);
+        assert_eq!((decoded.received, decoded.loss), (9, 1));
+        // 本机丢弃计入发送数, 不计入丢失
+        assert_eq!(decoded.sent(), 13);
+        assert_eq!(decoded.loss_rate, 1.0 / 13.0);
+        assert_eq!(decoded.latency, tr.latency);
+        assert_eq!(decoded.late, 1);
+        assert_eq!(decoded.send_failed, 2);
+        assert_eq!(decoded.local_drops, 3);
+        assert_eq!(
+            (decoded.foreign, decoded.spoofed, decoded.mismatched),
+            (4, 5, 6)
+        );
+        assert_eq!(
+            (
+                decoded.user_timestamps,
+                decoded.software_timestamps,
+                decoded.hardware_timestamps
+            ),
+            (7, 8, 9)
+        );
+        assert!(decode(&buf[2..FIXED_LEN], VERSION).is_none());
+    }
+
+    #[test]
+    fn decode_version_1() {
+        // 版本 1 的记录就是当前记录去掉新增的计数
+        let tr = result("10.0.0.1", Duration::from_secs(1), 1_700_000_000_000);
+        let buf = encode(&tr);
+        let mut v1 = buf[2..2 + FIXED_LEN_V1].to_vec();
+        v1.extend_from_slice(tr.target.as_bytes());
+
+        let decoded = decode(&v1, VERSION_1).unwrap();
+        assert_eq!(decoded.target, tr.target);
+        assert_eq!(decoded.late, 1);
+        assert_eq!(decoded.send_failed, 0);
+    }
+
+    #[test]
+    fn only_base_windows_are_stored() {
+        let dir = temp_dir("windows");
+        let window = Duration::from_secs(1);
+        let mut store =
+            Store::open_store(&dir, Duration::from_secs(u32::MAX as u64), window).unwrap();
+        let now = now_ms();
+        store.append(&result("a", window, now)).unwrap();
+        store.append(&result("a", window, now + 1000)).unwrap();
+        store
+            .append(&result("a", Duration::from_secs(60), now))
+            .unwrap();
+        store.flush().unwrap();
+
+        let (_, path) = list_segments(&dir).unwrap().pop().unwrap();
+        let (version, compacted, results) = read_segment(&path).unwrap();
+        assert_eq!(version, VERSION);
+        assert!(!compacted);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|tr| tr.window == window));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_after_torn_record() {
+        let dir = temp_dir("torn");
+        let window = Duration::from_secs(1);
+        let retention = Duration::from_secs(u32::MAX as u64);
+        let now = now_ms() / DAY_MS * DAY_MS;
+        let mut store = Store::open_store(&dir, retention, window).unwrap();
+        store.append(&result("a", window, now)).unwrap();
+        store.append(&result("a", window, now + 1000)).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        // 模拟写入第二条记录时进程退出
+        let (_, path) = list_segments(&dir).unwrap().pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut store = Store::open_store(&dir, retention, window).unwrap();
+        for i in 2..5 {
+            store.append(&result("a", window, now + i * 1000)).unwrap();
+        }
+        store.flush().unwrap();
+
+        let (_, _, results) = read_segment(&path).unwrap();
+        let timestamps: Vec<u64> = results.iter().map(|tr| tr.timestamp - now).collect();
+        assert_eq!(timestamps, vec![0, 2000, 3000, 4000]);
+        assert!(results.iter().all(|tr| tr.target == "a"));
+
+        // 文件头不完整时重新写入文件头
+        drop(store);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(3)
+            .unwrap();
+        let mut store = Store::open_store(&dir, retention, window).unwrap();
+        store.append(&result("a", window, now)).unwrap();
+        store.flush().unwrap();
+        assert_eq!(read_segment(&path).unwrap().2.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact_merges_minutes() {
+        let dir = temp_dir("compact");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("results-1.bin");
+        let window = Duration::from_secs(1);
+        let results = [
+            result("a", window, 60_000),
+            result("a", window, 61_000),
+            result("a", window, 120_000),
+        ];
+        write_segment(&path, false, results.iter()).unwrap();
+
+        let store = Store {
+            dir: dir.clone(),
+            retention: Duration::ZERO,
+            window,
+            day: 0,
+            writer: None,
+        };
+        store.compact(&path, 0).unwrap();
+        let (_, compacted, merged) = read_segment(&path).unwrap();
+        assert!(compacted);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].timestamp, 60_000);
+        assert_eq!(merged[0].window, COMPACT_WINDOW);
+        assert_eq!((merged[0].received, merged[0].loss), (18, 2));
+        assert_eq!(merged[0].send_failed, 4);
+        assert_eq!(merged[0].latency, 2_000_000);
+        assert_eq!(merged[1].received, 9);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
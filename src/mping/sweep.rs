@@ -0,0 +1,110 @@
+#![cfg(target_os = "linux")]
+
+use std::net::IpAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::mping::ping::{self, PingOption};
+use crate::mping::stat::{self, TargetResult};
+
+// 与 fping 相同的退出码
+// 所有地址都存活
+pub const EXIT_ALL_ALIVE: i32 = 0;
+// 有不可达的地址
+pub const EXIT_UNREACHABLE: i32 = 1;
+// 有无法解析的目标
+pub const EXIT_BAD_TARGET: i32 = 2;
+// 运行出错
+pub const EXIT_ERROR: i32 = 4;
+
+// Show 控制输出哪些地址
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Show {
+    All,
+    Alive,
+    Unreachable,
+}
+
+/// 对每个地址发送 `popt.count` 次探测后输出存活和不可达的地址, 返回不可达的地址数
+/// `popt.interval` 窗口之外的 roll-up 结果被忽略
+pub fn run(addrs: Vec<IpAddr>, popt: PingOption, show: Show) -> anyhow::Result<usize> {
+    let window = popt.interval;
+    let (tx, rx) = mpsc::channel::<TargetResult>();
+    let collector = stat::collect_hosts(rx, window);
+
+    ping::ping_until(
+        addrs.clone(),
+        popt,
+        false,
+        Some(tx),
+        Arc::new(AtomicBool::new(false)),
+    )?;
+    let hosts = collector
+        .join()
+        .map_err(|_| anyhow::anyhow!("sweep collector panicked"))?;
+
+    let mut unreachable = 0;
+    for addr in &addrs {
+        let target = addr.to_string();
+        match hosts.get(&target) {
+            Some(host) if host.received > 0 => {
+                if show != Show::Unreachable {
+                    println!(
+                        "{} is alive ({:.2} ms, {}/{} received)",
+                        target,
+                        host.rtt_ms(),
+                        host.received,
+                        host.sent
+                    );
+                }
+            }
+            _ => {
+                unreachable += 1;
+                if show != Show::Alive {
+                    println!("{} is unreachable", target);
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "{} targets, {} alive, {} unreachable",
+        addrs.len(),
+        addrs.len() - unreachable,
+        unreachable
+    );
+
+    Ok(unreachable)
+}
+
+// 扫描的默认等待时间: 至少 1 秒, 并且不小于超时时间
+pub fn delay_for(timeout: Duration) -> u64 {
+    timeout.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 需要创建 raw socket 的权限
+    #[test]
+    fn loopback_is_alive_with_one_probe() {
+        let timeout = Duration::from_secs(1);
+        let popt = PingOption {
+            timeout,
+            ttl: 64,
+            ident: std::process::id(),
+            len: 64,
+            rate: 10,
+            rate_for_all: true,
+            delay: delay_for(timeout),
+            count: Some(1),
+            interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let addrs = vec!["127.0.0.1".parse().unwrap()];
+        assert_eq!(run(addrs, popt, Show::All).unwrap(), 0);
+    }
+}
@@ -0,0 +1,401 @@
+#![cfg(target_os = "linux")]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+use libc::{
+    c_char, c_int, c_void, freeifaddrs, getifaddrs, hwtstamp_config, ifaddrs, ifreq, ioctl, pipe,
+    read, sighandler_t, signal, sockaddr_in, write, AF_INET, HWTSTAMP_FILTER_ALL,
+    HWTSTAMP_FILTER_NONE, HWTSTAMP_TX_OFF, HWTSTAMP_TX_ON, IFNAMSIZ, SIGINT, SIGTERM, SIOCETHTOOL,
+    SIOCGHWTSTAMP, SIOCSHWTSTAMP, SOF_TIMESTAMPING_OPT_CMSG, SOF_TIMESTAMPING_OPT_TSONLY,
+    SOF_TIMESTAMPING_RAW_HARDWARE, SOF_TIMESTAMPING_RX_HARDWARE, SOF_TIMESTAMPING_RX_SOFTWARE,
+    SOF_TIMESTAMPING_SOFTWARE, SOF_TIMESTAMPING_TX_HARDWARE, SOF_TIMESTAMPING_TX_SOFTWARE,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+// ethtool 获取时间戳能力的命令, libc 中没有定义
+const ETHTOOL_GET_TS_INFO: u32 = 0x41;
+
+// 对应内核的 struct ethtool_ts_info
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct EthtoolTsInfo {
+    cmd: u32,
+    so_timestamping: u32,
+    phc_index: i32,
+    tx_types: u32,
+    tx_reserved: [u32; 3],
+    rx_filters: u32,
+    rx_reserved: [u32; 3],
+}
+
+/// 时间戳模式
+/// - `Software` 只使用内核软件时间戳
+/// - `Hardware` 使用网卡硬件时间戳, 有出口网卡不支持时报错
+/// - `Auto` 所有出口网卡都支持硬件时间戳时使用硬件时间戳, 否则使用软件时间戳
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampingMode {
+    Software,
+    Hardware,
+    #[default]
+    Auto,
+}
+
+// 返回 SO_TIMESTAMPING 的选项
+// 硬件模式下只请求硬件的发送时间戳, 保证发送和接收时间戳来自同一个时钟
+pub fn timestamping_flags(hardware: bool) -> u32 {
+    let common = SOF_TIMESTAMPING_SOFTWARE
+        | SOF_TIMESTAMPING_RX_SOFTWARE
+        | SOF_TIMESTAMPING_OPT_CMSG
+        | SOF_TIMESTAMPING_OPT_TSONLY;
+
+    if hardware {
+        common
+            | SOF_TIMESTAMPING_TX_HARDWARE
+            | SOF_TIMESTAMPING_RX_HARDWARE
+            | SOF_TIMESTAMPING_RAW_HARDWARE
+    } else {
+        common | SOF_TIMESTAMPING_TX_SOFTWARE
+    }
+}
+
+// Timestamping 是 setup 的结果, drop 时恢复本次开启硬件时间戳的网卡的原始配置
+pub struct Timestamping {
+    // 是否实际使用硬件时间戳
+    pub hardware: bool,
+    // 本次开启了硬件时间戳的网卡
+    ifnames: Vec<String>,
+}
+
+impl Drop for Timestamping {
+    fn drop(&mut self) {
+        for ifname in &self.ifnames {
+            release_hardware(ifname);
+        }
+    }
+}
+
+// 根据请求的模式检测出口网卡的能力并开启硬件时间戳
+// Hardware 模式下有出口网卡不支持硬件时间戳时返回错误, Auto 模式下退化为软件时间戳
+pub fn setup(mode: TimestampingMode, addrs: &[IpAddr]) -> anyhow::Result<Timestamping> {
+    let mut timestamping = Timestamping {
+        hardware: false,
+        ifnames: Vec::new(),
+    };
+    if mode == TimestampingMode::Software {
+        info!("timestamping: requested {:?}, effective software", mode);
+        return Ok(timestamping);
+    }
+
+    // 收集所有目标的出口网卡
+    let mut ifnames = BTreeSet::new();
+    for addr in addrs {
+        match egress_interface(*addr) {
+            Some(ifname) => {
+                ifnames.insert(ifname);
+            }
+            None => warn!("timestamping: no egress interface found for {}", addr),
+        }
+    }
+    if ifnames.is_empty() && mode == TimestampingMode::Hardware {
+        return Err(anyhow::anyhow!(
+            "hardware timestamping: no egress interface found"
+        ));
+    }
+
+    for ifname in &ifnames {
+        match enable_hardware(ifname) {
+            Ok(()) => {
+                info!("timestamping: {} hardware timestamping enabled", ifname);
+                timestamping.ifnames.push(ifname.clone());
+            }
+            // 返回时 drop timestamping, 恢复已经开启的网卡
+            Err(e) if mode == TimestampingMode::Hardware => {
+                return Err(anyhow::anyhow!(
+                    "hardware timestamping on {}: {}",
+                    ifname,
+                    e
+                ));
+            }
+            Err(e) => {
+                info!("timestamping: {} uses software timestamping: {}", ifname, e);
+            }
+        }
+    }
+
+    // 只有部分网卡支持时不使用硬件时间戳, 恢复已经开启的网卡
+    timestamping.hardware = !ifnames.is_empty() && timestamping.ifnames.len() == ifnames.len();
+    if !timestamping.hardware {
+        for ifname in timestamping.ifnames.drain(..) {
+            release_hardware(&ifname);
+        }
+    }
+    info!(
+        "timestamping: requested {:?}, effective {}",
+        mode,
+        if timestamping.hardware {
+            "hardware"
+        } else {
+            "software"
+        }
+    );
+
+    Ok(timestamping)
+}
+
+// 通过连接一个 UDP socket 获取到达目标的本地地址, 再找到拥有该地址的网卡
+pub fn egress_interface(addr: IpAddr) -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(SocketAddr::new(addr, 9)).ok()?;
+    let local = match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return None,
+    };
+
+    interface_by_addr(local)
+}
+
+// 遍历 getifaddrs 返回的网卡列表, 查找地址为 local 的网卡名
+fn interface_by_addr(local: Ipv4Addr) -> Option<String> {
+    let mut ifap: *mut ifaddrs = std::ptr::null_mut();
+    if unsafe { getifaddrs(&mut ifap) } != 0 {
+        return None;
+    }
+
+    let mut found = None;
+    let mut ifa = ifap;
+    while !ifa.is_null() {
+        let cur = unsafe { &*ifa };
+        if !cur.ifa_addr.is_null() && unsafe { (*cur.ifa_addr).sa_family } as i32 == AF_INET {
+            let sin = unsafe { &*(cur.ifa_addr as *const sockaddr_in) };
+            if Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)) == local {
+                let name = unsafe { CStr::from_ptr(cur.ifa_name) };
+                found = Some(name.to_string_lossy().into_owned());
+                break;
+            }
+        }
+        ifa = cur.ifa_next;
+    }
+
+    unsafe { freeifaddrs(ifap) };
+    found
+}
+
+// 构造指定网卡名的 ifreq, ifr_data 指向 data
+fn new_ifreq(ifname: &str, data: *mut c_void) -> ifreq {
+    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
+    for (i, b) in ifname.bytes().take(IFNAMSIZ - 1).enumerate() {
+        ifr.ifr_name[i] = b as c_char;
+    }
+    ifr.ifr_ifru.ifru_data = data as *mut c_char;
+    ifr
+}
+
+// 通过 ETHTOOL_GET_TS_INFO 查询网卡的时间戳能力
+fn ts_info(fd: i32, ifname: &str) -> std::io::Result<EthtoolTsInfo> {
+    let mut info = EthtoolTsInfo {
+        cmd: ETHTOOL_GET_TS_INFO,
+        ..Default::default()
+    };
+    let mut ifr = new_ifreq(ifname, &mut info as *mut _ as *mut c_void);
+    if unsafe { ioctl(fd, SIOCETHTOOL, &mut ifr) } == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(info)
+}
+
+// 开启了硬件时间戳的网卡: 开启前的配置和使用它的会话数
+// SIOCSHWTSTAMP 修改的是整个网卡的配置, 最后一个会话结束时恢复
+static HARDWARE: Mutex<BTreeMap<String, (hwtstamp_config, usize)>> = Mutex::new(BTreeMap::new());
+// 信号处理函数通知恢复线程的管道写端
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+static SIGNAL_HANDLER: Once = Once::new();
+
+// 检查网卡是否支持硬件收发时间戳, 支持时保存原来的配置并通过 SIOCSHWTSTAMP 开启
+fn enable_hardware(ifname: &str) -> anyhow::Result<()> {
+    let mut hardware = HARDWARE.lock().unwrap();
+    if let Some((_, sessions)) = hardware.get_mut(ifname) {
+        *sessions += 1;
+        return Ok(());
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let fd = socket.as_raw_fd();
+
+    let info = ts_info(fd, ifname)?;
+    let required = SOF_TIMESTAMPING_TX_HARDWARE
+        | SOF_TIMESTAMPING_RX_HARDWARE
+        | SOF_TIMESTAMPING_RAW_HARDWARE;
+    if info.so_timestamping & required != required
+        || info.tx_types & (1 << HWTSTAMP_TX_ON) == 0
+        || info.rx_filters & (1 << HWTSTAMP_FILTER_ALL) == 0
+    {
+        return Err(anyhow::anyhow!(
+            "hardware timestamping not supported (capabilities: {:#x})",
+            info.so_timestamping
+        ));
+    }
+
+    // 不支持 SIOCGHWTSTAMP 的驱动认为原来没有开启硬件时间戳
+    let mut saved = hwtstamp_config {
+        flags: 0,
+        tx_type: HWTSTAMP_TX_OFF as i32,
+        rx_filter: HWTSTAMP_FILTER_NONE as i32,
+    };
+    let mut ifr = new_ifreq(ifname, &mut saved as *mut _ as *mut c_void);
+    if unsafe { ioctl(fd, SIOCGHWTSTAMP, &mut ifr) } == -1 {
+        warn!(
+            "timestamping: {} SIOCGHWTSTAMP failed, hardware timestamping is disabled on exit: {}",
+            ifname,
+            Error::last_os_error()
+        );
+    }
+
+    let config = hwtstamp_config {
+        flags: 0,
+        tx_type: HWTSTAMP_TX_ON as i32,
+        rx_filter: HWTSTAMP_FILTER_ALL as i32,
+    };
+    set_hwtstamp(fd, ifname, config)?;
+    hardware.insert(ifname.to_string(), (saved, 1));
+    install_signal_handler();
+
+    Ok(())
+}
+
+// 一个会话不再使用网卡的硬件时间戳, 最后一个会话结束时恢复原来的配置
+fn release_hardware(ifname: &str) {
+    let mut hardware = HARDWARE.lock().unwrap();
+    let Some((saved, sessions)) = hardware.get_mut(ifname) else {
+        return;
+    };
+    *sessions -= 1;
+    if *sessions > 0 {
+        return;
+    }
+
+    let saved = *saved;
+    hardware.remove(ifname);
+    restore(ifname, saved);
+}
+
+// 恢复网卡原来的时间戳配置
+fn restore(ifname: &str, saved: hwtstamp_config) {
+    let result = UdpSocket::bind("0.0.0.0:0")
+        .map_err(anyhow::Error::from)
+        .and_then(|socket| set_hwtstamp(socket.as_raw_fd(), ifname, saved));
+    match result {
+        Ok(()) => info!("timestamping: {} timestamping config restored", ifname),
+        Err(e) => warn!(
+            "timestamping: failed to restore {} timestamping config: {}",
+            ifname, e
+        ),
+    }
+}
+
+fn set_hwtstamp(fd: i32, ifname: &str, mut config: hwtstamp_config) -> anyhow::Result<()> {
+    let mut ifr = new_ifreq(ifname, &mut config as *mut _ as *mut c_void);
+    if unsafe { ioctl(fd, SIOCSHWTSTAMP, &mut ifr) } == -1 {
+        return Err(anyhow::anyhow!(
+            "SIOCSHWTSTAMP failed: {}",
+            Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// 被 SIGINT 或 SIGTERM 终止时 Timestamping 不会被 drop
+// 信号处理函数只向管道写入信号值, 由单独的线程恢复所有网卡的配置后退出
+fn install_signal_handler() {
+    SIGNAL_HANDLER.call_once(|| {
+        let mut fds = [0; 2];
+        if unsafe { pipe(fds.as_mut_ptr()) } == -1 {
+            warn!(
+                "timestamping: failed to create signal pipe, timestamping config is not restored on signals: {}",
+                Error::last_os_error()
+            );
+            return;
+        }
+        SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+
+        let read_fd = fds[0];
+        thread::spawn(move || {
+            let mut sig = 0u8;
+            while unsafe { read(read_fd, &mut sig as *mut _ as *mut c_void, 1) } != 1 {}
+            for (ifname, (saved, _)) in std::mem::take(&mut *HARDWARE.lock().unwrap()) {
+                restore(&ifname, saved);
+            }
+            process::exit(128 + sig as i32);
+        });
+
+        let handler = on_signal as extern "C" fn(c_int) as sighandler_t;
+        unsafe {
+            signal(SIGINT, handler);
+            signal(SIGTERM, handler);
+        }
+    });
+}
+
+extern "C" fn on_signal(sig: c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    let sig = sig as u8;
+    unsafe { write(fd, &sig as *const _ as *const c_void, 1) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn flags_use_one_clock() {
+        let hardware = timestamping_flags(true);
+        assert_ne!(hardware & SOF_TIMESTAMPING_RAW_HARDWARE, 0);
+        assert_eq!(hardware & SOF_TIMESTAMPING_TX_SOFTWARE, 0);
+        let software = timestamping_flags(false);
+        assert_ne!(software & SOF_TIMESTAMPING_TX_SOFTWARE, 0);
+        assert_eq!(software & SOF_TIMESTAMPING_TX_HARDWARE, 0);
+    }
+
+    #[test]
+    fn loopback_egress_interface() {
+        assert_eq!(egress_interface(LOOPBACK).as_deref(), Some("lo"));
+    }
+
+    // 回环网卡没有 PHC, 在任何机器上都可以测试退化
+    #[test]
+    fn auto_falls_back_to_software() {
+        let timestamping = setup(TimestampingMode::Auto, &[LOOPBACK]).unwrap();
+        assert!(!timestamping.hardware);
+        assert!(timestamping.ifnames.is_empty());
+        assert!(!HARDWARE.lock().unwrap().contains_key("lo"));
+
+        let timestamping = setup(TimestampingMode::Software, &[LOOPBACK]).unwrap();
+        assert!(!timestamping.hardware);
+    }
+
+    #[test]
+    fn forced_hardware_fails_without_phc() {
+        let err = setup(TimestampingMode::Hardware, &[LOOPBACK])
+            .err()
+            .expect("loopback has no hardware timestamping");
+        assert!(err.to_string().contains("lo"), "{}", err);
+        assert!(!HARDWARE.lock().unwrap().contains_key("lo"));
+
+        // 找不到出口网卡时同样报错
+        assert!(setup(TimestampingMode::Hardware, &[]).is_err());
+    }
+}
@@ -0,0 +1,540 @@
+#![cfg(target_os = "linux")]
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::mping::stat::TargetResult;
+
+// 火花线保留的窗口数
+const HISTORY_LEN: usize = 20;
+// 火花线使用的字符, 从低到高
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// 表格的列, 也是可以排序的字段
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Column {
+    Target,
+    Sent,
+    Received,
+    Loss,
+    Last,
+    Avg,
+    Max,
+    Bitflip,
+}
+
+const COLUMNS: [Column; 8] = [
+    Column::Target,
+    Column::Sent,
+    Column::Received,
+    Column::Loss,
+    Column::Last,
+    Column::Avg,
+    Column::Max,
+    Column::Bitflip,
+];
+
+impl Column {
+    fn title(&self) -> &'static str {
+        match self {
+            Column::Target => "target",
+            Column::Sent => "sent",
+            Column::Received => "recv",
+            Column::Loss => "loss%",
+            Column::Last => "last(ms)",
+            Column::Avg => "avg(ms)",
+            Column::Max => "max(ms)",
+            Column::Bitflip => "bitflip",
+        }
+    }
+}
+
+// TargetRow 是一个目标从启动以来的累计统计
+#[derive(Default)]
+struct TargetRow {
+    target: String,
+    sent: u64,
+    received: u64,
+    loss: u64,
+    bitflips: u64,
+    // 最近一个窗口的平均延迟
+    last_latency: u128,
+    // 所有回复的延迟之和
+    latency_sum: u128,
+    max_latency: u128,
+    // 最近窗口的平均延迟, 没有回复的窗口为 None
+    history: VecDeque<Option<u128>>,
+}
+
+impl TargetRow {
+    fn add(&mut self, tr: &TargetResult) {
+        self.sent += tr.sent() as u64;
+        self.received += tr.received as u64;
+        self.loss += tr.loss as u64;
+        self.bitflips += tr.bitflip_count as u64;
+        self.latency_sum += tr.latency * tr.received as u128;
+        self.max_latency = self.max_latency.max(tr.max_latency);
+
+        let latency = if tr.received > 0 {
+            self.last_latency = tr.latency;
+            Some(tr.latency)
+        } else {
+            None
+        };
+        self.history.push_back(latency);
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+    }
+
+    fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.loss as f64 / self.sent as f64
+        }
+    }
+
+    fn avg_latency(&self) -> u128 {
+        if self.received == 0 {
+            0
+        } else {
+            self.latency_sum / self.received as u128
+        }
+    }
+
+    // 按最近窗口中的最大延迟缩放的火花线
+    fn sparkline(&self) -> String {
+        let max = self.history.iter().flatten().max().copied().unwrap_or(0);
+        self.history
+            .iter()
+            .map(|latency| match latency {
+                None => ' ',
+                Some(_) if max == 0 => SPARKS[0],
+                Some(l) => SPARKS[(l * (SPARKS.len() as u128 - 1) / max) as usize],
+            })
+            .collect()
+    }
+
+    // 表格中一行的内容, 与 COLUMNS 的顺序相同, 最后是火花线
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.target.clone(),
+            self.sent.to_string(),
+            self.received.to_string(),
+            format!("{:.2}", self.loss_rate() * 100.0),
+            format_ms(self.last_latency),
+            format_ms(self.avg_latency()),
+            format_ms(self.max_latency),
+            self.bitflips.to_string(),
+            self.sparkline(),
+        ]
+    }
+
+    fn compare(&self, other: &TargetRow, column: Column) -> CmpOrdering {
+        match column {
+            Column::Target => match (
+                self.target.parse::<IpAddr>(),
+                other.target.parse::<IpAddr>(),
+            ) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => self.target.cmp(&other.target),
+            },
+            Column::Sent => self.sent.cmp(&other.sent),
+            Column::Received => self.received.cmp(&other.received),
+            Column::Loss => self.loss_rate().total_cmp(&other.loss_rate()),
+            Column::Last => self.last_latency.cmp(&other.last_latency),
+            Column::Avg => self.avg_latency().cmp(&other.avg_latency()),
+            Column::Max => self.max_latency.cmp(&other.max_latency),
+            Column::Bitflip => self.bitflips.cmp(&other.bitflips),
+        }
+    }
+}
+
+// 把纳秒格式化为毫秒
+fn format_ms(nanos: u128) -> String {
+    format!("{:.2}", nanos as f64 / 1_000_000.0)
+}
+
+// App 保存界面的状态
+struct App {
+    // 只展示该长度窗口的结果, roll-up 窗口的结果被忽略
+    window: Duration,
+    rows: BTreeMap<String, TargetRow>,
+    sort: Column,
+    reverse: bool,
+    filter: String,
+    // 是否正在输入过滤条件
+    editing: bool,
+    // 暂停时新结果先缓存起来, 恢复后再更新表格
+    paused: bool,
+    pending: Vec<TargetResult>,
+    table_state: TableState,
+}
+
+impl App {
+    fn new_app(window: Duration) -> App {
+        App {
+            window,
+            rows: BTreeMap::new(),
+            sort: Column::Target,
+            reverse: false,
+            filter: String::new(),
+            editing: false,
+            paused: false,
+            pending: Vec::new(),
+            table_state: TableState::default(),
+        }
+    }
+
+    fn apply(&mut self, tr: TargetResult) {
+        if tr.window != self.window {
+            return;
+        }
+        if self.paused {
+            self.pending.push(tr);
+            return;
+        }
+
+        self.rows
+            .entry(tr.target.clone())
+            .or_insert_with(|| TargetRow {
+                target: tr.target.clone(),
+                ..Default::default()
+            })
+            .add(&tr);
+    }
+
+    // 过滤并排序后的行
+    fn visible_rows(&self) -> Vec<&TargetRow> {
+        let mut rows: Vec<&TargetRow> = self
+            .rows
+            .values()
+            .filter(|row| row.target.contains(&self.filter))
+            .collect();
+        rows.sort_by(|a, b| {
+            let ord = a.compare(b, self.sort);
+            if self.reverse {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+        rows
+    }
+
+    // 处理按键, 返回 false 表示退出
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        if self.editing {
+            match code {
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.editing = false;
+                    self.filter.clear();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('p') | KeyCode::Char(' ') => {
+                self.paused = !self.paused;
+                if !self.paused {
+                    for tr in std::mem::take(&mut self.pending) {
+                        self.apply(tr);
+                    }
+                }
+            }
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('s') => {
+                let i = COLUMNS.iter().position(|c| *c == self.sort).unwrap();
+                self.sort = COLUMNS[(i + 1) % COLUMNS.len()];
+            }
+            KeyCode::Char(c @ '1'..='8') => {
+                let column = COLUMNS[c as usize - '1' as usize];
+                // 再次选择同一列时反转排序
+                if column == self.sort {
+                    self.reverse = !self.reverse;
+                } else {
+                    self.sort = column;
+                    self.reverse = false;
+                }
+            }
+            KeyCode::Down | KeyCode::Char('j') => self.table_state.scroll_down_by(1),
+            KeyCode::Up | KeyCode::Char('k') => self.table_state.scroll_up_by(1),
+            KeyCode::PageDown => self.table_state.scroll_down_by(20),
+            KeyCode::PageUp => self.table_state.scroll_up_by(20),
+            _ => {}
+        }
+
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [table_area, status_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+
+        let header = Row::new(self.header().into_iter().map(Cell::from))
+            .style(Style::default().add_modifier(Modifier::BOLD));
+
+        let rows = self.visible_rows();
+        let shown = rows.len();
+        let table_rows: Vec<Row> = rows
+            .into_iter()
+            .map(|row| Row::new(row.cells().into_iter().map(Cell::from)))
+            .collect();
+
+        let widths = [
+            Constraint::Length(18),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(11),
+            Constraint::Length(11),
+            Constraint::Length(11),
+            Constraint::Length(10),
+            Constraint::Length(HISTORY_LEN as u16),
+        ];
+        let table = Table::new(table_rows, widths)
+            .header(header)
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                " mping [{:?}] targets: {} shown: {} ",
+                self.window,
+                self.rows.len(),
+                shown
+            )))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, table_area, &mut self.table_state);
+        frame.render_widget(Paragraph::new(self.status()), status_area);
+    }
+
+    // 表头, 排序的列带有方向标记
+    fn header(&self) -> Vec<String> {
+        COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let mark = match (*c == self.sort, self.reverse) {
+                    (true, false) => "↑",
+                    (true, true) => "↓",
+                    _ => "",
+                };
+                format!("{}:{}{}", i + 1, c.title(), mark)
+            })
+            .chain(std::iter::once("rtt".to_string()))
+            .collect()
+    }
+
+    // 底部的状态行
+    fn status(&self) -> String {
+        if self.editing {
+            format!("filter: {}_  (enter: apply, esc: clear)", self.filter)
+        } else {
+            format!(
+                "{}filter: {}  | q: quit  p: pause  /: filter  1-8/s: sort  r: reverse  ↑↓: scroll",
+                if self.paused { "[PAUSED] " } else { "" },
+                self.filter
+            )
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        rx: Receiver<TargetResult>,
+        stop: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            while let Ok(tr) = rx.try_recv() {
+                self.apply(tr);
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(Duration::from_millis(200))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.handle_key(key.code) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 运行终端界面, 展示 `window` 窗口的统计结果
+/// 用户退出或 `stop` 被设置时返回, 返回前恢复终端
+pub fn run(
+    rx: Receiver<TargetResult>,
+    window: Duration,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = App::new_app(window).run(&mut terminal, rx, stop);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn result(target: &str, received: u32, loss: u32, latency_ms: u128) -> TargetResult {
+        TargetResult {
+            target: target.to_string(),
+            window: WINDOW,
+            received,
+            loss,
+            latency: latency_ms * 1_000_000,
+            max_latency: latency_ms * 1_000_000,
+            ..Default::default()
+        }
+    }
+
+    fn app() -> App {
+        let mut app = App::new_app(WINDOW);
+        app.apply(result("10.0.0.10", 10, 0, 5));
+        app.apply(result("10.0.0.2", 5, 5, 1));
+        app.apply(result("10.0.0.3", 9, 1, 20));
+        app
+    }
+
+    fn targets(app: &App) -> Vec<&str> {
+        app.visible_rows()
+            .iter()
+            .map(|row| row.target.as_str())
+            .collect()
+    }
+
+    fn keys(app: &mut App, input: &str) {
+        for c in input.chars() {
+            assert!(app.handle_key(KeyCode::Char(c)));
+        }
+    }
+
+    #[test]
+    fn sort_order() {
+        let mut app = app();
+        // 地址按数值排序, 而不是按字符串
+        assert_eq!(targets(&app), vec!["10.0.0.2", "10.0.0.3", "10.0.0.10"]);
+        assert_eq!(app.header()[0], "1:target↑");
+
+        keys(&mut app, "4");
+        assert_eq!(app.sort, Column::Loss);
+        assert_eq!(targets(&app), vec!["10.0.0.10", "10.0.0.3", "10.0.0.2"]);
+        // 再次选择同一列时反转
+        keys(&mut app, "4");
+        assert_eq!(targets(&app), vec!["10.0.0.2", "10.0.0.3", "10.0.0.10"]);
+        assert_eq!(app.header()[3], "4:loss%↓");
+
+        keys(&mut app, "6");
+        assert!(!app.reverse);
+        assert_eq!(targets(&app), vec!["10.0.0.2", "10.0.0.10", "10.0.0.3"]);
+        keys(&mut app, "r");
+        assert_eq!(targets(&app), vec!["10.0.0.3", "10.0.0.10", "10.0.0.2"]);
+
+        // s 依次切换排序的列, 最后一列之后回到第一列
+        keys(&mut app, "8s");
+        assert_eq!(app.sort, Column::Target);
+        keys(&mut app, "s");
+        assert_eq!(app.sort, Column::Sent);
+    }
+
+    #[test]
+    fn filter_targets() {
+        let mut app = app();
+        keys(&mut app, "/");
+        assert!(app.editing);
+        // 输入过滤条件时 q 不退出
+        keys(&mut app, "0.3q");
+        assert_eq!(app.filter, "0.3q");
+        assert!(app.handle_key(KeyCode::Backspace));
+        assert!(app.handle_key(KeyCode::Enter));
+        assert!(!app.editing);
+        assert_eq!(targets(&app), vec!["10.0.0.3"]);
+        assert!(app.status().starts_with("filter: 0.3 "));
+
+        keys(&mut app, "/1");
+        assert_eq!(app.status(), "filter: 0.31_  (enter: apply, esc: clear)");
+        assert!(targets(&app).is_empty());
+        assert!(app.handle_key(KeyCode::Esc));
+        assert_eq!(app.filter, "");
+        assert_eq!(targets(&app).len(), 3);
+
+        assert!(!app.handle_key(KeyCode::Char('q')));
+        assert!(!app.handle_key(KeyCode::Esc));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut app = app();
+        keys(&mut app, "p");
+        assert!(app.status().starts_with("[PAUSED] "));
+        app.apply(result("10.0.0.2", 10, 0, 1));
+        app.apply(result("10.0.0.4", 10, 0, 1));
+        assert_eq!(app.rows["10.0.0.2"].sent, 10);
+        assert_eq!(app.rows.len(), 3);
+
+        // 恢复后按顺序应用暂停期间的结果
+        keys(&mut app, " ");
+        assert!(app.pending.is_empty());
+        assert_eq!(app.rows["10.0.0.2"].sent, 20);
+        assert_eq!(app.rows["10.0.0.2"].loss_rate(), 0.25);
+        assert_eq!(app.rows.len(), 4);
+    }
+
+    #[test]
+    fn rollups_are_ignored() {
+        let mut app = App::new_app(WINDOW);
+        let mut tr = result("10.0.0.1", 10, 0, 1);
+        tr.window = Duration::from_secs(60);
+        app.apply(tr);
+        assert!(app.rows.is_empty());
+    }
+
+    #[test]
+    fn sparkline_history() {
+        let mut app = App::new_app(WINDOW);
+        for i in 0..HISTORY_LEN as u128 + 5 {
+            app.apply(result("10.0.0.1", 1, 0, i + 1));
+        }
+        app.apply(result("10.0.0.1", 0, 1, 0));
+
+        let row = &app.rows["10.0.0.1"];
+        assert_eq!(row.history.len(), HISTORY_LEN);
+        let sparkline: Vec<char> = row.sparkline().chars().collect();
+        assert_eq!(sparkline.len(), HISTORY_LEN);
+        // 没有回复的窗口为空白, 最大延迟为最高的字符
+        assert_eq!(sparkline[HISTORY_LEN - 1], ' ');
+        assert_eq!(sparkline[HISTORY_LEN - 2], SPARKS[SPARKS.len() - 1]);
+        // 最近窗口没有回复时仍然显示最近一次的延迟
+        assert_eq!(row.last_latency, 25_000_000);
+
+        let cells = row.cells();
+        assert_eq!(cells.len(), COLUMNS.len() + 1);
+        assert_eq!(cells[..4], ["10.0.0.1", "26", "25", "3.85"]);
+        assert_eq!(cells[6], "25.00");
+    }
+}